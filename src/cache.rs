@@ -0,0 +1,642 @@
+use crate::errors::ErnieError;
+use crate::trace;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// count and log a failed write of a cache: the call goes on without the cache, which only misses
+fn write_failed(write_errors: &AtomicU64, error: std::io::Error) {
+    write_errors.fetch_add(1, Ordering::Relaxed);
+    trace::record_cache_error(&ErnieError::CacheError(error.to_string()));
+}
+
+/** A CacheBackend stores json responses by key. Implement it to keep responses in a shared store (e.g. redis); `MemoryCache`, `DiskCache` and `FileCache` are provided.
+
+Keys are hex strings, safe to use as file names.
+*/
+pub trait CacheBackend: Send + Sync {
+    /// the value stored under `key`, unless it expired
+    fn get(&self, key: &str) -> Option<Value>;
+    /// store `value` under `key`, for `ttl` if set
+    fn put(&self, key: &str, value: &Value, ttl: Option<Duration>);
+    fn remove(&self, key: &str);
+    fn clear(&self);
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    value: Value,
+    expires_at: Option<Instant>,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    entries: HashMap<String, MemoryEntry>,
+    /// keys by the tick of their last use, the least recently used first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl MemoryState {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+}
+
+/// An in-memory cache evicting the least recently used entry beyond `capacity` entries.
+#[derive(Debug)]
+pub struct MemoryCache {
+    capacity: usize,
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        MemoryCache {
+            capacity: capacity.max(1),
+            state: Mutex::new(MemoryState::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl CacheBackend for MemoryCache {
+    fn get(&self, key: &str) -> Option<Value> {
+        let mut state = self.state.lock().unwrap();
+        let expired = state
+            .entries
+            .get(key)?
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Instant::now());
+        if expired {
+            state.remove(key);
+            return None;
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        let entry = state.entries.get_mut(key)?;
+        let previous = std::mem::replace(&mut entry.last_used, tick);
+        let value = entry.value.clone();
+        state.recency.remove(&previous);
+        state.recency.insert(tick, key.to_string());
+        Some(value)
+    }
+
+    fn put(&self, key: &str, value: &Value, ttl: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        state.remove(key);
+        state.tick += 1;
+        let tick = state.tick;
+        state.entries.insert(
+            key.to_string(),
+            MemoryEntry {
+                value: value.clone(),
+                expires_at: ttl.map(|ttl| Instant::now() + ttl),
+                last_used: tick,
+            },
+        );
+        state.recency.insert(tick, key.to_string());
+        while state.entries.len() > self.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        self.state.lock().unwrap().remove(key);
+    }
+
+    fn clear(&self) {
+        *self.state.lock().unwrap() = MemoryState::default();
+    }
+}
+
+/// A cache keeping one json file per entry in a directory, so that it survives restarts.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+    write_errors: Arc<AtomicU64>,
+}
+
+impl DiskCache {
+    /// use `dir` as the cache directory, creating it if needed
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, ErnieError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(|e| ErnieError::CacheError(e.to_string()))?;
+        Ok(DiskCache {
+            dir,
+            write_errors: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// how many entries could not be written or removed, each failure being logged with the `tracing` feature
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    /// milliseconds since the unix epoch, the unit of the expiry of the entries of the disk and file caches
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    /// when an entry stored now for `ttl` expires, in milliseconds so that sub-second TTLs are kept
+    fn expires_at(ttl: Option<Duration>) -> Option<u64> {
+        ttl.map(|ttl| DiskCache::now().saturating_add(ttl.as_millis() as u64))
+    }
+}
+
+impl CacheBackend for DiskCache {
+    fn get(&self, key: &str) -> Option<Value> {
+        let text = std::fs::read_to_string(self.path(key)).ok()?;
+        let mut entry: Value = serde_json::from_str(&text).ok()?;
+        let expires_at = entry.get("expires_at").and_then(|v| v.as_u64());
+        if expires_at.is_some_and(|expires_at| expires_at <= DiskCache::now()) {
+            self.remove(key);
+            return None;
+        }
+        entry.get_mut("value").map(Value::take)
+    }
+
+    fn put(&self, key: &str, value: &Value, ttl: Option<Duration>) {
+        let entry = serde_json::json!({
+            "expires_at": DiskCache::expires_at(ttl),
+            "value": value,
+        });
+        // write to a temporary file first so that readers never see a partial entry
+        let temporary = self.dir.join(format!("{}.tmp", key));
+        if let Err(e) = std::fs::write(&temporary, entry.to_string())
+            .and_then(|_| std::fs::rename(&temporary, self.path(key)))
+        {
+            write_failed(&self.write_errors, e);
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        match std::fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                write_failed(&self.write_errors, e)
+            }
+            _ => {}
+        }
+    }
+
+    fn clear(&self) {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => return write_failed(&self.write_errors, e),
+        };
+        for entry in entries.flatten() {
+            if entry.path().extension().is_some_and(|ext| ext == "json") {
+                if let Err(e) = std::fs::remove_file(entry.path()) {
+                    write_failed(&self.write_errors, e);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FileState {
+    entries: HashMap<String, (Value, Option<u64>)>,
+    file: File,
+}
+
+/** A cache keeping all its entries in a single file, loaded in memory when opened.
+
+Entries are appended to the file as json lines, the last line of a key winning, so that a crash loses at most the entry being written. Call `compact` to drop the replaced and removed entries from the file.
+*/
+#[derive(Debug)]
+pub struct FileCache {
+    path: PathBuf,
+    state: Mutex<FileState>,
+    write_errors: AtomicU64,
+}
+
+impl FileCache {
+    /// open the cache file at `path`, creating it if needed
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self, ErnieError> {
+        let path = path.into();
+        let cache_error = |e: std::io::Error| ErnieError::CacheError(e.to_string());
+        let mut entries = HashMap::new();
+        let mut partial = false;
+        if path.exists() {
+            let text = std::fs::read_to_string(&path).map_err(cache_error)?;
+            partial = !text.is_empty() && !text.ends_with('\n');
+            for line in text.lines() {
+                // a partial line is left by a crash while writing, skip it
+                let Ok(mut entry) = serde_json::from_str::<Value>(line) else {
+                    continue;
+                };
+                let Some(key) = entry.get("key").and_then(|key| key.as_str()) else {
+                    continue;
+                };
+                let key = key.to_string();
+                let expires_at = entry.get("expires_at").and_then(|v| v.as_u64());
+                match entry.get_mut("value").map(Value::take) {
+                    Some(Value::Null) | None => entries.remove(&key),
+                    Some(value) => entries.insert(key, (value, expires_at)),
+                };
+            }
+        }
+        let mut file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(cache_error)?;
+        if partial {
+            // end the partial line, so that the next entry is not appended to it
+            writeln!(file).map_err(cache_error)?;
+        }
+        Ok(FileCache {
+            path,
+            state: Mutex::new(FileState { entries, file }),
+            write_errors: AtomicU64::new(0),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// how many entries could not be written to the file, each failure being logged with the `tracing` feature. The entries are still served until the cache is dropped.
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+
+    fn append(
+        file: &mut File,
+        key: &str,
+        value: &Value,
+        expires_at: Option<u64>,
+    ) -> std::io::Result<()> {
+        let line = serde_json::json!({"key": key, "expires_at": expires_at, "value": value});
+        writeln!(file, "{}", line)
+    }
+
+    /// rewrite the file with the live entries only
+    pub fn compact(&self) -> Result<(), ErnieError> {
+        let cache_error = |e: std::io::Error| ErnieError::CacheError(e.to_string());
+        let mut state = self.state.lock().unwrap();
+        let now = DiskCache::now();
+        state
+            .entries
+            .retain(|_, (_, expires_at)| expires_at.is_none_or(|expires_at| expires_at > now));
+        let mut temporary = self.path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let mut file = File::create(&temporary).map_err(cache_error)?;
+        for (key, (value, expires_at)) in &state.entries {
+            FileCache::append(&mut file, key, value, *expires_at).map_err(cache_error)?;
+        }
+        file.sync_all().map_err(cache_error)?;
+        std::fs::rename(&temporary, &self.path).map_err(cache_error)?;
+        state.file = File::options()
+            .append(true)
+            .open(&self.path)
+            .map_err(cache_error)?;
+        Ok(())
+    }
+}
+
+impl CacheBackend for FileCache {
+    fn get(&self, key: &str) -> Option<Value> {
+        let mut state = self.state.lock().unwrap();
+        let (value, expires_at) = state.entries.get(key)?;
+        if expires_at.is_some_and(|expires_at| expires_at <= DiskCache::now()) {
+            state.entries.remove(key);
+            return None;
+        }
+        Some(value.clone())
+    }
+
+    fn put(&self, key: &str, value: &Value, ttl: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        let expires_at = DiskCache::expires_at(ttl);
+        if let Err(e) = FileCache::append(&mut state.file, key, value, expires_at) {
+            write_failed(&self.write_errors, e);
+        }
+        state
+            .entries
+            .insert(key.to_string(), (value.clone(), expires_at));
+    }
+
+    fn remove(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.remove(key).is_some() {
+            if let Err(e) = FileCache::append(&mut state.file, key, &Value::Null, None) {
+                write_failed(&self.write_errors, e);
+            }
+        }
+    }
+
+    fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        if let Err(e) = state.file.set_len(0) {
+            write_failed(&self.write_errors, e);
+        }
+    }
+}
+
+/// sort the keys of every object, so that equal bodies serialize to the same text whatever the order of their fields
+fn canonicalize(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let mut sorted = Map::new();
+            for key in keys {
+                sorted.insert(key.clone(), canonicalize(&map[key]));
+            }
+            Value::Object(sorted)
+        }
+        Value::Array(values) => Value::Array(values.iter().map(canonicalize).collect()),
+        _ => value.clone(),
+    }
+}
+
+/** ResponseCache serves repeated requests from a cache instead of calling the API again.
+
+Attach it with `with_cache` to a `ChatEndpoint`, an `EmbeddingEndpoint` or a `RerankerEndpoint`. Responses of `invoke` and `ainvoke` are stored under a key made of the endpoint url and the canonicalized request body (after the `before_request` middlewares ran), and served for identical requests until their TTL expires.
+Chat `stream` and `astream` calls replay a cached answer as a synthetic stream of one chunk per sentence, and call the API on a miss.
+Cached responses do not count against budgets or rate limits, and are reported to metrics with the "cache_hit" outcome.
+```
+use erniebot_rs::cache::ResponseCache;
+use std::time::Duration;
+let cache = ResponseCache::in_memory(1000).with_ttl(Duration::from_secs(3600));
+// the same entries, but always calling the API and refreshing them
+let refreshing = cache.clone().with_bypass(true);
+```
+*/
+#[derive(Clone)]
+pub struct ResponseCache {
+    backend: Arc<dyn CacheBackend>,
+    ttl: Option<Duration>,
+    bypass: bool,
+}
+
+impl std::fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("ttl", &self.ttl)
+            .field("bypass", &self.bypass)
+            .finish()
+    }
+}
+
+impl ResponseCache {
+    pub fn new(backend: Arc<dyn CacheBackend>) -> Self {
+        ResponseCache {
+            backend,
+            ttl: None,
+            bypass: false,
+        }
+    }
+
+    /// a cache holding at most `capacity` responses in memory
+    pub fn in_memory(capacity: usize) -> Self {
+        ResponseCache::new(Arc::new(MemoryCache::new(capacity)))
+    }
+
+    /// a cache keeping responses as files in `dir`
+    pub fn on_disk(dir: impl Into<PathBuf>) -> Result<Self, ErnieError> {
+        Ok(ResponseCache::new(Arc::new(DiskCache::new(dir)?)))
+    }
+
+    /// a cache keeping responses in the single file `path`, see `FileCache`
+    pub fn in_file(path: impl Into<PathBuf>) -> Result<Self, ErnieError> {
+        Ok(ResponseCache::new(Arc::new(FileCache::new(path)?)))
+    }
+
+    /// set how long responses stay in the cache. They stay until evicted by default.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// when `bypass` is set, cached responses are never served, but new responses are still stored
+    pub fn with_bypass(mut self, bypass: bool) -> Self {
+        self.bypass = bypass;
+        self
+    }
+
+    /// the key of a request: a hash of the endpoint url and the canonicalized body, without its `stream` field so that streams share the entries of non-stream calls
+    pub fn key(url: &str, body: &Value) -> String {
+        let mut body = canonicalize(body);
+        if let Some(map) = body.as_object_mut() {
+            map.remove("stream");
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(url.as_bytes());
+        hasher.update(b"\n");
+        hasher.update(body.to_string().as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// the cached response under `key`, unless the cache is bypassed
+    pub(crate) fn get(&self, key: &str) -> Option<Value> {
+        if self.bypass {
+            return None;
+        }
+        self.backend.get(key)
+    }
+
+    pub(crate) fn put(&self, key: &str, response: &Value) {
+        self.backend.put(key, response, self.ttl);
+    }
+
+    pub fn clear(&self) {
+        self.backend.clear();
+    }
+}
+
+/// split a cached chat answer into the chunks of a synthetic stream, one per sentence
+pub(crate) fn replay_chunks(response: &Value) -> Vec<Value> {
+    let result = response
+        .get("result")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    let mut sentences = Vec::new();
+    let mut sentence = String::new();
+    for c in result.chars() {
+        sentence.push(c);
+        if matches!(c, '。' | '！' | '？' | '!' | '?' | '\n') {
+            sentences.push(std::mem::take(&mut sentence));
+        }
+    }
+    if !sentence.is_empty() || sentences.is_empty() {
+        sentences.push(sentence);
+    }
+    let last = sentences.len() - 1;
+    sentences
+        .into_iter()
+        .enumerate()
+        .map(|(index, sentence)| {
+            let mut chunk = response.clone();
+            chunk["result"] = Value::String(sentence);
+            chunk["sentence_id"] = Value::from(index);
+            chunk["is_end"] = Value::Bool(index == last);
+            chunk
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replay_chunks, CacheBackend, DiskCache, FileCache, MemoryCache, ResponseCache};
+    use serde_json::json;
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn test_key() {
+        let url = "https://aip.baidubce.com/chat/completions";
+        let a = json!({"messages": [{"role": "user", "content": "hi"}], "temperature": 0.5});
+        let b = json!({"temperature": 0.5, "stream": true, "messages": [{"content": "hi", "role": "user"}]});
+        assert_eq!(ResponseCache::key(url, &a), ResponseCache::key(url, &b));
+        assert_ne!(
+            ResponseCache::key(url, &a),
+            ResponseCache::key("https://aip.baidubce.com/chat/eb-instant", &a)
+        );
+    }
+
+    #[test]
+    fn test_memory_cache() {
+        let cache = MemoryCache::new(2);
+        cache.put("a", &json!(1), None);
+        cache.put("b", &json!(2), None);
+        assert_eq!(cache.get("a"), Some(json!(1)));
+        cache.put("c", &json!(3), None);
+        // b is the least recently used
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.len(), 2);
+        cache.put("d", &json!(4), Some(Duration::from_millis(20)));
+        sleep(Duration::from_millis(30));
+        assert_eq!(cache.get("d"), None);
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("erniebot-cache-{}", std::process::id()));
+        let cache = ResponseCache::on_disk(&dir).unwrap();
+        let key = ResponseCache::key("url", &json!({"input": ["a"]}));
+        cache.put(&key, &json!({"data": []}));
+        assert_eq!(cache.get(&key), Some(json!({"data": []})));
+        assert_eq!(cache.clone().with_bypass(true).get(&key), None);
+        let reopened = DiskCache::new(&dir).unwrap();
+        assert!(reopened.get(&key).is_some());
+        cache.clear();
+        assert_eq!(cache.get(&key), None);
+
+        // a directory in the way of the temporary file of an entry
+        let disk = DiskCache::new(&dir).unwrap();
+        std::fs::create_dir_all(dir.join("blocked.tmp")).unwrap();
+        disk.put("blocked", &json!(1), None);
+        assert_eq!(disk.get("blocked"), None);
+        assert_eq!(disk.write_errors(), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_file_cache() {
+        let path =
+            std::env::temp_dir().join(format!("erniebot-cache-{}.jsonl", std::process::id()));
+        let cache = FileCache::new(&path).unwrap();
+        cache.put("a", &json!([1.5, 2.0]), None);
+        cache.put("b", &json!(1), None);
+        cache.put("b", &json!(2), None);
+        cache.put("c", &json!(3), None);
+        cache.remove("c");
+        // a partial line left by a crash
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"key\": \"d\", \"val")
+            .unwrap();
+        let reopened = FileCache::new(&path).unwrap();
+        assert_eq!(reopened.len(), 2);
+        reopened.put("f", &json!(6), None);
+        assert_eq!(FileCache::new(&path).unwrap().get("f"), Some(json!(6)));
+        reopened.remove("f");
+        assert_eq!(reopened.get("a"), Some(json!([1.5, 2.0])));
+        assert_eq!(reopened.get("b"), Some(json!(2)));
+        reopened.compact().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        reopened.put("e", &json!(5), None);
+        assert_eq!(FileCache::new(&path).unwrap().len(), 3);
+        reopened.clear();
+        assert!(FileCache::new(&path).unwrap().is_empty());
+        assert_eq!(reopened.write_errors(), 0);
+
+        // a file that cannot be written
+        reopened.state.lock().unwrap().file = std::fs::File::open(&path).unwrap();
+        reopened.put("g", &json!(7), None);
+        assert_eq!(reopened.get("g"), Some(json!(7)));
+        assert_eq!(reopened.write_errors(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_sub_second_ttl() {
+        let dir = std::env::temp_dir().join(format!("erniebot-ttl-{}", std::process::id()));
+        let backends: Vec<Arc<dyn CacheBackend>> = vec![
+            Arc::new(MemoryCache::new(2)),
+            Arc::new(DiskCache::new(&dir).unwrap()),
+            Arc::new(FileCache::new(dir.join("cache.jsonl")).unwrap()),
+        ];
+        for backend in &backends {
+            backend.put("a", &json!(1), Some(Duration::from_millis(500)));
+            assert_eq!(backend.get("a"), Some(json!(1)));
+        }
+        sleep(Duration::from_millis(600));
+        for backend in &backends {
+            assert_eq!(backend.get("a"), None);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_replay_chunks() {
+        let chunks =
+            replay_chunks(&json!({"result": "你好。我是文心一言", "usage": {"total_tokens": 9}}));
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0]["result"], "你好。");
+        assert_eq!(chunks[1]["is_end"], true);
+        assert_eq!(chunks[1]["sentence_id"], 1);
+    }
+}
@@ -0,0 +1,259 @@
+use serde::{Deserialize, Serialize};
+
+/// A web reference returned in `search_info.search_results` when search is enabled.
+///
+/// The `index` is the number used by the inline `^[n]^` markers in the answer.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct SearchResult {
+    pub index: u64,
+    pub url: String,
+    pub title: String,
+}
+
+/** A piece of the answer text together with the sources cited right after it.
+
+The answer returned with `ChatOpt::EnableCitation(true)` looks like `"Rust is fast^[1]^. It is safe^[1][2]^."`.
+It is split into spans at every marker, so the example above gives three spans: `"Rust is fast"` citing 1, `". It is safe"` citing 1 and 2, and `"."` citing nothing.
+*/
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct CitedSpan {
+    /// The text of the span, without citation markers.
+    pub text: String,
+    /// Byte offset of the span in the original answer.
+    pub start: usize,
+    /// Byte offset of the end of the span (exclusive, before the marker) in the original answer.
+    pub end: usize,
+    /// The indices cited by the marker following this span, in order of appearance.
+    pub indices: Vec<u64>,
+    /// The search results matching `indices`. Indices without a matching search result are skipped.
+    pub sources: Vec<SearchResult>,
+}
+
+/// A citation marker found in the answer: its byte range and the indices it refers to.
+struct Marker {
+    start: usize,
+    end: usize,
+    indices: Vec<u64>,
+}
+
+/// Try to parse a marker like `^[1]^` or `^[1][2]^` starting at byte `start` of `text`.
+fn parse_marker(text: &str, start: usize) -> Option<Marker> {
+    let bytes = text.as_bytes();
+    let mut pos = start;
+    if bytes.get(pos) != Some(&b'^') {
+        return None;
+    }
+    pos += 1;
+    let mut indices = Vec::new();
+    while bytes.get(pos) == Some(&b'[') {
+        let digits_start = pos + 1;
+        let mut digits_end = digits_start;
+        while bytes.get(digits_end).is_some_and(|b| b.is_ascii_digit()) {
+            digits_end += 1;
+        }
+        if digits_end == digits_start || bytes.get(digits_end) != Some(&b']') {
+            return None;
+        }
+        indices.push(text[digits_start..digits_end].parse().ok()?);
+        pos = digits_end + 1;
+    }
+    if indices.is_empty() || bytes.get(pos) != Some(&b'^') {
+        return None;
+    }
+    Some(Marker {
+        start,
+        end: pos + 1,
+        indices,
+    })
+}
+
+fn find_markers(text: &str) -> Vec<Marker> {
+    let mut markers = Vec::new();
+    let mut pos = 0;
+    while let Some(offset) = text[pos..].find('^') {
+        let start = pos + offset;
+        match parse_marker(text, start) {
+            Some(marker) => {
+                pos = marker.end;
+                markers.push(marker);
+            }
+            None => pos = start + 1,
+        }
+    }
+    markers
+}
+
+fn resolve_sources(indices: &[u64], search_results: &[SearchResult]) -> Vec<SearchResult> {
+    indices
+        .iter()
+        .filter_map(|index| search_results.iter().find(|r| r.index == *index))
+        .cloned()
+        .collect()
+}
+
+/// Split an answer into spans at its citation markers, resolving each marker against `search_results`.
+pub fn parse_citations(text: &str, search_results: &[SearchResult]) -> Vec<CitedSpan> {
+    let mut spans = Vec::new();
+    let mut span_start = 0;
+    for marker in find_markers(text) {
+        spans.push(CitedSpan {
+            text: text[span_start..marker.start].to_string(),
+            start: span_start,
+            end: marker.start,
+            sources: resolve_sources(&marker.indices, search_results),
+            indices: marker.indices,
+        });
+        span_start = marker.end;
+    }
+    if span_start < text.len() {
+        spans.push(CitedSpan {
+            text: text[span_start..].to_string(),
+            start: span_start,
+            end: text.len(),
+            ..Default::default()
+        });
+    }
+    spans
+}
+
+/// Remove all citation markers from an answer.
+pub fn strip_citations(text: &str) -> String {
+    parse_citations(text, &[])
+        .into_iter()
+        .map(|span| span.text)
+        .collect()
+}
+
+/// escape the characters of a title that would end the text of a Markdown link
+fn escape_link_text(title: &str) -> String {
+    let mut escaped = String::with_capacity(title.len());
+    for c in title.chars() {
+        if matches!(c, '\\' | '[' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// percent-encode the characters of a url that would end the destination of a Markdown link
+fn escape_link_destination(url: &str) -> String {
+    let mut escaped = String::with_capacity(url.len());
+    for c in url.chars() {
+        match c {
+            ' ' => escaped.push_str("%20"),
+            '(' => escaped.push_str("%28"),
+            ')' => escaped.push_str("%29"),
+            '<' => escaped.push_str("%3C"),
+            '>' => escaped.push_str("%3E"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/** Render an answer as Markdown, turning `^[n]^` markers into footnote references and appending the footnotes.
+
+```
+use erniebot_rs::chat::{render_markdown_with_footnotes, SearchResult};
+let search_results = vec![SearchResult {
+    index: 1,
+    url: "https://www.rust-lang.org".to_string(),
+    title: "Rust".to_string(),
+}];
+let markdown = render_markdown_with_footnotes("Rust is fast^[1]^.", &search_results);
+assert_eq!(
+    markdown,
+    "Rust is fast[^1].\n\n[^1]: [Rust](https://www.rust-lang.org)\n"
+);
+```
+Only indices that match a search result are turned into footnotes, the others are dropped from the text. Brackets in titles are escaped, and spaces and parentheses in urls are percent-encoded, so that they do not break the links.
+*/
+pub fn render_markdown_with_footnotes(text: &str, search_results: &[SearchResult]) -> String {
+    let mut body = String::new();
+    let mut cited: Vec<&SearchResult> = Vec::new();
+    for span in parse_citations(text, search_results) {
+        body.push_str(&span.text);
+        for index in &span.indices {
+            if let Some(result) = search_results.iter().find(|r| r.index == *index) {
+                body.push_str(&format!("[^{}]", index));
+                if !cited.iter().any(|r| r.index == *index) {
+                    cited.push(result);
+                }
+            }
+        }
+    }
+    if cited.is_empty() {
+        return body;
+    }
+    cited.sort_by_key(|r| r.index);
+    body.push_str("\n\n");
+    for result in cited {
+        body.push_str(&format!(
+            "[^{}]: [{}]({})\n",
+            result.index,
+            escape_link_text(&result.title),
+            escape_link_destination(&result.url)
+        ));
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_citations, render_markdown_with_footnotes, strip_citations, SearchResult};
+
+    fn search_results() -> Vec<SearchResult> {
+        vec![
+            SearchResult {
+                index: 1,
+                url: "https://example.com/1".to_string(),
+                title: "第一".to_string(),
+            },
+            SearchResult {
+                index: 2,
+                url: "https://example.com/2".to_string(),
+                title: "second".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_parse_citations() {
+        let text = "北京是中国的首都^[1]^。It is big^[1][2]^, 2^3 is 8.";
+        let spans = parse_citations(text, &search_results());
+        assert_eq!(spans.len(), 3);
+        assert_eq!(spans[0].text, "北京是中国的首都");
+        assert_eq!(spans[0].indices, vec![1]);
+        assert_eq!(spans[0].sources[0].title, "第一");
+        assert_eq!(spans[1].text, "。It is big");
+        assert_eq!(spans[1].indices, vec![1, 2]);
+        assert_eq!(spans[1].sources.len(), 2);
+        assert_eq!(spans[2].text, ", 2^3 is 8.");
+        assert!(spans[2].indices.is_empty());
+        assert_eq!(&text[spans[1].start..spans[1].end], spans[1].text);
+    }
+
+    #[test]
+    fn test_strip_citations() {
+        assert_eq!(strip_citations("a^[1]^b^[2][3]^c^[x]^"), "abc^[x]^");
+    }
+
+    #[test]
+    fn test_render_markdown_with_footnotes() {
+        let markdown = render_markdown_with_footnotes("A^[2]^ B^[1][9]^", &search_results());
+        assert_eq!(
+            markdown,
+            "A[^2] B[^1]\n\n[^1]: [第一](https://example.com/1)\n[^2]: [second](https://example.com/2)\n"
+        );
+        let search_results = vec![SearchResult {
+            index: 1,
+            url: "https://baike.baidu.com/item/Rust (语言)".to_string(),
+            title: "[置顶] Rust_百度百科".to_string(),
+        }];
+        assert_eq!(
+            render_markdown_with_footnotes("A^[1]^", &search_results),
+            "A[^1]\n\n[^1]: [\\[置顶\\] Rust_百度百科](https://baike.baidu.com/item/Rust%20%28语言%29)\n"
+        );
+    }
+}
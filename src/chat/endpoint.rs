@@ -1,43 +1,171 @@
-use super::message::Message;
+use super::message::{Message, Role};
 use super::model::ChatModel;
 use super::option::ChatOpt;
 use super::response::{Response, Responses, StreamResponse};
 
+use crate::cache::ResponseCache;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::credentials::CredentialPool;
 use crate::errors::ErnieError;
-use crate::utils::{build_url, get_access_token};
+use crate::metrics::{Metrics, MetricsHook};
+use crate::middleware::Middleware;
+use crate::rate_limiter::RateLimiter;
+use crate::single_flight::SingleFlight;
+use crate::timeout::Timeouts;
+use crate::trace;
+use crate::transport::{event_source_error, EndpointKind, Transport};
+use crate::usage::UsageTracker;
 use json_value_merge::Merge;
-use reqwest_eventsource::{Event, RequestBuilderExt};
+use reqwest_eventsource::{Error as EventSourceError, Event};
 use serde_json::Value;
+use std::sync::Arc;
 use tokio_stream::StreamExt;
-use url::Url;
 
 static CHAT_API_URL: &str = "https://aip.baidubce.com/rpc/2.0/ai_custom/v1/wenxinworkshop/chat/";
+/// The user message sent to ask the model to go on with a truncated answer
+static CONTINUATION_PROMPT: &str = "继续";
 
 /** ChatEndpoint is a struct that represents the chat endpoint of erniebot API
 */
 #[derive(Debug, Clone)]
 pub struct ChatEndpoint {
-    url: Url,
-    access_token: String,
+    transport: Transport,
+    max_continuations: u32,
 }
 
 impl ChatEndpoint {
     /// create a new chat instance using pre-defined model
     pub fn new(model: ChatModel) -> Result<Self, ErnieError> {
         Ok(ChatEndpoint {
-            url: build_url(CHAT_API_URL, model.to_string().as_str())?,
-            access_token: get_access_token()?,
+            transport: Transport::new(EndpointKind::Chat, CHAT_API_URL, &model.to_string())?,
+            max_continuations: 0,
         })
     }
 
     /// create a new chat instance using custom model release on https://aip.baidubce.com/rpc/2.0/ai_custom/v1/wenxinworkshop/chat/{custom_endpoint}
     pub fn new_with_custom_endpoint(endpoint: &str) -> Result<Self, ErnieError> {
         Ok(ChatEndpoint {
-            url: build_url(CHAT_API_URL, endpoint)?,
-            access_token: get_access_token()?,
+            transport: Transport::new(EndpointKind::Chat, CHAT_API_URL, endpoint)?,
+            max_continuations: 0,
         })
     }
 
+    /// create a new chat instance using pre-defined model, spreading the calls over the credentials of a pool
+    pub fn new_with_credential_pool(
+        model: ChatModel,
+        pool: CredentialPool,
+    ) -> Result<Self, ErnieError> {
+        Ok(ChatEndpoint {
+            transport: Transport::new_with_credential_pool(
+                EndpointKind::Chat,
+                CHAT_API_URL,
+                &model.to_string(),
+                pool,
+            )?,
+            max_continuations: 0,
+        })
+    }
+
+    /// create a new chat instance using custom model, spreading the calls over the credentials of a pool
+    pub fn new_with_custom_endpoint_and_credential_pool(
+        endpoint: &str,
+        pool: CredentialPool,
+    ) -> Result<Self, ErnieError> {
+        Ok(ChatEndpoint {
+            transport: Transport::new_with_credential_pool(
+                EndpointKind::Chat,
+                CHAT_API_URL,
+                endpoint,
+                pool,
+            )?,
+            max_continuations: 0,
+        })
+    }
+
+    /// the model name of this endpoint, i.e. the last part of its url (e.g. "completions_pro", or the custom endpoint)
+    pub fn model(&self) -> &str {
+        self.transport.model()
+    }
+
+    /// attach a usage tracker, which records the tokens of every call and enforces its budget before sending requests
+    pub fn with_usage_tracker(mut self, tracker: UsageTracker) -> Self {
+        self.transport.usage_tracker = Some(tracker);
+        self
+    }
+
+    /// attach a rate limiter, which makes calls wait until they fit in the requests and tokens per minute quota of the model
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.transport.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// attach a circuit breaker, which makes calls fail fast with `ErnieError::CircuitOpenError` while the endpoint keeps failing
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.transport.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// attach a metrics hook, which receives the latency, tokens, retries and outcome of every call
+    pub fn with_metrics(mut self, hook: Arc<dyn MetricsHook>) -> Self {
+        self.transport.metrics = Some(Metrics(hook));
+        self
+    }
+
+    /// add a middleware at the end of the chain, see `Middleware`
+    pub fn with_middleware(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.transport.middlewares.0.push(middleware);
+        self
+    }
+
+    /// attach a response cache, which serves identical requests without calling the API again, see `ResponseCache`
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.transport.cache = Some(cache);
+        self
+    }
+
+    /// coalesce concurrent identical `ainvoke` calls into one request, see `SingleFlight`
+    pub fn with_single_flight(mut self, single_flight: SingleFlight) -> Self {
+        self.transport.single_flight = Some(single_flight);
+        self
+    }
+
+    /// set the timeouts of every call made by this endpoint
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.transport.timeouts = timeouts;
+        self
+    }
+
+    /// return a copy of this endpoint for a single call, with the timeouts set in `overrides` taking precedence over the endpoint ones
+    pub fn with_timeout_overrides(&self, overrides: &Timeouts) -> Self {
+        let mut endpoint = self.clone();
+        endpoint.transport.timeouts = self.transport.timeouts.merge(overrides);
+        endpoint
+    }
+
+    /** enable automatic continuation of truncated answers.
+
+    When a response comes back with `is_truncated: true`, the endpoint sends the answer so far back as an assistant message followed by a "continue" prompt, and stitches the continuation onto the answer. This is repeated at most `max_rounds` times. `invoke`, `stream`, `ainvoke` and `astream` all return one logical answer, whose (last chunk's) usage covers every round. Passing 0 disables it, which is the default.
+    */
+    pub fn with_auto_continuation(mut self, max_rounds: u32) -> Self {
+        self.max_continuations = max_rounds;
+        self
+    }
+
+    fn continuation_messages(messages: &[Message], answer: &str) -> Vec<Message> {
+        let mut messages = messages.to_vec();
+        messages.push(Message {
+            role: Role::Assistant,
+            content: answer.to_string(),
+            ..Default::default()
+        });
+        messages.push(Message {
+            role: Role::User,
+            content: CONTINUATION_PROMPT.to_string(),
+            ..Default::default()
+        });
+        messages
+    }
+
     fn generate_body(
         messages: &Vec<Message>,
         options: &Vec<ChatOpt>,
@@ -68,19 +196,24 @@ impl ChatEndpoint {
         messages: &Vec<Message>,
         options: &Vec<ChatOpt>,
     ) -> Result<Response, ErnieError> {
-        let body = ChatEndpoint::generate_body(messages, options, false)?;
-        let response: Value = ureq::post(self.url.as_str())
-            .set("Content-Type", "application/json")
-            .query("access_token", self.access_token.as_str())
-            .send_json(body)
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?
-            .into_json()
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?;
-
-        //if error_code key in response, means RemoteAPIError
-        if response.get("error_code").is_some() {
-            return Err(ErnieError::RemoteAPIError(response.to_string()));
+        let mut response = self.invoke_once(messages, options)?;
+        let mut rounds = 0;
+        while rounds < self.max_continuations && response.is_truncated() {
+            let messages =
+                ChatEndpoint::continuation_messages(messages, &response.get_chat_result()?);
+            response.merge_continuation(self.invoke_once(&messages, options)?);
+            rounds += 1;
         }
+        Ok(response)
+    }
+
+    fn invoke_once(
+        &self,
+        messages: &Vec<Message>,
+        options: &Vec<ChatOpt>,
+    ) -> Result<Response, ErnieError> {
+        let body = ChatEndpoint::generate_body(messages, options, false)?;
+        let response = self.transport.post(body)?;
         Ok(Response::new(response))
     }
     /// stream method is used to send a request to erniebot chat endpoint. This is a blocking method that will return response in multiple chunks from the chat endpoint
@@ -88,17 +221,26 @@ impl ChatEndpoint {
         &self,
         messages: &Vec<Message>,
         options: &Vec<ChatOpt>,
+    ) -> Result<Responses, ErnieError> {
+        let mut responses = self.stream_once(messages, options)?;
+        let mut rounds = 0;
+        while rounds < self.max_continuations && responses.is_truncated() {
+            let messages =
+                ChatEndpoint::continuation_messages(messages, &responses.get_whole_result()?);
+            responses.merge_continuation(self.stream_once(&messages, options)?);
+            rounds += 1;
+        }
+        Ok(responses)
+    }
+
+    fn stream_once(
+        &self,
+        messages: &Vec<Message>,
+        options: &Vec<ChatOpt>,
     ) -> Result<Responses, ErnieError> {
         let body = ChatEndpoint::generate_body(messages, options, true)?;
-        let response: String = ureq::post(self.url.as_str())
-            .set("Content-Type", "application/json")
-            .query("access_token", self.access_token.as_str())
-            .send_json(body)
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?
-            .into_string()
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?;
-        let response = Responses::from_text(&response)?;
-        Ok(response)
+        let response = self.transport.post_for_text(body)?;
+        Responses::from_text(&response)
     }
 
     /// ainvoke method is used to send a request to erniebot chat endpoint. This is an async method that will return a full response from the chat endpoint
@@ -107,66 +249,204 @@ impl ChatEndpoint {
         messages: &Vec<Message>,
         options: &Vec<ChatOpt>,
     ) -> Result<Response, ErnieError> {
-        let body = ChatEndpoint::generate_body(messages, options, false)?;
-        let client = reqwest::Client::new();
-        let response: Value = client
-            .post(self.url.as_str())
-            .header("Content-Type", "application/json")
-            .query(&[("access_token", self.access_token.as_str())])
-            .json(&body)
-            .send()
-            .await
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?
-            .json()
-            .await
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?;
-
-        //if error_code key in response, means RemoteAPIError
-        if response.get("error_code").is_some() {
-            return Err(ErnieError::RemoteAPIError(response.to_string()));
+        let mut response = self.ainvoke_once(messages, options).await?;
+        let mut rounds = 0;
+        while rounds < self.max_continuations && response.is_truncated() {
+            let messages =
+                ChatEndpoint::continuation_messages(messages, &response.get_chat_result()?);
+            response.merge_continuation(self.ainvoke_once(&messages, options).await?);
+            rounds += 1;
         }
+        Ok(response)
+    }
+
+    async fn ainvoke_once(
+        &self,
+        messages: &Vec<Message>,
+        options: &Vec<ChatOpt>,
+    ) -> Result<Response, ErnieError> {
+        let body = ChatEndpoint::generate_body(messages, options, false)?;
+        let response = self.transport.apost(body).await?;
         Ok(Response::new(response))
     }
 
     /// astream method is used to send a request to erniebot chat endpoint. This is an async method that will return response in multiple chunks from the chat endpoint
+    ///
+    /// if the stream ends early because of an error (a timeout, a broken connection...), the error can be taken from `StreamResponse::take_error` once the stream is exhausted
     pub async fn astream(
         &self,
         messages: &Vec<Message>,
         options: &Vec<ChatOpt>,
     ) -> Result<StreamResponse, ErnieError> {
-        let body = ChatEndpoint::generate_body(messages, options, true)?;
-        let client = reqwest::Client::new();
-        let mut event_source = client
-            .post(self.url.as_str())
-            .header("Content-Type", "application/json")
-            .query(&[("access_token", self.access_token.as_str())])
-            .json(&body)
-            .eventsource()
-            .map_err(|e| ErnieError::StreamError(e.to_string()))?;
-        let (sender, stream_response) = StreamResponse::new();
-        tokio::spawn(async move {
-            while let Some(event) = event_source.next().await {
-                if event.is_err() {
-                    break;
+        let mut body = ChatEndpoint::generate_body(messages, options, true)?;
+        let mut call = self.transport.start_call("astream");
+        let cached = self
+            .transport
+            .before_request(&call, &mut body)
+            .and_then(|_| self.transport.cached_chunks(&mut call, &body));
+        match cached {
+            Ok(Some(chunks)) => {
+                let (sender, stream_response) = StreamResponse::new();
+                self.transport.end_call(&call, chunks.last(), None);
+                for chunk in chunks {
+                    let _ = sender.send(Response::new(chunk));
+                }
+                return Ok(stream_response);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                self.transport.end_call(&call, None, Some(&e));
+                return Err(e);
+            }
+        }
+        let mut stream =
+            match trace::instrument(self.transport.event_source(&body), call.span.clone()).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    self.transport.end_call(&call, None, Some(&e));
+                    return Err(e);
                 }
-                let event = event.unwrap();
-                match event {
-                    Event::Open => continue,
-                    Event::Message(message_event) => {
-                        let data = &message_event.data;
-                        match serde_json::from_str(data) {
-                            Ok(value) => {
-                                let response = Response::new(value);
-                                sender.send(response).unwrap();
+            };
+        let (sender, stream_response) = StreamResponse::new();
+        let error = stream_response.error_slot();
+        let endpoint = self.clone();
+        let messages = messages.clone();
+        let options = options.clone();
+        let span = call.span.clone();
+        tokio::spawn(trace::instrument(
+            async move {
+                let timeouts = endpoint.transport.timeouts;
+                let mut answer = String::new();
+                let mut usage: Option<Value> = None;
+                let mut last: Option<Value> = None;
+                let mut rounds = 0;
+                loop {
+                    let mut truncated = false;
+                    let mut first_token = true;
+                    loop {
+                        let timeout = if first_token {
+                            timeouts.first_token
+                        } else {
+                            timeouts.idle
+                        };
+                        let event = match timeout {
+                            Some(timeout) => {
+                                match tokio::time::timeout(timeout, stream.event_source.next())
+                                    .await
+                                {
+                                    Ok(event) => event,
+                                    Err(_) if first_token => {
+                                        *error.lock().unwrap() =
+                                            Some(ErnieError::FirstTokenTimeoutError(format!(
+                                                "no chunk received within {:?}",
+                                                timeout
+                                            )));
+                                        break;
+                                    }
+                                    Err(_) => {
+                                        *error.lock().unwrap() =
+                                            Some(ErnieError::IdleTimeoutError(format!(
+                                            "no chunk received within {:?} after the previous one",
+                                            timeout
+                                        )));
+                                        break;
+                                    }
+                                }
                             }
-                            Err(_) => {
+                            None => stream.event_source.next().await,
+                        };
+                        let event = match event {
+                            None | Some(Err(EventSourceError::StreamEnded)) => break,
+                            Some(Err(e)) => {
+                                *error.lock().unwrap() = Some(event_source_error(e).await);
                                 break;
                             }
+                            Some(Ok(event)) => event,
+                        };
+                        match event {
+                            Event::Open => continue,
+                            Event::Message(message_event) => {
+                                call.first_token();
+                                first_token = false;
+                                let data = &message_event.data;
+                                let _chunk = trace::chunk_span(data, call.start).entered();
+                                let value = serde_json::from_str(data)
+                                    .map_err(|e| ErnieError::GetResponseError(e.to_string()))
+                                    .and_then(|mut value| {
+                                        endpoint.transport.on_stream_chunk(&call, &mut value)?;
+                                        Ok(value)
+                                    });
+                                match value {
+                                    Ok(value) => {
+                                        let mut response = Response::new(value);
+                                        if response.is_end() {
+                                            endpoint
+                                                .transport
+                                                .record_usage(&body, response.get_raw_response());
+                                        }
+                                        if let Ok(result) = response.get_chat_result() {
+                                            answer.push_str(&result);
+                                        }
+                                        if let Some(usage) = &usage {
+                                            response.add_usage(usage);
+                                        }
+                                        if response.is_end() {
+                                            last = Some(response.get_raw_response().clone());
+                                        }
+                                        truncated = rounds < endpoint.max_continuations
+                                            && response.is_end()
+                                            && response.is_truncated();
+                                        if truncated {
+                                            usage = response.get("usage").cloned();
+                                            response.mark_continued();
+                                        }
+                                        if sender.send(response).is_err() {
+                                            stream.close(None);
+                                            endpoint.transport.end_call(&call, last.as_ref(), None);
+                                            return;
+                                        }
+                                        if truncated {
+                                            break;
+                                        }
+                                    }
+                                    Err(e) => {
+                                        *error.lock().unwrap() = Some(e);
+                                        break;
+                                    }
+                                }
+                            }
                         }
                     }
+                    stream.close(error.lock().unwrap().as_ref());
+                    if !truncated {
+                        break;
+                    }
+                    rounds += 1;
+                    let messages = ChatEndpoint::continuation_messages(&messages, &answer);
+                    let next = match ChatEndpoint::generate_body(&messages, &options, true) {
+                        Ok(next_body) => {
+                            body = next_body;
+                            match endpoint.transport.before_request(&call, &mut body) {
+                                Ok(()) => endpoint.transport.event_source(&body).await,
+                                Err(e) => Err(e),
+                            }
+                        }
+                        Err(e) => Err(e),
+                    };
+                    stream = match next {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            *error.lock().unwrap() = Some(e);
+                            break;
+                        }
+                    };
                 }
-            }
-        });
+                endpoint
+                    .transport
+                    .end_call(&call, last.as_ref(), error.lock().unwrap().as_ref());
+            },
+            span,
+        ));
         Ok(stream_response)
     }
 }
@@ -190,4 +470,18 @@ mod tests {
         let s = serde_json::to_string(&result).unwrap();
         println!("{}", s);
     }
+
+    #[test]
+    fn test_continuation_messages() {
+        let messages = vec![Message {
+            role: Role::User,
+            content: "write a long poem".to_string(),
+            ..Default::default()
+        }];
+        let result = ChatEndpoint::continuation_messages(&messages, "the first half");
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[1].role, Role::Assistant);
+        assert_eq!(result[1].content, "the first half");
+        assert_eq!(result[2].role, Role::User);
+    }
 }
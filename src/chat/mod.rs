@@ -1,13 +1,21 @@
+mod citation;
 mod endpoint;
 mod function;
 mod message;
 mod model;
 mod option;
 mod response;
+mod router;
+mod semantic_cache;
 
+pub use citation::{
+    parse_citations, render_markdown_with_footnotes, strip_citations, CitedSpan, SearchResult,
+};
 pub use endpoint::ChatEndpoint;
 pub use function::{Example, Function, FunctionCall, ToolChoice};
 pub use message::{Message, Role};
 pub use model::ChatModel;
 pub use option::{ChatOpt, ResponseFormat};
 pub use response::{Response, Responses, StreamResponse};
+pub use router::{ChatRouter, RoutedResponse};
+pub use semantic_cache::{Eviction, SemanticCache, SemanticHit, SemanticResponse};
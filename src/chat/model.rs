@@ -1,3 +1,4 @@
+use super::ChatOpt;
 use serde::{Deserialize, Serialize};
 use strum_macros::{Display, EnumString};
 
@@ -16,6 +17,23 @@ pub enum ChatModel {
     Ernie40,
 }
 
+impl ChatModel {
+    /// whether the model accepts an option. `ChatRouter` strips the options a fallback model does not accept.
+    pub fn supports(&self, option: &ChatOpt) -> bool {
+        match self {
+            ChatModel::ErnieBotTurbo => matches!(
+                option,
+                ChatOpt::Temperature(_)
+                    | ChatOpt::TopP(_)
+                    | ChatOpt::PenaltyScore(_)
+                    | ChatOpt::System(_)
+                    | ChatOpt::UserId(_)
+            ),
+            ChatModel::ErnieBot | ChatModel::Ernie40 => !matches!(option, ChatOpt::TopK(_)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::ChatModel;
@@ -42,4 +60,13 @@ mod tests {
             ChatModel::Ernie40
         );
     }
+
+    #[test]
+    fn test_chat_model_supports() {
+        use crate::chat::ChatOpt;
+        assert!(ChatModel::Ernie40.supports(&ChatOpt::EnableCitation(true)));
+        assert!(!ChatModel::ErnieBotTurbo.supports(&ChatOpt::EnableCitation(true)));
+        assert!(ChatModel::ErnieBotTurbo.supports(&ChatOpt::Temperature(0.5)));
+        assert!(!ChatModel::ErnieBot.supports(&ChatOpt::TopK(10)));
+    }
 }
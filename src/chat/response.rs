@@ -1,7 +1,9 @@
+use super::citation::{parse_citations, render_markdown_with_footnotes, CitedSpan, SearchResult};
 use super::FunctionCall;
 use crate::errors::ErnieError;
 use serde::{Deserialize, Serialize};
 use serde_json::value;
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc::{self, UnboundedReceiver};
 use tokio_stream::Stream;
 
@@ -72,6 +74,76 @@ impl Response {
         let function_call = serde_json::from_value(value.clone()).ok()?;
         Some(function_call)
     }
+
+    /// whether the answer was cut off because the output hit the length limit
+    pub fn is_truncated(&self) -> bool {
+        self.get("is_truncated")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+    }
+
+    /// whether this is the last chunk of a stream
+    pub fn is_end(&self) -> bool {
+        self.get("is_end")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false)
+    }
+
+    /// add the token counts of `usage` to the usage of this response, if it has one
+    pub(crate) fn add_usage(&mut self, usage: &value::Value) {
+        let Some(own_usage) = self.get_mut("usage").and_then(|v| v.as_object_mut()) else {
+            return;
+        };
+        for key in ["prompt_tokens", "completion_tokens", "total_tokens"] {
+            let added = usage.get(key).and_then(|v| v.as_u64()).unwrap_or(0);
+            if let Some(tokens) = own_usage.get(key).and_then(|v| v.as_u64()) {
+                own_usage.insert(key.to_string(), (tokens + added).into());
+            }
+        }
+    }
+
+    /// mark the last chunk of a truncated round as not being the end of the (continued) answer
+    pub(crate) fn mark_continued(&mut self) {
+        if let Some(object) = self.raw_response.as_object_mut() {
+            object.insert("is_end".to_string(), false.into());
+            object.insert("is_truncated".to_string(), false.into());
+        }
+    }
+
+    /// join the answer of a continuation request after this one: `result` is concatenated, `usage` is summed and the other fields are taken from `next`
+    pub(crate) fn merge_continuation(&mut self, next: Response) {
+        let mut result = self.get_chat_result().unwrap_or_default();
+        result.push_str(&next.get_chat_result().unwrap_or_default());
+        let usage = self.get("usage").cloned();
+        self.raw_response = next.raw_response;
+        if let Some(object) = self.raw_response.as_object_mut() {
+            object.insert("result".to_string(), result.into());
+        }
+        if let Some(usage) = usage {
+            self.add_usage(&usage);
+        }
+    }
+
+    /// get the web references in `search_info.search_results`, returned when search is enabled
+    pub fn get_search_results(&self) -> Option<Vec<SearchResult>> {
+        let value = self.get("search_info")?.get("search_results")?;
+        let search_results = serde_json::from_value(value.clone()).ok()?;
+        Some(search_results)
+    }
+
+    /// split the chat result into spans at its `^[n]^` citation markers, resolving each marker to its search result
+    pub fn get_cited_spans(&self) -> Result<Vec<CitedSpan>, ErnieError> {
+        let result = self.get_chat_result()?;
+        let search_results = self.get_search_results().unwrap_or_default();
+        Ok(parse_citations(&result, &search_results))
+    }
+
+    /// render the chat result as Markdown, with citation markers turned into footnote links
+    pub fn get_markdown_with_footnotes(&self) -> Result<String, ErnieError> {
+        let result = self.get_chat_result()?;
+        let search_results = self.get_search_results().unwrap_or_default();
+        Ok(render_markdown_with_footnotes(&result, &search_results))
+    }
 }
 
 /// Responses is using for sync stream response.
@@ -125,16 +197,106 @@ impl Responses {
         }
         Ok(result)
     }
+
+    /// get the last chunk of the stream, which carries the usage and the end state
+    pub fn last(&self) -> Option<&Response> {
+        self.responses.last()
+    }
+
+    /// whether the answer was cut off because the output hit the length limit
+    pub fn is_truncated(&self) -> bool {
+        self.responses
+            .last()
+            .map(|response| response.is_truncated())
+            .unwrap_or(false)
+    }
+
+    /// get tokens used by prompt, as reported by the last chunk. With automatic continuation this covers all rounds.
+    pub fn get_prompt_tokens(&self) -> Option<u64> {
+        self.responses.last()?.get_prompt_tokens()
+    }
+
+    /// get tokens used by completion, as reported by the last chunk. With automatic continuation this covers all rounds.
+    pub fn get_completion_tokens(&self) -> Option<u64> {
+        self.responses.last()?.get_completion_tokens()
+    }
+
+    /// get total tokens used, as reported by the last chunk. With automatic continuation this covers all rounds.
+    pub fn get_total_tokens(&self) -> Option<u64> {
+        self.responses.last()?.get_total_tokens()
+    }
+
+    /// append the chunks of a continuation request, carrying the usage of the previous rounds over
+    pub(crate) fn merge_continuation(&mut self, next: Responses) {
+        let usage = self.responses.last().and_then(|r| r.get("usage").cloned());
+        if let Some(last) = self.responses.last_mut() {
+            last.mark_continued();
+        }
+        for mut response in next.responses {
+            if let Some(usage) = &usage {
+                response.add_usage(usage);
+            }
+            self.responses.push(response);
+        }
+    }
+
+    /// get the web references of the stream. They are carried by the chunks, the first chunk that has them is used.
+    pub fn get_search_results(&self) -> Option<Vec<SearchResult>> {
+        self.responses
+            .iter()
+            .find_map(|response| response.get_search_results())
+    }
+
+    /// split the whole chat result into spans at its citation markers, see `Response::get_cited_spans`
+    pub fn get_cited_spans(&self) -> Result<Vec<CitedSpan>, ErnieError> {
+        let result = self.get_whole_result()?;
+        let search_results = self.get_search_results().unwrap_or_default();
+        Ok(parse_citations(&result, &search_results))
+    }
+
+    /// render the whole chat result as Markdown with footnote links, see `Response::get_markdown_with_footnotes`
+    pub fn get_markdown_with_footnotes(&self) -> Result<String, ErnieError> {
+        let result = self.get_whole_result()?;
+        let search_results = self.get_search_results().unwrap_or_default();
+        Ok(render_markdown_with_footnotes(&result, &search_results))
+    }
 }
 
 /// StreamResponse is a struct that represents the response of erniebot API in async stream case.
 pub struct StreamResponse {
     receiver: UnboundedReceiver<Response>,
+    error: Arc<Mutex<Option<ErnieError>>>,
+    first: Option<Response>,
 }
 impl StreamResponse {
     pub fn new() -> (mpsc::UnboundedSender<Response>, Self) {
         let (sender, receiver) = mpsc::unbounded_channel();
-        (sender, Self { receiver })
+        (
+            sender,
+            Self {
+                receiver,
+                error: Arc::new(Mutex::new(None)),
+                first: None,
+            },
+        )
+    }
+
+    /// the slot where the producer of the stream stores the error that ended it early
+    pub(crate) fn error_slot(&self) -> Arc<Mutex<Option<ErnieError>>> {
+        self.error.clone()
+    }
+
+    /// wait for the first chunk, keeping it to be yielded first. Returns false when the stream ended without any chunk.
+    pub(crate) async fn wait_first_chunk(&mut self) -> bool {
+        if self.first.is_none() {
+            self.first = self.receiver.recv().await;
+        }
+        self.first.is_some()
+    }
+
+    /// take the error that ended the stream early (e.g. `ErnieError::IdleTimeoutError`), if any. Check it once the stream returned `None`.
+    pub fn take_error(&self) -> Option<ErnieError> {
+        self.error.lock().unwrap().take()
     }
 }
 
@@ -145,6 +307,69 @@ impl Stream for StreamResponse {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
+        if let Some(first) = self.first.take() {
+            return std::task::Poll::Ready(Some(first));
+        }
         self.receiver.poll_recv(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Response, Responses};
+    #[test]
+    fn test_get_search_results() {
+        let response = Response::new(serde_json::json!({
+            "result": "Rust 1.0 was released in 2015^[1]^.",
+            "search_info": {
+                "search_results": [
+                    {"index": 1, "url": "https://blog.rust-lang.org", "title": "Announcing Rust 1.0"}
+                ]
+            }
+        }));
+        let search_results = response.get_search_results().unwrap();
+        assert_eq!(search_results[0].index, 1);
+        assert_eq!(search_results[0].title, "Announcing Rust 1.0");
+        let spans = response.get_cited_spans().unwrap();
+        assert_eq!(spans[0].sources, search_results);
+        assert_eq!(
+            response.get_markdown_with_footnotes().unwrap(),
+            "Rust 1.0 was released in 2015[^1].\n\n[^1]: [Announcing Rust 1.0](https://blog.rust-lang.org)\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_continuation() {
+        let mut response = Response::new(serde_json::json!({
+            "result": "床前明月光，",
+            "is_truncated": true,
+            "usage": {"prompt_tokens": 5, "completion_tokens": 6, "total_tokens": 11}
+        }));
+        response.merge_continuation(Response::new(serde_json::json!({
+            "result": "疑是地上霜。",
+            "is_truncated": false,
+            "usage": {"prompt_tokens": 12, "completion_tokens": 6, "total_tokens": 18}
+        })));
+        assert_eq!(
+            response.get_chat_result().unwrap(),
+            "床前明月光，疑是地上霜。"
+        );
+        assert!(!response.is_truncated());
+        assert_eq!(response.get_prompt_tokens(), Some(17));
+        assert_eq!(response.get_total_tokens(), Some(29));
+    }
+
+    #[test]
+    fn test_responses_merge_continuation() {
+        let text = "data: {\"result\": \"a\", \"is_end\": false}\n\n\
+            data: {\"result\": \"b\", \"is_end\": true, \"is_truncated\": true, \"usage\": {\"prompt_tokens\": 1, \"completion_tokens\": 2, \"total_tokens\": 3}}\n\n";
+        let mut responses = Responses::from_text(text).unwrap();
+        let next = "data: {\"result\": \"c\", \"is_end\": true, \"is_truncated\": false, \"usage\": {\"prompt_tokens\": 4, \"completion_tokens\": 1, \"total_tokens\": 5}}\n\n";
+        responses.merge_continuation(Responses::from_text(next).unwrap());
+        assert_eq!(responses.get_whole_result().unwrap(), "abc");
+        assert!(!responses.is_truncated());
+        assert_eq!(responses.get_completion_tokens(), Some(3));
+        assert_eq!(responses.get_total_tokens(), Some(8));
+        assert!(!responses.responses[1].is_end());
+    }
+}
@@ -0,0 +1,193 @@
+use super::endpoint::ChatEndpoint;
+use super::message::Message;
+use super::model::ChatModel;
+use super::option::ChatOpt;
+use super::response::{Response, Responses, StreamResponse};
+use crate::errors::ErnieError;
+use std::str::FromStr;
+
+/// The answer of a `ChatRouter`, together with the model that produced it.
+#[derive(Debug)]
+pub struct RoutedResponse<T> {
+    pub response: T,
+    /// the model name of the endpoint that answered (see `ChatEndpoint::model`)
+    pub model: String,
+    /// the errors of the endpoints tried before, in order, with their model name
+    pub failures: Vec<(String, ErnieError)>,
+}
+
+/** ChatRouter tries an ordered list of chat endpoints, falling back to the next one when a call fails with a retryable error (see `ErnieError::is_retryable`).
+
+Options that a fallback model does not accept (see `ChatModel::supports`) are stripped before calling it. Options for custom endpoints are passed unchanged.
+```no_run
+use erniebot_rs::chat::{ChatModel, ChatRouter, Message, Role};
+let router = ChatRouter::new()
+    .with_model(ChatModel::Ernie40)
+    .unwrap()
+    .with_model(ChatModel::ErnieBot)
+    .unwrap()
+    .with_model(ChatModel::ErnieBotTurbo)
+    .unwrap();
+let messages = vec![Message {
+    role: Role::User,
+    content: "hello".to_string(),
+    ..Default::default()
+}];
+let routed = router.invoke(&messages, &vec![]).unwrap();
+println!("{} answered: {}", routed.model, routed.response.get_chat_result().unwrap());
+```
+*/
+#[derive(Debug, Clone, Default)]
+pub struct ChatRouter {
+    endpoints: Vec<ChatEndpoint>,
+}
+
+impl ChatRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add an endpoint at the end of the chain. Use this to add endpoints configured with timeouts, rate limiters...
+    pub fn with_endpoint(mut self, endpoint: ChatEndpoint) -> Self {
+        self.endpoints.push(endpoint);
+        self
+    }
+
+    /// add a pre-defined model at the end of the chain
+    pub fn with_model(self, model: ChatModel) -> Result<Self, ErnieError> {
+        Ok(self.with_endpoint(ChatEndpoint::new(model)?))
+    }
+
+    /// add a custom endpoint at the end of the chain
+    pub fn with_custom_endpoint(self, endpoint: &str) -> Result<Self, ErnieError> {
+        Ok(self.with_endpoint(ChatEndpoint::new_with_custom_endpoint(endpoint)?))
+    }
+
+    pub fn endpoints(&self) -> &[ChatEndpoint] {
+        &self.endpoints
+    }
+
+    fn options_for(endpoint: &ChatEndpoint, options: &[ChatOpt]) -> Vec<ChatOpt> {
+        match ChatModel::from_str(endpoint.model()) {
+            Ok(model) => options
+                .iter()
+                .filter(|option| model.supports(option))
+                .cloned()
+                .collect(),
+            Err(_) => options.to_vec(),
+        }
+    }
+
+    fn no_endpoint() -> ErnieError {
+        ErnieError::InvokeError("no endpoint in the chat router".to_string())
+    }
+
+    fn route<T>(
+        &self,
+        options: &[ChatOpt],
+        call: impl Fn(&ChatEndpoint, &Vec<ChatOpt>) -> Result<T, ErnieError>,
+    ) -> Result<RoutedResponse<T>, ErnieError> {
+        let mut failures = Vec::new();
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            let options = ChatRouter::options_for(endpoint, options);
+            match call(endpoint, &options) {
+                Ok(response) => {
+                    return Ok(RoutedResponse {
+                        response,
+                        model: endpoint.model().to_string(),
+                        failures,
+                    })
+                }
+                Err(e) if e.is_retryable() && i + 1 < self.endpoints.len() => {
+                    failures.push((endpoint.model().to_string(), e))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ChatRouter::no_endpoint())
+    }
+
+    /// blocking non-stream call, see `ChatEndpoint::invoke`
+    pub fn invoke(
+        &self,
+        messages: &Vec<Message>,
+        options: &[ChatOpt],
+    ) -> Result<RoutedResponse<Response>, ErnieError> {
+        self.route(options, |endpoint, options| {
+            endpoint.invoke(messages, options)
+        })
+    }
+
+    /// blocking stream call, see `ChatEndpoint::stream`
+    pub fn stream(
+        &self,
+        messages: &Vec<Message>,
+        options: &[ChatOpt],
+    ) -> Result<RoutedResponse<Responses>, ErnieError> {
+        self.route(options, |endpoint, options| {
+            endpoint.stream(messages, options)
+        })
+    }
+
+    /// async non-stream call, see `ChatEndpoint::ainvoke`
+    pub async fn ainvoke(
+        &self,
+        messages: &Vec<Message>,
+        options: &[ChatOpt],
+    ) -> Result<RoutedResponse<Response>, ErnieError> {
+        let mut failures = Vec::new();
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            let options = ChatRouter::options_for(endpoint, options);
+            match endpoint.ainvoke(messages, &options).await {
+                Ok(response) => {
+                    return Ok(RoutedResponse {
+                        response,
+                        model: endpoint.model().to_string(),
+                        failures,
+                    })
+                }
+                Err(e) if e.is_retryable() && i + 1 < self.endpoints.len() => {
+                    failures.push((endpoint.model().to_string(), e))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ChatRouter::no_endpoint())
+    }
+
+    /// async stream call, see `ChatEndpoint::astream`. The router waits for the first chunk of each endpoint, so that a stream failing before answering anything falls back to the next endpoint.
+    pub async fn astream(
+        &self,
+        messages: &Vec<Message>,
+        options: &[ChatOpt],
+    ) -> Result<RoutedResponse<StreamResponse>, ErnieError> {
+        let mut failures = Vec::new();
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            let options = ChatRouter::options_for(endpoint, options);
+            let result = match endpoint.astream(messages, &options).await {
+                Ok(mut stream) => {
+                    if stream.wait_first_chunk().await {
+                        Ok(stream)
+                    } else {
+                        stream.take_error().map_or(Ok(stream), Err)
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(response) => {
+                    return Ok(RoutedResponse {
+                        response,
+                        model: endpoint.model().to_string(),
+                        failures,
+                    })
+                }
+                Err(e) if e.is_retryable() && i + 1 < self.endpoints.len() => {
+                    failures.push((endpoint.model().to_string(), e))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ChatRouter::no_endpoint())
+    }
+}
@@ -0,0 +1,418 @@
+use super::endpoint::ChatEndpoint;
+use super::message::{Message, Role};
+use super::option::ChatOpt;
+use super::response::Response;
+use crate::cache::ResponseCache;
+use crate::embedding::{l2_normalize, similarity, EmbeddingEndpoint, EmbeddingResponse};
+use crate::errors::ErnieError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Which entry a full `SemanticCache` drops to make room for a new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Eviction {
+    /// the entry that was served the longest time ago
+    #[default]
+    LeastRecentlyUsed,
+    /// the entry that was served the fewest times
+    LeastFrequentlyUsed,
+    /// the entry that was stored first
+    Oldest,
+}
+
+/// The cached prompt that matched an incoming one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemanticHit {
+    pub prompt: String,
+    /// cosine similarity between the embeddings of the two prompts
+    pub similarity: f64,
+}
+
+/// The answer of a `SemanticCache`.
+#[derive(Debug, Clone)]
+pub struct SemanticResponse {
+    pub response: Response,
+    /// the matching cached prompt, `None` when the chat endpoint answered
+    pub hit: Option<SemanticHit>,
+}
+
+#[derive(Debug)]
+struct SemanticEntry {
+    /// fingerprint of everything but the prompt: model, history and options
+    scope: String,
+    prompt: String,
+    /// normalized embedding of the prompt
+    vector: Vec<f64>,
+    response: Response,
+    created_at: Instant,
+    last_used: Instant,
+    hits: u64,
+}
+
+/// The local index of a semantic cache, searched by brute force.
+#[derive(Debug)]
+struct SemanticIndex {
+    entries: Vec<SemanticEntry>,
+    capacity: usize,
+    eviction: Eviction,
+    ttl: Option<Duration>,
+}
+
+impl SemanticIndex {
+    fn remove_expired(&mut self, now: Instant) {
+        if let Some(ttl) = self.ttl {
+            self.entries
+                .retain(|entry| now.duration_since(entry.created_at) < ttl);
+        }
+    }
+
+    /// the most similar entry of `scope` at or above `threshold`. Entries of another dimension, embedded by another model, never match.
+    fn lookup(
+        &mut self,
+        scope: &str,
+        vector: &[f64],
+        threshold: f64,
+    ) -> Option<(Response, SemanticHit)> {
+        let now = Instant::now();
+        self.remove_expired(now);
+        let (entry, similarity) = self
+            .entries
+            .iter_mut()
+            .filter(|entry| entry.scope == scope && entry.vector.len() == vector.len())
+            .map(|entry| {
+                let similarity = similarity(&entry.vector, vector);
+                (entry, similarity)
+            })
+            .filter(|(_, similarity)| *similarity >= threshold)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        entry.last_used = now;
+        entry.hits += 1;
+        Some((
+            entry.response.clone(),
+            SemanticHit {
+                prompt: entry.prompt.clone(),
+                similarity,
+            },
+        ))
+    }
+
+    fn insert(&mut self, scope: String, prompt: String, vector: Vec<f64>, response: Response) {
+        let now = Instant::now();
+        self.remove_expired(now);
+        while self.entries.len() >= self.capacity {
+            let victim = self
+                .entries
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, entry)| match self.eviction {
+                    Eviction::LeastRecentlyUsed => (0, entry.last_used),
+                    Eviction::LeastFrequentlyUsed => (entry.hits, entry.last_used),
+                    Eviction::Oldest => (0, entry.created_at),
+                })
+                .map(|(index, _)| index);
+            match victim {
+                Some(index) => self.entries.swap_remove(index),
+                None => break,
+            };
+        }
+        self.entries.push(SemanticEntry {
+            scope,
+            prompt,
+            vector,
+            response,
+            created_at: now,
+            last_used: now,
+            hits: 0,
+        });
+    }
+}
+
+/** SemanticCache answers chat requests whose last user prompt is close enough to a prompt answered before, without calling the chat model.
+
+Incoming prompts are embedded with the given `EmbeddingEndpoint`, and compared by cosine similarity with the cached prompts that share the same chat model, conversation history and options (except `ChatOpt::UserId`). When the best match reaches `threshold`, its stored answer is returned; otherwise the chat endpoint answers and the answer is stored.
+The cache is cheap to clone and all clones share the same entries.
+```no_run
+use erniebot_rs::chat::{ChatEndpoint, ChatModel, Message, Role, SemanticCache};
+use erniebot_rs::embedding::{EmbeddingEndpoint, EmbeddingModel};
+let cache = SemanticCache::new(
+    ChatEndpoint::new(ChatModel::ErnieBotTurbo).unwrap(),
+    EmbeddingEndpoint::new(EmbeddingModel::EmbeddingV1).unwrap(),
+)
+.with_threshold(0.92)
+.with_capacity(10_000);
+let messages = vec![Message {
+    role: Role::User,
+    content: "怎么修改密码？".to_string(),
+    ..Default::default()
+}];
+let answer = cache.invoke(&messages, &vec![]).unwrap();
+println!("cached: {}", answer.hit.is_some());
+```
+*/
+#[derive(Debug, Clone)]
+pub struct SemanticCache {
+    chat: ChatEndpoint,
+    embedding: EmbeddingEndpoint,
+    threshold: f64,
+    index: Arc<Mutex<SemanticIndex>>,
+}
+
+impl SemanticCache {
+    /// create a cache holding at most 1000 answers, matching prompts with a similarity of at least 0.95
+    pub fn new(chat: ChatEndpoint, embedding: EmbeddingEndpoint) -> Self {
+        SemanticCache {
+            chat,
+            embedding,
+            threshold: 0.95,
+            index: Arc::new(Mutex::new(SemanticIndex {
+                entries: Vec::new(),
+                capacity: 1000,
+                eviction: Eviction::default(),
+                ttl: None,
+            })),
+        }
+    }
+
+    /// set the minimal cosine similarity, between -1 and 1, for a cached prompt to match
+    pub fn with_threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// set how many answers the cache holds
+    pub fn with_capacity(self, capacity: usize) -> Self {
+        self.index.lock().unwrap().capacity = capacity.max(1);
+        self
+    }
+
+    /// set which answer is dropped when the cache is full
+    pub fn with_eviction(self, eviction: Eviction) -> Self {
+        self.index.lock().unwrap().eviction = eviction;
+        self
+    }
+
+    /// set how long answers stay in the cache
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        self.index.lock().unwrap().ttl = Some(ttl);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.index.lock().unwrap().entries.clear();
+    }
+
+    /// the prompt to embed (the last user message) and the fingerprint of the rest of the request
+    fn split_request(
+        &self,
+        messages: &[Message],
+        options: &[ChatOpt],
+    ) -> Option<(String, String, Option<String>)> {
+        let (last, history) = messages.split_last()?;
+        if last.role != Role::User || last.content.is_empty() {
+            return None;
+        }
+        let mut user_id = None;
+        let options: Vec<&ChatOpt> = options
+            .iter()
+            .filter(|option| match option {
+                ChatOpt::UserId(id) => {
+                    user_id = Some(id.clone());
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        let scope = ResponseCache::key(
+            self.chat.model(),
+            &serde_json::json!({"history": history, "options": options}),
+        );
+        Some((last.content.clone(), scope, user_id))
+    }
+
+    fn first_vector(response: EmbeddingResponse) -> Result<Vec<f64>, ErnieError> {
+        let mut vector = response.get_embedding_results()?.into_iter().next().ok_or(
+            ErnieError::GetResponseError("no embedding in the response".to_string()),
+        )?;
+        l2_normalize(&mut vector);
+        Ok(vector)
+    }
+
+    /// whether an answer is worth storing: a complete text answer
+    fn cacheable(response: &Response) -> bool {
+        response
+            .get_chat_result()
+            .is_ok_and(|result| !result.is_empty())
+            && !response.is_truncated()
+    }
+
+    /// blocking call, see `ChatEndpoint::invoke`
+    pub fn invoke(
+        &self,
+        messages: &Vec<Message>,
+        options: &Vec<ChatOpt>,
+    ) -> Result<SemanticResponse, ErnieError> {
+        let Some((prompt, scope, user_id)) = self.split_request(messages, options) else {
+            return Ok(SemanticResponse {
+                response: self.chat.invoke(messages, options)?,
+                hit: None,
+            });
+        };
+        let vector = SemanticCache::first_vector(
+            self.embedding
+                .invoke(&vec![prompt.clone()], user_id.as_deref())?,
+        )?;
+        let hit = self
+            .index
+            .lock()
+            .unwrap()
+            .lookup(&scope, &vector, self.threshold);
+        if let Some((response, hit)) = hit {
+            return Ok(SemanticResponse {
+                response,
+                hit: Some(hit),
+            });
+        }
+        let response = self.chat.invoke(messages, options)?;
+        if SemanticCache::cacheable(&response) {
+            self.index
+                .lock()
+                .unwrap()
+                .insert(scope, prompt, vector, response.clone());
+        }
+        Ok(SemanticResponse {
+            response,
+            hit: None,
+        })
+    }
+
+    /// async call, see `ChatEndpoint::ainvoke`
+    pub async fn ainvoke(
+        &self,
+        messages: &Vec<Message>,
+        options: &Vec<ChatOpt>,
+    ) -> Result<SemanticResponse, ErnieError> {
+        let Some((prompt, scope, user_id)) = self.split_request(messages, options) else {
+            return Ok(SemanticResponse {
+                response: self.chat.ainvoke(messages, options).await?,
+                hit: None,
+            });
+        };
+        let vector = SemanticCache::first_vector(
+            self.embedding
+                .ainvoke(&vec![prompt.clone()], user_id.as_deref())
+                .await?,
+        )?;
+        let hit = self
+            .index
+            .lock()
+            .unwrap()
+            .lookup(&scope, &vector, self.threshold);
+        if let Some((response, hit)) = hit {
+            return Ok(SemanticResponse {
+                response,
+                hit: Some(hit),
+            });
+        }
+        let response = self.chat.ainvoke(messages, options).await?;
+        if SemanticCache::cacheable(&response) {
+            self.index
+                .lock()
+                .unwrap()
+                .insert(scope, prompt, vector, response.clone());
+        }
+        Ok(SemanticResponse {
+            response,
+            hit: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Eviction, SemanticIndex};
+    use crate::chat::Response;
+    use crate::embedding::l2_normalize;
+    use serde_json::json;
+
+    fn normalize(mut vector: Vec<f64>) -> Vec<f64> {
+        l2_normalize(&mut vector);
+        vector
+    }
+
+    fn index(capacity: usize, eviction: Eviction) -> SemanticIndex {
+        SemanticIndex {
+            entries: Vec::new(),
+            capacity,
+            eviction,
+            ttl: None,
+        }
+    }
+
+    fn answer(text: &str) -> Response {
+        Response::new(json!({"result": text}))
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut index = index(10, Eviction::LeastRecentlyUsed);
+        index.insert(
+            "scope".to_string(),
+            "how to reset my password".to_string(),
+            normalize(vec![1.0, 0.0, 0.1]),
+            answer("click on forgot password"),
+        );
+        let (response, hit) = index
+            .lookup("scope", &normalize(vec![1.0, 0.05, 0.1]), 0.95)
+            .unwrap();
+        assert_eq!(
+            response.get_chat_result().unwrap(),
+            "click on forgot password"
+        );
+        assert!(hit.similarity > 0.99);
+        assert!(index
+            .lookup("scope", &normalize(vec![0.0, 1.0, 0.0]), 0.95)
+            .is_none());
+        // another history or options
+        assert!(index
+            .lookup("other", &normalize(vec![1.0, 0.0, 0.1]), 0.95)
+            .is_none());
+        // an embedding of another model, sharing the first components
+        assert!(index
+            .lookup("scope", &normalize(vec![1.0, 0.0]), 0.95)
+            .is_none());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut index = index(2, Eviction::LeastFrequentlyUsed);
+        index.insert(
+            "s".to_string(),
+            "a".to_string(),
+            vec![1.0, 0.0],
+            answer("a"),
+        );
+        index.insert(
+            "s".to_string(),
+            "b".to_string(),
+            vec![0.0, 1.0],
+            answer("b"),
+        );
+        index.lookup("s", &[1.0, 0.0], 0.9).unwrap();
+        index.insert(
+            "s".to_string(),
+            "c".to_string(),
+            vec![-1.0, 0.0],
+            answer("c"),
+        );
+        let prompts: Vec<&str> = index.entries.iter().map(|e| e.prompt.as_str()).collect();
+        assert!(prompts.contains(&"a") && prompts.contains(&"c") && !prompts.contains(&"b"));
+    }
+}
@@ -0,0 +1,422 @@
+use crate::errors::ErnieError;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The state of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// calls go through, and their outcomes are counted
+    Closed,
+    /// calls fail fast with `ErnieError::CircuitOpenError`
+    Open,
+    /// a few probe calls go through to find out whether the endpoint recovered
+    HalfOpen,
+}
+
+type StateListener = Arc<dyn Fn(CircuitState, CircuitState) + Send + Sync>;
+
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    /// outcomes of the calls in the window while closed, `true` for a failure
+    outcomes: VecDeque<(Instant, bool)>,
+    opened_at: Option<Instant>,
+    /// probes let through since the circuit became half-open
+    probes: u32,
+    /// probes that succeeded since the circuit became half-open
+    probe_successes: u32,
+}
+
+impl BreakerState {
+    fn transition(
+        &mut self,
+        to: CircuitState,
+        now: Instant,
+    ) -> Option<(CircuitState, CircuitState)> {
+        let from = self.state;
+        if from == to {
+            return None;
+        }
+        self.state = to;
+        self.probes = 0;
+        self.probe_successes = 0;
+        match to {
+            CircuitState::Open => self.opened_at = Some(now),
+            CircuitState::Closed => {
+                self.outcomes.clear();
+                self.opened_at = None;
+            }
+            CircuitState::HalfOpen => {}
+        }
+        Some((from, to))
+    }
+}
+
+/** CircuitBreaker stops sending requests to an endpoint that keeps failing.
+
+While closed, the breaker counts the outcomes of the calls within a sliding window. Once at least `minimum_calls` calls were made in the window and the share of failures reaches `failure_rate`, the circuit opens and calls fail fast with `ErnieError::CircuitOpenError`, without waiting for the server.
+After `open_duration`, the circuit becomes half-open and lets `half_open_calls` probe calls through: if they all succeed the circuit closes again, and the first failure opens it for another `open_duration`.
+
+Only errors telling that the endpoint is unhealthy count as failures: server side errors, network errors and timeouts (see `ErnieError::is_retryable`), except rate limit errors.
+The breaker is cheap to clone and all clones share the same state. Attach it to an endpoint with `with_circuit_breaker`, one breaker per endpoint.
+```
+use erniebot_rs::circuit_breaker::CircuitBreaker;
+use std::time::Duration;
+let breaker = CircuitBreaker::new()
+    .with_failure_rate(0.5)
+    .with_window(Duration::from_secs(30))
+    .with_open_duration(Duration::from_secs(10))
+    .on_state_change(|from, to| println!("circuit went from {:?} to {:?}", from, to));
+```
+*/
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    state: Arc<Mutex<BreakerState>>,
+    failure_rate: f64,
+    window: Duration,
+    minimum_calls: u32,
+    open_duration: Duration,
+    half_open_calls: u32,
+    listeners: Vec<StateListener>,
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("state", &self.state)
+            .field("failure_rate", &self.failure_rate)
+            .field("window", &self.window)
+            .field("minimum_calls", &self.minimum_calls)
+            .field("open_duration", &self.open_duration)
+            .field("half_open_calls", &self.half_open_calls)
+            .field("listeners", &self.listeners.len())
+            .finish()
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new()
+    }
+}
+
+impl CircuitBreaker {
+    /// create a closed breaker opening at 50% of failures over at least 10 calls in 60 seconds, and staying open for 30 seconds
+    pub fn new() -> Self {
+        CircuitBreaker {
+            state: Arc::new(Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                outcomes: VecDeque::new(),
+                opened_at: None,
+                probes: 0,
+                probe_successes: 0,
+            })),
+            failure_rate: 0.5,
+            window: Duration::from_secs(60),
+            minimum_calls: 10,
+            open_duration: Duration::from_secs(30),
+            half_open_calls: 1,
+            listeners: Vec::new(),
+        }
+    }
+
+    /// set the share of failed calls, between 0 and 1, at which the circuit opens
+    pub fn with_failure_rate(mut self, failure_rate: f64) -> Self {
+        self.failure_rate = failure_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// set the sliding window over which the failure rate is computed
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// set how many calls the window must hold before the circuit may open
+    pub fn with_minimum_calls(mut self, minimum_calls: u32) -> Self {
+        self.minimum_calls = minimum_calls.max(1);
+        self
+    }
+
+    /// set how long the circuit stays open before letting probe calls through
+    pub fn with_open_duration(mut self, open_duration: Duration) -> Self {
+        self.open_duration = open_duration;
+        self
+    }
+
+    /// set how many probe calls must succeed in the half-open state to close the circuit
+    pub fn with_half_open_calls(mut self, half_open_calls: u32) -> Self {
+        self.half_open_calls = half_open_calls.max(1);
+        self
+    }
+
+    /// call `listener` with the previous and the new state on every state change. Listeners are called outside of the internal lock, so they may use the breaker.
+    pub fn on_state_change(
+        mut self,
+        listener: impl Fn(CircuitState, CircuitState) + Send + Sync + 'static,
+    ) -> Self {
+        self.listeners.push(Arc::new(listener));
+        self
+    }
+
+    /// the current state. An open circuit whose `open_duration` elapsed is reported as half-open.
+    pub fn state(&self) -> CircuitState {
+        let state = self.state.lock().unwrap();
+        match state.opened_at {
+            Some(opened_at)
+                if state.state == CircuitState::Open
+                    && opened_at.elapsed() >= self.open_duration =>
+            {
+                CircuitState::HalfOpen
+            }
+            _ => state.state,
+        }
+    }
+
+    fn notify(&self, transition: Option<(CircuitState, CircuitState)>) {
+        if let Some((from, to)) = transition {
+            for listener in &self.listeners {
+                listener(from, to);
+            }
+        }
+    }
+
+    /// let a call through, or fail fast if the circuit is open. The outcome of the call is recorded with `Permit::record`.
+    pub(crate) fn acquire(&self) -> Result<Permit, ErnieError> {
+        let now = Instant::now();
+        let (result, transition) = {
+            let mut state = self.state.lock().unwrap();
+            let mut transition = None;
+            if state.state == CircuitState::Open
+                && state
+                    .opened_at
+                    .is_some_and(|opened_at| now.duration_since(opened_at) >= self.open_duration)
+            {
+                transition = state.transition(CircuitState::HalfOpen, now);
+            }
+            let result = match state.state {
+                CircuitState::Closed => Ok(Permit {
+                    breaker: Some(self.clone()),
+                    probe: false,
+                }),
+                CircuitState::HalfOpen if state.probes < self.half_open_calls => {
+                    state.probes += 1;
+                    Ok(Permit {
+                        breaker: Some(self.clone()),
+                        probe: true,
+                    })
+                }
+                CircuitState::HalfOpen => Err(ErnieError::CircuitOpenError(
+                    "the circuit is half-open and waiting for its probe calls".to_string(),
+                )),
+                CircuitState::Open => {
+                    let remaining = state
+                        .opened_at
+                        .map(|opened_at| self.open_duration.saturating_sub(now - opened_at))
+                        .unwrap_or_default();
+                    Err(ErnieError::CircuitOpenError(format!(
+                        "the circuit is open for another {:?}",
+                        remaining
+                    )))
+                }
+            };
+            (result, transition)
+        };
+        self.notify(transition);
+        result
+    }
+
+    /// whether an error tells that the endpoint is unhealthy
+    fn is_failure(error: &ErnieError) -> bool {
+        error.is_retryable() && !error.is_rate_limited()
+    }
+
+    /// record the outcome of a call let through by `acquire`: `None` for a success, or the error of the call
+    fn record(&self, error: Option<&ErnieError>) {
+        let failed = error.is_some_and(CircuitBreaker::is_failure);
+        let now = Instant::now();
+        let transition = {
+            let mut state = self.state.lock().unwrap();
+            match state.state {
+                CircuitState::Closed => {
+                    state.outcomes.push_back((now, failed));
+                    while state
+                        .outcomes
+                        .front()
+                        .is_some_and(|(at, _)| now.duration_since(*at) > self.window)
+                    {
+                        state.outcomes.pop_front();
+                    }
+                    let calls = state.outcomes.len();
+                    let failures = state.outcomes.iter().filter(|(_, failed)| *failed).count();
+                    if failed
+                        && calls >= self.minimum_calls as usize
+                        && failures as f64 >= self.failure_rate * calls as f64
+                    {
+                        state.transition(CircuitState::Open, now)
+                    } else {
+                        None
+                    }
+                }
+                CircuitState::HalfOpen if failed => state.transition(CircuitState::Open, now),
+                CircuitState::HalfOpen => {
+                    state.probe_successes += 1;
+                    if state.probe_successes >= self.half_open_calls {
+                        state.transition(CircuitState::Closed, now)
+                    } else {
+                        None
+                    }
+                }
+                // a call let through before the circuit opened
+                CircuitState::Open => None,
+            }
+        };
+        self.notify(transition);
+    }
+
+    /// a probe was dropped before it completed: open the circuit again, so that it does not stay half-open forever
+    fn abandon_probe(&self) {
+        let transition = {
+            let mut state = self.state.lock().unwrap();
+            if state.state == CircuitState::HalfOpen {
+                state.transition(CircuitState::Open, Instant::now())
+            } else {
+                None
+            }
+        };
+        self.notify(transition);
+    }
+}
+
+/** A call let through by a circuit breaker, whose outcome must be recorded with `record`.
+
+A permit dropped without being recorded, like the one of a cancelled future, records nothing: cancelling a call tells nothing about the endpoint. A dropped probe opens the half-open circuit again though, so that a lost probe does not keep it half-open forever.
+*/
+#[derive(Default)]
+pub(crate) struct Permit {
+    /// `None` once recorded, or when no breaker is attached
+    breaker: Option<CircuitBreaker>,
+    /// whether the call is a probe of a half-open circuit
+    probe: bool,
+}
+
+impl Permit {
+    /// record the outcome of the call: `None` for a success, or the error of the call
+    pub(crate) fn record(mut self, error: Option<&ErnieError>) {
+        if let Some(breaker) = self.breaker.take() {
+            breaker.record(error);
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(breaker) = self.breaker.take() {
+            if self.probe {
+                breaker.abandon_probe();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitState};
+    use crate::errors::ErnieError;
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    fn server_error() -> ErnieError {
+        ErnieError::RemoteAPIError(r#"{"error_code":336100,"error_msg":"try again"}"#.to_string())
+    }
+
+    #[test]
+    fn test_open_and_recover() {
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        let recorded = transitions.clone();
+        let breaker = CircuitBreaker::new()
+            .with_minimum_calls(4)
+            .with_failure_rate(0.5)
+            .with_open_duration(Duration::from_millis(50))
+            .on_state_change(move |from, to| recorded.lock().unwrap().push((from, to)));
+        for error in [None, Some(server_error()), None] {
+            breaker.acquire().unwrap().record(error.as_ref());
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.acquire().unwrap().record(Some(&server_error()));
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(matches!(
+            breaker.acquire(),
+            Err(ErnieError::CircuitOpenError(_))
+        ));
+
+        sleep(Duration::from_millis(60));
+        let probe = breaker.acquire().unwrap();
+        // only one probe at a time
+        assert!(matches!(
+            breaker.acquire(),
+            Err(ErnieError::CircuitOpenError(_))
+        ));
+        probe.record(None);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(
+            *transitions.lock().unwrap(),
+            vec![
+                (CircuitState::Closed, CircuitState::Open),
+                (CircuitState::Open, CircuitState::HalfOpen),
+                (CircuitState::HalfOpen, CircuitState::Closed),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_probe_reopens() {
+        let breaker = CircuitBreaker::new()
+            .with_minimum_calls(1)
+            .with_open_duration(Duration::from_millis(20));
+        breaker.acquire().unwrap().record(Some(&server_error()));
+        assert_eq!(breaker.state(), CircuitState::Open);
+        sleep(Duration::from_millis(30));
+        breaker
+            .acquire()
+            .unwrap()
+            .record(Some(&ErnieError::ReadTimeoutError("slow".to_string())));
+        assert!(matches!(
+            breaker.acquire(),
+            Err(ErnieError::CircuitOpenError(_))
+        ));
+        // a dropped probe reopens the circuit too
+        sleep(Duration::from_millis(30));
+        drop(breaker.acquire().unwrap());
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_dropped_calls_are_not_failures() {
+        let breaker = CircuitBreaker::new().with_minimum_calls(1);
+        for _ in 0..3 {
+            drop(breaker.acquire().unwrap());
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.acquire().unwrap().record(None);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_client_errors_are_not_failures() {
+        let breaker = CircuitBreaker::new().with_minimum_calls(1);
+        let rate_limited = ErnieError::RemoteAPIError(
+            r#"{"error_code":336501,"error_msg":"Rate limit reached for RPM"}"#.to_string(),
+        );
+        for error in [
+            rate_limited,
+            ErnieError::RemoteAPIError(r#"{"error_code":336003,"error_msg":"bad"}"#.to_string()),
+        ] {
+            breaker.acquire().unwrap().record(Some(&error));
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,655 @@
+use crate::chat::{ChatEndpoint, Message, Role};
+use crate::embedding::{l2_normalize, similarity, Scalar};
+use crate::errors::ErnieError;
+use crate::utils::{rng_state, xorshift64star};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// normalized copies of the vectors in `f64`, failing if their dimensions differ
+fn normalized<T: Scalar>(vectors: &[Vec<T>]) -> Result<Vec<Vec<f64>>, ErnieError> {
+    let dimension = vectors.first().map_or(0, Vec::len);
+    vectors
+        .iter()
+        .enumerate()
+        .map(|(index, vector)| {
+            if vector.len() != dimension {
+                return Err(ErnieError::DimensionMismatchError(format!(
+                    "vector {} has dimension {}, but the first one has dimension {}",
+                    index,
+                    vector.len(),
+                    dimension
+                )));
+            }
+            let mut vector: Vec<f64> = vector.iter().map(|x| (*x).into()).collect();
+            l2_normalize(&mut vector);
+            Ok(vector)
+        })
+        .collect()
+}
+
+/// A pair of vectors at least as similar as the threshold of `near_duplicates`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NearDuplicate {
+    pub original: usize,
+    /// the index of the duplicate, always after `original`
+    pub duplicate: usize,
+    /// cosine similarity of the two vectors
+    pub similarity: f64,
+}
+
+/** all the pairs of vectors whose cosine similarity is at least `threshold`, in the order of the vectors.
+
+Every pair is compared, so this is meant for some thousand vectors. Texts embedded by Qianfan models are near duplicates above a similarity of about 0.95.
+```
+use erniebot_rs::clustering::near_duplicates;
+let vectors = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.99, 0.05]];
+let found = near_duplicates(&vectors, 0.95).unwrap();
+assert_eq!((found[0].original, found[0].duplicate), (0, 2));
+```
+*/
+pub fn near_duplicates<T: Scalar>(
+    vectors: &[Vec<T>],
+    threshold: f64,
+) -> Result<Vec<NearDuplicate>, ErnieError> {
+    let vectors = normalized(vectors)?;
+    let mut found = Vec::new();
+    for (original, a) in vectors.iter().enumerate() {
+        for (duplicate, b) in vectors.iter().enumerate().skip(original + 1) {
+            let similarity = similarity(a, b);
+            if similarity >= threshold {
+                found.push(NearDuplicate {
+                    original,
+                    duplicate,
+                    similarity,
+                });
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// the indices of the vectors to keep to remove near duplicates: a vector is dropped when its cosine similarity with a vector kept before is at least `threshold`
+pub fn deduplicate<T: Scalar>(
+    vectors: &[Vec<T>],
+    threshold: f64,
+) -> Result<Vec<usize>, ErnieError> {
+    let vectors = normalized(vectors)?;
+    let mut kept: Vec<usize> = Vec::new();
+    for (index, vector) in vectors.iter().enumerate() {
+        if kept
+            .iter()
+            .all(|kept| similarity(&vectors[*kept], vector) < threshold)
+        {
+            kept.push(index);
+        }
+    }
+    Ok(kept)
+}
+
+/// The clusters found by `KMeans` or `Agglomerative`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Clustering {
+    /// the cluster of each vector, numbered from 0
+    pub labels: Vec<usize>,
+    /// the normalized mean of the vectors of each cluster
+    pub centroids: Vec<Vec<f64>>,
+    /// the cosine similarity of each vector with the centroid of its cluster, low for outliers
+    pub similarities: Vec<f64>,
+}
+
+impl Clustering {
+    /// number the clusters of `labels` in order of first appearance and compute their centroids
+    fn new(vectors: &[Vec<f64>], labels: &[usize]) -> Self {
+        let mut numbers = std::collections::HashMap::new();
+        let labels: Vec<usize> = labels
+            .iter()
+            .map(|label| {
+                let next = numbers.len();
+                *numbers.entry(*label).or_insert(next)
+            })
+            .collect();
+        let dimension = vectors.first().map_or(0, Vec::len);
+        let mut centroids = vec![vec![0.0; dimension]; numbers.len()];
+        for (vector, label) in vectors.iter().zip(&labels) {
+            for (sum, x) in centroids[*label].iter_mut().zip(vector) {
+                *sum += x;
+            }
+        }
+        centroids.iter_mut().for_each(|c| l2_normalize(c));
+        let similarities = vectors
+            .iter()
+            .zip(&labels)
+            .map(|(vector, label)| similarity(vector, &centroids[*label]))
+            .collect();
+        Clustering {
+            labels,
+            centroids,
+            similarities,
+        }
+    }
+
+    /// the number of clusters
+    pub fn len(&self) -> usize {
+        self.centroids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.centroids.is_empty()
+    }
+
+    /// the indices of the vectors of a cluster, the closest to its centroid first
+    pub fn members(&self, cluster: usize) -> Vec<usize> {
+        let mut members: Vec<usize> = (0..self.labels.len())
+            .filter(|index| self.labels[*index] == cluster)
+            .collect();
+        members.sort_by(|a, b| self.similarities[*b].total_cmp(&self.similarities[*a]));
+        members
+    }
+
+    /// the members of every cluster
+    pub fn groups(&self) -> Vec<Vec<usize>> {
+        (0..self.len())
+            .map(|cluster| self.members(cluster))
+            .collect()
+    }
+}
+
+/** KMeans groups vectors into `k` clusters by cosine similarity (spherical k-means, with k-means++ seeding).
+
+The result depends on the seed only, so runs with the same seed give the same clusters.
+```
+use erniebot_rs::clustering::KMeans;
+let vectors = vec![vec![1.0, 0.1], vec![0.0, 1.0], vec![0.9, 0.0], vec![0.1, 0.8]];
+let clustering = KMeans::new(2).fit(&vectors).unwrap();
+assert_eq!(clustering.labels, vec![0, 1, 0, 1]);
+```
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct KMeans {
+    k: usize,
+    max_iterations: usize,
+    seed: u64,
+}
+
+impl KMeans {
+    pub fn new(k: usize) -> Self {
+        KMeans {
+            k: k.max(1),
+            max_iterations: 100,
+            seed: 0,
+        }
+    }
+
+    /// set how many rounds of assignment are run at most, 100 by default. The search stops earlier once no vector changes cluster.
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations.max(1);
+        self
+    }
+
+    /// set the seed of the random choice of the first centroids
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// group the vectors into at most `k` clusters, fewer when there are fewer vectors
+    pub fn fit<T: Scalar>(&self, vectors: &[Vec<T>]) -> Result<Clustering, ErnieError> {
+        let vectors = normalized(vectors)?;
+        if vectors.is_empty() {
+            return Ok(Clustering::default());
+        }
+        let mut rng = rng_state(self.seed);
+        let mut centroids = KMeans::seed_centroids(&vectors, self.k.min(vectors.len()), &mut rng);
+        let mut labels = vec![usize::MAX; vectors.len()];
+        for _ in 0..self.max_iterations {
+            let mut changed = false;
+            for (vector, label) in vectors.iter().zip(labels.iter_mut()) {
+                let nearest = KMeans::nearest(&centroids, vector).0;
+                changed |= nearest != *label;
+                *label = nearest;
+            }
+            if !changed {
+                break;
+            }
+            centroids = KMeans::centroids(&vectors, &labels, centroids.len());
+        }
+        Ok(Clustering::new(&vectors, &labels))
+    }
+
+    /// the most similar centroid to `vector`, with the similarity
+    fn nearest(centroids: &[Vec<f64>], vector: &[f64]) -> (usize, f64) {
+        centroids
+            .iter()
+            .map(|centroid| similarity(centroid, vector))
+            .enumerate()
+            .fold((0, f64::NEG_INFINITY), |best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            })
+    }
+
+    /// a uniform random number in [0, 1)
+    fn random(rng: &mut u64) -> f64 {
+        (xorshift64star(rng) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// k-means++: each centroid is a vector drawn with a probability growing with its distance to the centroids chosen before
+    fn seed_centroids(vectors: &[Vec<f64>], k: usize, rng: &mut u64) -> Vec<Vec<f64>> {
+        let first = (KMeans::random(rng) * vectors.len() as f64) as usize;
+        let mut centroids = vec![vectors[first].clone()];
+        while centroids.len() < k {
+            let weights: Vec<f64> = vectors
+                .iter()
+                .map(|vector| {
+                    (1.0 - KMeans::nearest(&centroids, vector).1)
+                        .max(0.0)
+                        .powi(2)
+                })
+                .collect();
+            let total: f64 = weights.iter().sum();
+            if total <= 0.0 {
+                // fewer distinct vectors than clusters
+                break;
+            }
+            let mut target = KMeans::random(rng) * total;
+            let chosen = weights
+                .iter()
+                .position(|weight| {
+                    target -= weight;
+                    target < 0.0
+                })
+                .unwrap_or(vectors.len() - 1);
+            centroids.push(vectors[chosen].clone());
+        }
+        centroids
+    }
+
+    /// the normalized mean of each cluster. An empty cluster moves to the vector the least similar to its centroid.
+    fn centroids(vectors: &[Vec<f64>], labels: &[usize], k: usize) -> Vec<Vec<f64>> {
+        let mut centroids = vec![vec![0.0; vectors[0].len()]; k];
+        let mut sizes = vec![0; k];
+        for (vector, label) in vectors.iter().zip(labels) {
+            sizes[*label] += 1;
+            for (sum, x) in centroids[*label].iter_mut().zip(vector) {
+                *sum += x;
+            }
+        }
+        centroids.iter_mut().for_each(|c| l2_normalize(c));
+        for cluster in 0..k {
+            if sizes[cluster] > 0 {
+                continue;
+            }
+            let farthest = vectors
+                .iter()
+                .zip(labels)
+                .map(|(vector, label)| similarity(vector, &centroids[*label]))
+                .enumerate()
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(0, |(index, _)| index);
+            centroids[cluster] = vectors[farthest].clone();
+        }
+        centroids
+    }
+}
+
+/// How `Agglomerative` measures the similarity of two clusters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Linkage {
+    /// the similarity of their most similar vectors, giving chains of related vectors
+    Single,
+    /// the similarity of their least similar vectors, giving tight clusters
+    Complete,
+    /// the mean similarity of their vectors
+    #[default]
+    Average,
+}
+
+/** Agglomerative clustering: every vector starts in its own cluster, and the two most similar clusters are merged until no two clusters are as similar as the threshold, or until `count` clusters are left.
+
+Unlike `KMeans`, the number of clusters does not have to be known. The similarities of all the pairs of vectors are kept in memory, so this is meant for some thousand vectors.
+```
+use erniebot_rs::clustering::{Agglomerative, Linkage};
+let vectors = vec![vec![1.0, 0.1], vec![0.0, 1.0], vec![0.9, 0.0], vec![0.1, 0.8]];
+let clustering = Agglomerative::new(0.9)
+    .with_linkage(Linkage::Complete)
+    .fit(&vectors)
+    .unwrap();
+assert_eq!(clustering.labels, vec![0, 1, 0, 1]);
+```
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Agglomerative {
+    threshold: f64,
+    count: usize,
+    linkage: Linkage,
+}
+
+impl Agglomerative {
+    /// merge clusters while their similarity is at least `threshold`
+    pub fn new(threshold: f64) -> Self {
+        Agglomerative {
+            threshold,
+            count: 1,
+            linkage: Linkage::default(),
+        }
+    }
+
+    /// merge clusters until `count` clusters are left, whatever their similarity
+    pub fn new_with_count(count: usize) -> Self {
+        Agglomerative {
+            threshold: f64::NEG_INFINITY,
+            count: count.max(1),
+            linkage: Linkage::default(),
+        }
+    }
+
+    pub fn with_linkage(mut self, linkage: Linkage) -> Self {
+        self.linkage = linkage;
+        self
+    }
+
+    pub fn fit<T: Scalar>(&self, vectors: &[Vec<T>]) -> Result<Clustering, ErnieError> {
+        let vectors = normalized(vectors)?;
+        let n = vectors.len();
+        let mut similarities = vec![0.0f32; n * n];
+        for i in 0..n {
+            for j in i + 1..n {
+                let s = similarity(&vectors[i], &vectors[j]) as f32;
+                similarities[i * n + j] = s;
+                similarities[j * n + i] = s;
+            }
+        }
+        let mut sizes = vec![1usize; n];
+        let mut active = vec![true; n];
+        let mut labels: Vec<usize> = (0..n).collect();
+        // the most similar active cluster to each active cluster, with the similarity
+        let nearest_of = |i: usize, active: &[bool], similarities: &[f32]| {
+            (0..n)
+                .filter(|j| *j != i && active[*j])
+                .map(|j| (j, similarities[i * n + j]))
+                .fold((usize::MAX, f32::NEG_INFINITY), |best, candidate| {
+                    if candidate.1 > best.1 {
+                        candidate
+                    } else {
+                        best
+                    }
+                })
+        };
+        let mut nearest: Vec<(usize, f32)> = (0..n)
+            .map(|i| nearest_of(i, &active, &similarities))
+            .collect();
+        let mut remaining = n;
+        while remaining > self.count {
+            let Some((a, (b, best))) = nearest
+                .iter()
+                .enumerate()
+                .filter(|(i, (j, _))| active[*i] && *j != usize::MAX)
+                .max_by(|x, y| x.1 .1.total_cmp(&y.1 .1))
+                .map(|(i, nearest)| (i, *nearest))
+            else {
+                break;
+            };
+            if (best as f64) < self.threshold {
+                break;
+            }
+            // merge b into a
+            for j in (0..n).filter(|j| active[*j] && *j != a && *j != b) {
+                let (sa, sb) = (similarities[a * n + j], similarities[b * n + j]);
+                let merged = match self.linkage {
+                    Linkage::Single => sa.max(sb),
+                    Linkage::Complete => sa.min(sb),
+                    Linkage::Average => {
+                        (sa * sizes[a] as f32 + sb * sizes[b] as f32) / (sizes[a] + sizes[b]) as f32
+                    }
+                };
+                similarities[a * n + j] = merged;
+                similarities[j * n + a] = merged;
+            }
+            active[b] = false;
+            sizes[a] += sizes[b];
+            labels.iter_mut().filter(|l| **l == b).for_each(|l| *l = a);
+            remaining -= 1;
+            for j in 0..n {
+                if !active[j] {
+                    continue;
+                }
+                if j == a || nearest[j].0 == a || nearest[j].0 == b {
+                    nearest[j] = nearest_of(j, &active, &similarities);
+                } else if similarities[j * n + a] > nearest[j].1 {
+                    nearest[j] = (a, similarities[j * n + a]);
+                }
+            }
+        }
+        Ok(Clustering::new(&vectors, &labels))
+    }
+}
+
+static DEFAULT_INSTRUCTION: &str = "The texts below belong to one group. \
+Reply with a short label, of a few words, naming their common topic. Reply with the label only.";
+
+/** ClusterLabeler names clusters of texts by asking a chat model to summarize a sample of each cluster.
+
+The sample is made of the texts the closest to the centroid of the cluster.
+```no_run
+use erniebot_rs::chat::{ChatEndpoint, ChatModel};
+use erniebot_rs::clustering::{ClusterLabeler, KMeans};
+use erniebot_rs::embedding::{EmbeddingEndpoint, EmbeddingModel};
+let feedback = vec!["app crashes at startup".to_string(), "too expensive".to_string()];
+let embedding = EmbeddingEndpoint::new(EmbeddingModel::EmbeddingV1).unwrap();
+let vectors = embedding.embed_all(&feedback, None).unwrap().get_embedding_results().unwrap();
+let clustering = KMeans::new(2).fit(&vectors).unwrap();
+let labeler = ClusterLabeler::new(ChatEndpoint::new(ChatModel::ErnieBotTurbo).unwrap());
+for (label, members) in labeler.label(&feedback, &clustering).unwrap().iter().zip(clustering.groups()) {
+    println!("{}: {} texts", label, members.len());
+}
+```
+*/
+#[derive(Debug, Clone)]
+pub struct ClusterLabeler {
+    chat: ChatEndpoint,
+    samples: usize,
+    instruction: String,
+    concurrency: usize,
+}
+
+impl ClusterLabeler {
+    pub fn new(chat: ChatEndpoint) -> Self {
+        ClusterLabeler {
+            chat,
+            samples: 8,
+            instruction: DEFAULT_INSTRUCTION.to_string(),
+            concurrency: 4,
+        }
+    }
+
+    /// set how many texts of each cluster are shown to the model, 8 by default
+    pub fn with_samples(mut self, samples: usize) -> Self {
+        self.samples = samples.max(1);
+        self
+    }
+
+    /// set the instruction written before the sample, e.g. to ask for labels in another language
+    pub fn with_instruction(mut self, instruction: &str) -> Self {
+        self.instruction = instruction.to_string();
+        self
+    }
+
+    /// set how many clusters `alabel` labels at the same time, 4 by default
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// the messages asking for the label of each cluster, `texts` being the clustered texts in the order of the vectors
+    fn messages(
+        &self,
+        texts: &[String],
+        clustering: &Clustering,
+    ) -> Result<Vec<Vec<Message>>, ErnieError> {
+        if texts.len() != clustering.labels.len() {
+            return Err(ErnieError::InvokeError(format!(
+                "got {} texts for {} clustered vectors",
+                texts.len(),
+                clustering.labels.len()
+            )));
+        }
+        Ok(clustering
+            .groups()
+            .into_iter()
+            .map(|members| {
+                let sample: Vec<String> = members
+                    .into_iter()
+                    .take(self.samples)
+                    .map(|member| format!("- {}", texts[member].trim()))
+                    .collect();
+                vec![Message {
+                    role: Role::User,
+                    content: format!("{}\n\n{}", self.instruction, sample.join("\n")),
+                    ..Default::default()
+                }]
+            })
+            .collect())
+    }
+
+    /// the label of each cluster, in the order of the clusters
+    pub fn label(
+        &self,
+        texts: &[String],
+        clustering: &Clustering,
+    ) -> Result<Vec<String>, ErnieError> {
+        self.messages(texts, clustering)?
+            .iter()
+            .map(|messages| {
+                Ok(self
+                    .chat
+                    .invoke(messages, &vec![])?
+                    .get_chat_result()?
+                    .trim()
+                    .to_string())
+            })
+            .collect()
+    }
+
+    /// async version of `label`, labeling up to `with_concurrency` clusters at the same time
+    pub async fn alabel(
+        &self,
+        texts: &[String],
+        clustering: &Clustering,
+    ) -> Result<Vec<String>, ErnieError> {
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        let mut tasks = JoinSet::new();
+        for (cluster, messages) in self.messages(texts, clustering)?.into_iter().enumerate() {
+            let chat = self.chat.clone();
+            let permits = permits.clone();
+            tasks.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                let label = chat
+                    .ainvoke(&messages, &vec![])
+                    .await
+                    .and_then(|response| response.get_chat_result());
+                (cluster, label)
+            });
+        }
+        let mut labels = vec![String::new(); tasks.len()];
+        while let Some(joined) = tasks.join_next().await {
+            let (cluster, label) = joined.map_err(|e| ErnieError::InvokeError(e.to_string()))?;
+            labels[cluster] = label?.trim().to_string();
+        }
+        Ok(labels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ChatModel;
+    use crate::credentials::offline_pool;
+
+    /// three groups of noisy vectors around the axes of a 3 dimensional space, interleaved
+    fn blobs() -> Vec<Vec<f32>> {
+        (0..30)
+            .map(|i| {
+                let mut vector = vec![0.05 * ((i * 7 % 5) as f32); 3];
+                vector[i % 3] = 1.0;
+                vector
+            })
+            .collect()
+    }
+
+    fn expected() -> Vec<usize> {
+        (0..30).map(|i| i % 3).collect()
+    }
+
+    #[test]
+    fn test_near_duplicates() {
+        let vectors = vec![
+            vec![1.0, 0.0],
+            vec![0.0, 1.0],
+            vec![0.99, 0.05],
+            vec![1.0, 0.01],
+        ];
+        let pairs: Vec<_> = near_duplicates(&vectors, 0.95)
+            .unwrap()
+            .iter()
+            .map(|d| (d.original, d.duplicate))
+            .collect();
+        assert_eq!(pairs, [(0, 2), (0, 3), (2, 3)]);
+        assert_eq!(deduplicate(&vectors, 0.95).unwrap(), [0, 1]);
+        assert!(near_duplicates(&[vec![1.0], vec![1.0, 0.0]], 0.9).is_err());
+    }
+
+    #[test]
+    fn test_kmeans() {
+        let clustering = KMeans::new(3).with_seed(7).fit(&blobs()).unwrap();
+        assert_eq!(clustering.labels, expected());
+        assert_eq!(clustering.len(), 3);
+        assert!(clustering.similarities.iter().all(|s| *s > 0.9));
+        assert_eq!(clustering.members(1).len(), 10);
+        let few = KMeans::new(5)
+            .fit(&[vec![1.0f32, 0.0], vec![1.0, 0.0]])
+            .unwrap();
+        assert_eq!(few.labels, [0, 0]);
+        assert!(KMeans::new(2).fit::<f32>(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_agglomerative() {
+        for linkage in [Linkage::Single, Linkage::Complete, Linkage::Average] {
+            let clustering = Agglomerative::new(0.9)
+                .with_linkage(linkage)
+                .fit(&blobs())
+                .unwrap();
+            assert_eq!(clustering.labels, expected(), "{:?}", linkage);
+        }
+        let two = Agglomerative::new_with_count(2).fit(&blobs()).unwrap();
+        assert_eq!(two.len(), 2);
+        let none = Agglomerative::new(1.1).fit(&blobs()).unwrap();
+        assert_eq!(none.len(), 30);
+    }
+
+    #[test]
+    fn test_messages() {
+        let chat = ChatEndpoint::new_with_credential_pool(ChatModel::ErnieBotTurbo, offline_pool())
+            .unwrap();
+        let labeler = ClusterLabeler::new(chat)
+            .with_samples(1)
+            .with_instruction("Label:");
+        let texts: Vec<String> = ["crash", "price", "crashes on start"]
+            .iter()
+            .map(|text| text.to_string())
+            .collect();
+        let clustering = Clustering {
+            labels: vec![0, 1, 0],
+            centroids: vec![vec![1.0], vec![1.0]],
+            similarities: vec![0.8, 1.0, 0.9],
+        };
+        let messages = labeler.messages(&texts, &clustering).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0][0].content, "Label:\n\n- crashes on start");
+        assert!(labeler.messages(&texts[..2], &clustering).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+use crate::errors::ErnieError;
+use crate::rate_limiter::RateLimiter;
+use crate::utils::{afetch_access_token, fetch_access_token};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Access tokens are refreshed this long before they expire
+static TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// The AK/SK of one Qianfan application.
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub api_key: String,
+    pub secret_key: String,
+    /// relative share of the traffic with `SelectionStrategy::Weighted`, at least 1
+    pub weight: u32,
+    /// the quota of this application. Calls made with this credential wait for it, in addition to the rate limiter of the endpoint.
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+impl Credential {
+    pub fn new(api_key: &str, secret_key: &str) -> Self {
+        Credential {
+            api_key: api_key.to_string(),
+            secret_key: secret_key.to_string(),
+            weight: 1,
+            rate_limiter: None,
+        }
+    }
+
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight.max(1);
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+}
+
+/// How a `CredentialPool` picks the credential of each call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SelectionStrategy {
+    /// each credential in turn
+    #[default]
+    RoundRobin,
+    /// the credential with the fewest calls in flight
+    LeastLoaded,
+    /// in proportion to `Credential::weight`, spread evenly (smooth weighted round robin)
+    Weighted,
+}
+
+#[derive(Debug)]
+struct Entry {
+    credential: Credential,
+    /// cached access token and the instant it expires
+    token: Mutex<Option<(String, Instant)>>,
+    in_flight: AtomicUsize,
+    served: AtomicU64,
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Entry {
+    fn cached_token(&self) -> Option<String> {
+        let token = self.token.lock().unwrap();
+        match &*token {
+            Some((access_token, expires_at))
+                if Instant::now() + TOKEN_REFRESH_MARGIN < *expires_at =>
+            {
+                Some(access_token.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn store_token(&self, access_token: &str, lifetime: Duration) {
+        *self.token.lock().unwrap() = Some((access_token.to_string(), Instant::now() + lifetime));
+    }
+
+    fn ejected_until(&self, now: Instant) -> Option<Instant> {
+        self.ejected_until
+            .lock()
+            .unwrap()
+            .filter(|until| *until > now)
+    }
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    entries: Vec<Entry>,
+    strategy: SelectionStrategy,
+    /// how long a failing credential is left out, in milliseconds
+    ejection: AtomicU64,
+    next: AtomicUsize,
+    /// current weights of the smooth weighted round robin
+    current_weights: Mutex<Vec<i64>>,
+}
+
+impl PoolInner {
+    /// pick the index of the credential for the next call, among the credentials that are not ejected
+    fn select(&self) -> usize {
+        let now = Instant::now();
+        let available: Vec<usize> = (0..self.entries.len())
+            .filter(|i| self.entries[*i].ejected_until(now).is_none())
+            .collect();
+        if available.is_empty() {
+            // every credential is ejected: use the one coming back first rather than failing
+            return (0..self.entries.len())
+                .min_by_key(|i| self.entries[*i].ejected_until(now))
+                .unwrap_or(0);
+        }
+        match self.strategy {
+            SelectionStrategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                available[next % available.len()]
+            }
+            SelectionStrategy::LeastLoaded => *available
+                .iter()
+                .min_by_key(|i| {
+                    let entry = &self.entries[**i];
+                    (
+                        entry.in_flight.load(Ordering::Relaxed),
+                        entry.served.load(Ordering::Relaxed),
+                    )
+                })
+                .unwrap(),
+            SelectionStrategy::Weighted => {
+                let mut current_weights = self.current_weights.lock().unwrap();
+                let mut total = 0;
+                let mut best = available[0];
+                for i in available {
+                    let weight = self.entries[i].credential.weight.max(1) as i64;
+                    current_weights[i] += weight;
+                    total += weight;
+                    if current_weights[i] > current_weights[best] {
+                        best = i;
+                    }
+                }
+                current_weights[best] -= total;
+                best
+            }
+        }
+    }
+
+    fn eject(&self, index: usize) {
+        let ejection = Duration::from_millis(self.ejection.load(Ordering::Relaxed));
+        *self.entries[index].ejected_until.lock().unwrap() = Some(Instant::now() + ejection);
+    }
+}
+
+/** CredentialPool spreads calls over several Qianfan applications (AK/SK pairs), to add up their quotas.
+
+Each credential has its own access token cache and optionally its own rate limiter. A credential whose token cannot be fetched, or whose calls fail with an auth error or a rate limit error, is ejected from the pool for a while (60 seconds by default).
+The pool is cheap to clone and all clones share the same state, so one pool can serve several endpoints (see `new_with_credential_pool` on each endpoint).
+```no_run
+use erniebot_rs::chat::{ChatEndpoint, ChatModel};
+use erniebot_rs::credentials::{Credential, CredentialPool, SelectionStrategy};
+let pool = CredentialPool::new(
+    vec![
+        Credential::new("ak1", "sk1").with_weight(2),
+        Credential::new("ak2", "sk2"),
+    ],
+    SelectionStrategy::Weighted,
+);
+let chat = ChatEndpoint::new_with_credential_pool(ChatModel::ErnieBotTurbo, pool).unwrap();
+```
+*/
+#[derive(Debug, Clone)]
+pub struct CredentialPool {
+    inner: Arc<PoolInner>,
+}
+
+impl CredentialPool {
+    pub fn new(credentials: Vec<Credential>, strategy: SelectionStrategy) -> Self {
+        let current_weights = Mutex::new(vec![0; credentials.len()]);
+        let entries = credentials
+            .into_iter()
+            .map(|credential| Entry {
+                credential,
+                token: Mutex::new(None),
+                in_flight: AtomicUsize::new(0),
+                served: AtomicU64::new(0),
+                ejected_until: Mutex::new(None),
+            })
+            .collect();
+        CredentialPool {
+            inner: Arc::new(PoolInner {
+                entries,
+                strategy,
+                ejection: AtomicU64::new(60_000),
+                next: AtomicUsize::new(0),
+                current_weights,
+            }),
+        }
+    }
+
+    /// set how long a failing credential is left out of the pool, for all the clones of the pool
+    pub fn with_ejection_duration(self, ejection: Duration) -> Self {
+        self.inner
+            .ejection
+            .store(ejection.as_millis() as u64, Ordering::Relaxed);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.entries.is_empty()
+    }
+
+    /// the api keys of the credentials currently ejected from the pool
+    pub fn ejected(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.inner
+            .entries
+            .iter()
+            .filter(|entry| entry.ejected_until(now).is_some())
+            .map(|entry| entry.credential.api_key.clone())
+            .collect()
+    }
+
+    fn lease_entry(&self, index: usize, access_token: String) -> Lease {
+        let entry = &self.inner.entries[index];
+        entry.in_flight.fetch_add(1, Ordering::Relaxed);
+        entry.served.fetch_add(1, Ordering::Relaxed);
+        Lease {
+            access_token,
+            limiter: entry.credential.rate_limiter.clone(),
+            slot: Some((self.inner.clone(), index)),
+        }
+    }
+
+    fn no_credential() -> ErnieError {
+        ErnieError::GetAccessTokenError("the credential pool is empty".to_string())
+    }
+
+    /// pick a credential and get its access token, in a blocking way
+    pub(crate) fn lease(&self) -> Result<Lease, ErnieError> {
+        let mut last_error = CredentialPool::no_credential();
+        for _ in 0..self.len() {
+            let index = self.inner.select();
+            let entry = &self.inner.entries[index];
+            if let Some(access_token) = entry.cached_token() {
+                return Ok(self.lease_entry(index, access_token));
+            }
+            let credential = &entry.credential;
+            match fetch_access_token(&credential.api_key, &credential.secret_key) {
+                Ok((access_token, lifetime)) => {
+                    entry.store_token(&access_token, lifetime);
+                    return Ok(self.lease_entry(index, access_token));
+                }
+                Err(e) => {
+                    self.inner.eject(index);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// pick a credential and get its access token, in an async way
+    pub(crate) async fn alease(&self) -> Result<Lease, ErnieError> {
+        let mut last_error = CredentialPool::no_credential();
+        for _ in 0..self.len() {
+            let index = self.inner.select();
+            let entry = &self.inner.entries[index];
+            if let Some(access_token) = entry.cached_token() {
+                return Ok(self.lease_entry(index, access_token));
+            }
+            let credential = &entry.credential;
+            match afetch_access_token(&credential.api_key, &credential.secret_key).await {
+                Ok((access_token, lifetime)) => {
+                    entry.store_token(&access_token, lifetime);
+                    return Ok(self.lease_entry(index, access_token));
+                }
+                Err(e) => {
+                    self.inner.eject(index);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// The credential used by one call. While the lease is alive the call counts as in flight for `SelectionStrategy::LeastLoaded`.
+#[derive(Debug)]
+pub(crate) struct Lease {
+    pub(crate) access_token: String,
+    pub(crate) limiter: Option<RateLimiter>,
+    slot: Option<(Arc<PoolInner>, usize)>,
+}
+
+impl Lease {
+    /// a lease for a single access token, outside of any pool
+    pub(crate) fn from_access_token(access_token: &str) -> Self {
+        Lease {
+            access_token: access_token.to_string(),
+            limiter: None,
+            slot: None,
+        }
+    }
+
+    /// tell the pool about a failed call: auth errors drop the cached token and eject the credential, rate limit errors eject it
+    pub(crate) fn report_error(&self, error: &ErnieError) {
+        let Some((pool, index)) = &self.slot else {
+            return;
+        };
+        if error.is_auth_error() {
+            *pool.entries[*index].token.lock().unwrap() = None;
+            pool.eject(*index);
+        } else if error.is_rate_limited() {
+            pool.eject(*index);
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some((pool, index)) = &self.slot {
+            pool.entries[*index]
+                .in_flight
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// a pool with one made-up credential, for the tests of endpoints that never reach the API
+#[cfg(test)]
+pub(crate) fn offline_pool() -> CredentialPool {
+    CredentialPool::new(
+        vec![Credential::new("ak", "sk")],
+        SelectionStrategy::RoundRobin,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Credential, CredentialPool, SelectionStrategy};
+    use std::time::Duration;
+
+    fn pool(strategy: SelectionStrategy) -> CredentialPool {
+        let pool = CredentialPool::new(
+            vec![
+                Credential::new("ak1", "sk1").with_weight(3),
+                Credential::new("ak2", "sk2"),
+            ],
+            strategy,
+        );
+        for entry in &pool.inner.entries {
+            entry.store_token("token", Duration::from_secs(3600));
+        }
+        pool
+    }
+
+    fn picks(pool: &CredentialPool, n: usize) -> Vec<usize> {
+        (0..n).map(|_| pool.inner.select()).collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = pool(SelectionStrategy::RoundRobin);
+        assert_eq!(picks(&pool, 4), vec![0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let pool = pool(SelectionStrategy::Weighted);
+        assert_eq!(picks(&pool, 4), vec![0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_least_loaded() {
+        let pool = pool(SelectionStrategy::LeastLoaded);
+        let first = pool.lease().unwrap();
+        let second = pool.lease().unwrap();
+        assert_ne!(
+            first.slot.as_ref().unwrap().1,
+            second.slot.as_ref().unwrap().1
+        );
+        drop(first);
+        assert_eq!(pool.inner.select(), 0);
+    }
+
+    #[test]
+    fn test_ejection() {
+        let pool = pool(SelectionStrategy::RoundRobin);
+        let lease = pool.lease().unwrap();
+        lease.report_error(&crate::errors::ErnieError::RemoteAPIError(
+            r#"{"error_code":336502,"error_msg":"Rate limit reached for TPM"}"#.to_string(),
+        ));
+        assert_eq!(pool.ejected(), vec!["ak1".to_string()]);
+        assert_eq!(picks(&pool, 3), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn test_ejection_duration_of_a_shared_pool() {
+        let pool = pool(SelectionStrategy::RoundRobin);
+        let shared = pool.clone();
+        let pool = pool.with_ejection_duration(Duration::from_millis(20));
+        shared.inner.eject(0);
+        assert_eq!(pool.ejected(), vec!["ak1".to_string()]);
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(pool.ejected().is_empty());
+    }
+}
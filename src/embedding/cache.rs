@@ -0,0 +1,214 @@
+use super::model::EmbeddingModel;
+use super::response::EmbeddingResponse;
+use crate::cache::{CacheBackend, DiskCache, FileCache, MemoryCache};
+use crate::errors::ErnieError;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/** EmbeddingCache keeps the embedding of every text embedded by `EmbeddingEndpoint::embed_all` and `aembed_all`, keyed by the model and a hash of the text.
+
+Attach it with `EmbeddingEndpoint::with_embedding_cache`. Only the texts missing from the cache are sent to the API, so indexing a corpus again (e.g. with `VectorStore::upsert`) only costs the changed documents. The usage of the returned response counts the texts sent only.
+Unlike `ResponseCache`, which stores whole responses, texts are cached one by one, so that a batch mixing known and new texts is served in part from the cache.
+```no_run
+use erniebot_rs::embedding::{EmbeddingCache, EmbeddingEndpoint, EmbeddingModel};
+let cache = EmbeddingCache::in_file("embeddings.jsonl").unwrap();
+let embedding = EmbeddingEndpoint::new(EmbeddingModel::EmbeddingV1)
+    .unwrap()
+    .with_embedding_cache(cache);
+let texts = vec!["你好".to_string(), "世界".to_string()];
+embedding.embed_all(&texts, None).unwrap();
+// served from the cache, without calling the API
+let response = embedding.embed_all(&texts, None).unwrap();
+assert_eq!(response.get_total_tokens(), Some(0));
+```
+*/
+#[derive(Clone)]
+pub struct EmbeddingCache {
+    backend: Arc<dyn CacheBackend>,
+}
+
+impl std::fmt::Debug for EmbeddingCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmbeddingCache").finish_non_exhaustive()
+    }
+}
+
+/// The texts of an input missing from an `EmbeddingCache`.
+pub(crate) struct Lookup {
+    /// the cached embedding of each text of the input
+    found: Vec<Option<Value>>,
+    /// the distinct texts without a cached embedding, to be embedded
+    pub(crate) misses: Vec<String>,
+}
+
+impl EmbeddingCache {
+    pub fn new(backend: Arc<dyn CacheBackend>) -> Self {
+        EmbeddingCache { backend }
+    }
+
+    /// a cache holding at most `capacity` embeddings in memory
+    pub fn in_memory(capacity: usize) -> Self {
+        EmbeddingCache::new(Arc::new(MemoryCache::new(capacity)))
+    }
+
+    /// a cache keeping all the embeddings in the single file `path`, see `FileCache`
+    pub fn in_file(path: impl Into<PathBuf>) -> Result<Self, ErnieError> {
+        Ok(EmbeddingCache::new(Arc::new(FileCache::new(path)?)))
+    }
+
+    /// a cache keeping each embedding as a file in `dir`, see `DiskCache`
+    pub fn on_disk(dir: impl Into<PathBuf>) -> Result<Self, ErnieError> {
+        Ok(EmbeddingCache::new(Arc::new(DiskCache::new(dir)?)))
+    }
+
+    /// the key of a text: a hash of the model and the text
+    pub fn key(model: &EmbeddingModel, text: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(model.to_string().as_bytes());
+        hasher.update(b"\n");
+        hasher.update(text.as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// the cached embedding of `text` by `model`. An entry without the dimension of the model is ignored.
+    pub fn get(&self, model: &EmbeddingModel, text: &str) -> Option<Vec<f64>> {
+        let vector: Vec<f64> =
+            serde_json::from_value(self.backend.get(&EmbeddingCache::key(model, text))?).ok()?;
+        (vector.len() == model.dimension()).then_some(vector)
+    }
+
+    pub fn put(&self, model: &EmbeddingModel, text: &str, vector: &[f64]) {
+        self.backend.put(
+            &EmbeddingCache::key(model, text),
+            &Value::from(vector),
+            None,
+        );
+    }
+
+    pub fn clear(&self) {
+        self.backend.clear();
+    }
+
+    pub(crate) fn lookup(&self, model: &EmbeddingModel, input: &[String]) -> Lookup {
+        let mut misses = Vec::new();
+        let mut missed = HashSet::new();
+        let found = input
+            .iter()
+            .map(|text| {
+                let vector = self.get(model, text).map(Value::from);
+                if vector.is_none() && missed.insert(text.as_str()) {
+                    misses.push(text.clone());
+                }
+                vector
+            })
+            .collect();
+        Lookup { found, misses }
+    }
+
+    /// the embeddings of a response, one for each text sent
+    fn embeddings_of(
+        texts: &[String],
+        response: &EmbeddingResponse,
+    ) -> Result<Vec<Vec<f64>>, ErnieError> {
+        let embeddings = response.get_embeddings::<f64>()?;
+        if embeddings.len() != texts.len() {
+            return Err(ErnieError::GetResponseError(format!(
+                "got {} embeddings for {} texts",
+                embeddings.len(),
+                texts.len()
+            )));
+        }
+        Ok(embeddings
+            .into_iter()
+            .map(|embedding| embedding.vector)
+            .collect())
+    }
+
+    /// store the embeddings of a batch of texts as soon as it is embedded, so that a later batch failing does not lose them
+    pub(crate) fn store(
+        &self,
+        model: &EmbeddingModel,
+        texts: &[String],
+        response: &EmbeddingResponse,
+    ) -> Result<(), ErnieError> {
+        for (text, vector) in texts
+            .iter()
+            .zip(EmbeddingCache::embeddings_of(texts, response)?)
+        {
+            self.put(model, text, &vector);
+        }
+        Ok(())
+    }
+
+    /// build the response of the whole input, in its order, from the cached embeddings and the ones of the misses
+    pub(crate) fn complete(
+        &self,
+        input: &[String],
+        lookup: Lookup,
+        response: EmbeddingResponse,
+    ) -> Result<EmbeddingResponse, ErnieError> {
+        let embeddings = EmbeddingCache::embeddings_of(&lookup.misses, &response)?;
+        let mut embedded = HashMap::with_capacity(embeddings.len());
+        for (text, vector) in lookup.misses.iter().zip(embeddings) {
+            embedded.insert(text.as_str(), Value::from(vector));
+        }
+        let data = input
+            .iter()
+            .zip(lookup.found)
+            .enumerate()
+            .map(|(index, (text, found))| {
+                serde_json::json!({
+                    "object": "embedding",
+                    "embedding": found.unwrap_or_else(|| embedded[text.as_str()].clone()),
+                    "index": index,
+                })
+            })
+            .collect();
+        Ok(response.with_data(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_lookup_and_complete() {
+        let model = EmbeddingModel::EmbeddingV1;
+        let cache = EmbeddingCache::in_memory(10);
+        let vector = |x: f64| vec![x; 384];
+        cache.put(&model, "known", &vector(1.0));
+        assert_eq!(cache.get(&EmbeddingModel::Tao8k, "known"), None);
+        let input: Vec<String> = ["new", "known", "new", "other"]
+            .iter()
+            .map(|text| text.to_string())
+            .collect();
+        let lookup = cache.lookup(&model, &input);
+        assert_eq!(lookup.misses, ["new", "other"]);
+        let response = EmbeddingResponse::new(json!({
+            "data": [
+                {"object": "embedding", "embedding": vector(3.0), "index": 1},
+                {"object": "embedding", "embedding": vector(2.0), "index": 0},
+            ],
+            "usage": {"prompt_tokens": 2, "total_tokens": 2},
+        }))
+        .with_model(model.clone());
+        cache.store(&model, &lookup.misses, &response).unwrap();
+        let response = cache.complete(&input, lookup, response).unwrap();
+        let vectors = response.get_embedding_results().unwrap();
+        assert_eq!(
+            vectors,
+            [vector(2.0), vector(1.0), vector(2.0), vector(3.0)]
+        );
+        assert_eq!(response.get_total_tokens(), Some(2));
+        assert!(cache.lookup(&model, &input).misses.is_empty());
+    }
+}
@@ -1,10 +1,22 @@
+use super::cache::EmbeddingCache;
 use super::model::EmbeddingModel;
 use super::response::EmbeddingResponse;
+use crate::cache::ResponseCache;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::credentials::CredentialPool;
 use crate::errors::ErnieError;
-use crate::utils::{build_url, get_access_token};
+use crate::metrics::{Metrics, MetricsHook};
+use crate::middleware::Middleware;
+use crate::rate_limiter::RateLimiter;
+use crate::single_flight::SingleFlight;
+use crate::timeout::Timeouts;
+use crate::transport::{EndpointKind, Transport};
+use crate::usage::UsageTracker;
+use crate::utils::estimate_tokens;
 use json_value_merge::Merge;
-use serde_json::Value;
-use url::Url;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
 static EMBEDDING_BASE_URL: &str =
     "https://aip.baidubce.com/rpc/2.0/ai_custom/v1/wenxinworkshop/embeddings/";
@@ -13,18 +25,135 @@ static EMBEDDING_BASE_URL: &str =
 */
 #[derive(Debug, Clone)]
 pub struct EmbeddingEndpoint {
-    url: Url,
-    access_token: String,
+    transport: Transport,
+    model: EmbeddingModel,
+    batch_concurrency: usize,
+    embedding_cache: Option<EmbeddingCache>,
+}
+
+/// split an input into batches accepted by `model`, failing if a text is too long for it
+fn batches<'a>(
+    model: &EmbeddingModel,
+    input: &'a [String],
+) -> Result<Vec<&'a [String]>, ErnieError> {
+    let max_tokens = model.max_input_tokens();
+    for (index, text) in input.iter().enumerate() {
+        let tokens = estimate_tokens(text);
+        if tokens > max_tokens {
+            return Err(ErnieError::GenerateBodyError(format!(
+                "input {} has about {} tokens, more than the {} tokens accepted by {}",
+                index, tokens, max_tokens, model
+            )));
+        }
+    }
+    Ok(input.chunks(model.max_batch_size()).collect())
 }
 
 impl EmbeddingEndpoint {
     // create a new embedding instance using pre-defined model
     pub fn new(model: EmbeddingModel) -> Result<Self, ErnieError> {
         Ok(EmbeddingEndpoint {
-            url: build_url(EMBEDDING_BASE_URL, model.to_string().as_str())?,
-            access_token: get_access_token()?,
+            transport: Transport::new(
+                EndpointKind::Embedding,
+                EMBEDDING_BASE_URL,
+                &model.to_string(),
+            )?,
+            model,
+            batch_concurrency: 4,
+            embedding_cache: None,
+        })
+    }
+
+    /// create a new embedding instance using pre-defined model, spreading the calls over the credentials of a pool
+    pub fn new_with_credential_pool(
+        model: EmbeddingModel,
+        pool: CredentialPool,
+    ) -> Result<Self, ErnieError> {
+        Ok(EmbeddingEndpoint {
+            transport: Transport::new_with_credential_pool(
+                EndpointKind::Embedding,
+                EMBEDDING_BASE_URL,
+                &model.to_string(),
+                pool,
+            )?,
+            model,
+            batch_concurrency: 4,
+            embedding_cache: None,
         })
     }
+
+    /// the model computing the embeddings, which sets their dimension
+    pub fn model(&self) -> &EmbeddingModel {
+        &self.model
+    }
+
+    /// attach a usage tracker, which records the tokens of every call and enforces its budget before sending requests
+    pub fn with_usage_tracker(mut self, tracker: UsageTracker) -> Self {
+        self.transport.usage_tracker = Some(tracker);
+        self
+    }
+
+    /// attach a rate limiter, which makes calls wait until they fit in the requests and tokens per minute quota of the model
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.transport.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// attach a circuit breaker, which makes calls fail fast with `ErnieError::CircuitOpenError` while the endpoint keeps failing
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.transport.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// attach a metrics hook, which receives the latency, tokens, retries and outcome of every call
+    pub fn with_metrics(mut self, hook: Arc<dyn MetricsHook>) -> Self {
+        self.transport.metrics = Some(Metrics(hook));
+        self
+    }
+
+    /// add a middleware at the end of the chain, see `Middleware`
+    pub fn with_middleware(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.transport.middlewares.0.push(middleware);
+        self
+    }
+
+    /// attach a response cache, which serves identical requests without calling the API again, see `ResponseCache`
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.transport.cache = Some(cache);
+        self
+    }
+
+    /// attach an embedding cache, which `embed_all` and `aembed_all` consult to only send the texts never embedded before, see `EmbeddingCache`
+    pub fn with_embedding_cache(mut self, cache: EmbeddingCache) -> Self {
+        self.embedding_cache = Some(cache);
+        self
+    }
+
+    /// coalesce concurrent identical `ainvoke` calls into one request, see `SingleFlight`
+    pub fn with_single_flight(mut self, single_flight: SingleFlight) -> Self {
+        self.transport.single_flight = Some(single_flight);
+        self
+    }
+
+    /// set how many batches `aembed_all` sends at the same time, 4 by default
+    pub fn with_batch_concurrency(mut self, batch_concurrency: usize) -> Self {
+        self.batch_concurrency = batch_concurrency.max(1);
+        self
+    }
+
+    /// set the timeouts of every call made by this endpoint
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.transport.timeouts = timeouts;
+        self
+    }
+
+    /// return a copy of this endpoint for a single call, with the timeouts set in `overrides` taking precedence over the endpoint ones
+    pub fn with_timeout_overrides(&self, overrides: &Timeouts) -> Self {
+        let mut endpoint = self.clone();
+        endpoint.transport.timeouts = self.transport.timeouts.merge(overrides);
+        endpoint
+    }
+
     /// sync invoke
     pub fn invoke(
         &self,
@@ -37,19 +166,8 @@ impl EmbeddingEndpoint {
         if let Some(user_id) = user_id {
             body.merge(&serde_json::json!({"user_id": user_id}));
         }
-        let response: Value = ureq::post(self.url.as_str())
-            .query("access_token", self.access_token.as_str())
-            .send_json(body)
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?
-            .into_json()
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?;
-
-        //if error_code key in response, means RemoteAPIError
-        if response.get("error_code").is_some() {
-            return Err(ErnieError::RemoteAPIError(response.to_string()));
-        }
-
-        Ok(EmbeddingResponse::new(response))
+        let response = self.transport.post(body)?;
+        Ok(EmbeddingResponse::new(response).with_model(self.model.clone()))
     }
     ///async invoke
     pub async fn ainvoke(
@@ -63,23 +181,178 @@ impl EmbeddingEndpoint {
         if let Some(user_id) = user_id {
             body.merge(&serde_json::json!({"user_id": user_id}));
         }
-        let client = reqwest::Client::new();
-        let response: Value = client
-            .post(self.url.as_str())
-            .query(&[("access_token", self.access_token.as_str())])
-            .json(&body)
-            .send()
-            .await
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?
-            .json()
-            .await
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?;
-
-        //if error_code key in response, means RemoteAPIError
-        if response.get("error_code").is_some() {
-            return Err(ErnieError::RemoteAPIError(response.to_string()));
+        let response = self.transport.apost(body).await?;
+        Ok(EmbeddingResponse::new(response).with_model(self.model.clone()))
+    }
+
+    /** sync invoke on an input of any size: the input is split into batches accepted by the model, sent one after the other.
+
+    The embeddings are returned in the order of the input and the usage is the sum of all the batches. Fails before sending anything if a text is longer than `EmbeddingModel::max_input_tokens`, as estimated by `estimate_tokens`.
+    With an `EmbeddingCache` attached, only the texts missing from the cache are sent, and the embeddings of each batch are cached as soon as it succeeds, even if a later batch fails.
+    */
+    pub fn embed_all(
+        &self,
+        input: &[String],
+        user_id: Option<&str>,
+    ) -> Result<EmbeddingResponse, ErnieError> {
+        let Some(cache) = &self.embedding_cache else {
+            return self.embed_batches(input, user_id);
+        };
+        let lookup = cache.lookup(&self.model, input);
+        let response = self.embed_batches(&lookup.misses, user_id)?;
+        cache.complete(input, lookup, response)
+    }
+
+    /// store the embeddings of a batch in the embedding cache, if any, before the next batches are sent
+    fn cache_batch(
+        &self,
+        batch: &[String],
+        response: EmbeddingResponse,
+    ) -> Result<EmbeddingResponse, ErnieError> {
+        if let Some(cache) = &self.embedding_cache {
+            cache.store(&self.model, batch, &response)?;
+        }
+        Ok(response)
+    }
+
+    fn embed_batches(
+        &self,
+        input: &[String],
+        user_id: Option<&str>,
+    ) -> Result<EmbeddingResponse, ErnieError> {
+        let responses = batches(&self.model, input)?
+            .into_iter()
+            .map(|batch| {
+                self.invoke(&batch.to_vec(), user_id)
+                    .and_then(|response| self.cache_batch(batch, response))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(EmbeddingResponse::concat(responses)?.with_model(self.model.clone()))
+    }
+
+    /// async version of `embed_all`, sending up to `with_batch_concurrency` batches at the same time
+    pub async fn aembed_all(
+        &self,
+        input: &[String],
+        user_id: Option<&str>,
+    ) -> Result<EmbeddingResponse, ErnieError> {
+        let Some(cache) = &self.embedding_cache else {
+            return self.aembed_batches(input, user_id).await;
+        };
+        let lookup = cache.lookup(&self.model, input);
+        let response = self.aembed_batches(&lookup.misses, user_id).await?;
+        cache.complete(input, lookup, response)
+    }
+
+    async fn aembed_batches(
+        &self,
+        input: &[String],
+        user_id: Option<&str>,
+    ) -> Result<EmbeddingResponse, ErnieError> {
+        let batches = batches(&self.model, input)?;
+        let permits = Arc::new(Semaphore::new(self.batch_concurrency));
+        let mut tasks = JoinSet::new();
+        for (index, batch) in batches.into_iter().enumerate() {
+            let endpoint = self.clone();
+            let batch = batch.to_vec();
+            let user_id = user_id.map(str::to_string);
+            let permits = permits.clone();
+            tasks.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                let response = endpoint
+                    .ainvoke(&batch, user_id.as_deref())
+                    .await
+                    .and_then(|response| endpoint.cache_batch(&batch, response));
+                (index, response)
+            });
+        }
+        let mut responses = Vec::with_capacity(tasks.len());
+        while let Some(joined) = tasks.join_next().await {
+            let (index, response) = joined.map_err(|e| ErnieError::InvokeError(e.to_string()))?;
+            responses.push((index, response?));
+        }
+        responses.sort_by_key(|(index, _)| *index);
+        Ok(EmbeddingResponse::concat(
+            responses
+                .into_iter()
+                .map(|(_, response)| response)
+                .collect(),
+        )?
+        .with_model(self.model.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::offline_pool;
+    use crate::middleware::RequestContext;
+    use crate::utils::build_url;
+    use serde_json::{json, Value};
+
+    /// fails the requests embedding a text containing "fail"
+    struct FailOn;
+
+    impl Middleware for FailOn {
+        fn before_request(
+            &self,
+            _context: &RequestContext,
+            body: &mut Value,
+        ) -> Result<(), ErnieError> {
+            let texts = body["input"].as_array().cloned().unwrap_or_default();
+            match texts.iter().any(|text| text.as_str() == Some("fail")) {
+                true => Err(ErnieError::InvokeError("the batch failed".to_string())),
+                false => Ok(()),
+            }
         }
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_cached_as_they_complete() {
+        let model = EmbeddingModel::EmbeddingV1;
+        let mut input: Vec<String> = (0..20).map(|i| format!("text {}", i)).collect();
+        input[18] = "fail".to_string();
+        // the first batch is answered by the response cache, the second one fails
+        let responses = ResponseCache::in_memory(10);
+        let url = build_url(EMBEDDING_BASE_URL, &model.to_string()).unwrap();
+        let data: Vec<Value> = (0..16)
+            .map(
+                |index| json!({"object": "embedding", "embedding": vec![1.0; 384], "index": index}),
+            )
+            .collect();
+        responses.put(
+            &ResponseCache::key(url.as_str(), &json!({"input": &input[..16]})),
+            &json!({"data": data, "usage": {"prompt_tokens": 16, "total_tokens": 16}}),
+        );
+        let cache = EmbeddingCache::in_memory(100);
+        let endpoint = EmbeddingEndpoint::new_with_credential_pool(model.clone(), offline_pool())
+            .unwrap()
+            .with_middleware(Arc::new(FailOn))
+            .with_cache(responses)
+            .with_embedding_cache(cache.clone());
+
+        assert!(endpoint.embed_all(&input, None).is_err());
+        assert_eq!(cache.lookup(&model, &input).misses, &input[16..]);
+        cache.clear();
+        assert!(endpoint.aembed_all(&input, None).await.is_err());
+        assert_eq!(cache.lookup(&model, &input).misses, &input[16..]);
+    }
+
+    #[test]
+    fn test_batches() {
+        let input: Vec<String> = (0..40).map(|i| format!("text {}", i)).collect();
+        let split = batches(&EmbeddingModel::EmbeddingV1, &input).unwrap();
+        assert_eq!(
+            split.iter().map(|batch| batch.len()).collect::<Vec<_>>(),
+            vec![16, 16, 8]
+        );
+        assert_eq!(split[2][0], "text 32");
+
+        let long = vec!["word ".repeat(400)];
+        assert!(batches(&EmbeddingModel::EmbeddingV1, &long).is_err());
+        assert!(batches(&EmbeddingModel::Tao8k, &long).is_ok());
 
-        Ok(EmbeddingResponse::new(response))
+        let split = batches(&EmbeddingModel::Tao8k, &input[..3]).unwrap();
+        assert_eq!(split, vec![&input[0..1], &input[1..2], &input[2..3]]);
     }
 }
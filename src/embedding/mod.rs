@@ -1,7 +1,14 @@
+mod cache;
 mod endpoint;
 mod model;
 mod response;
+mod vector;
 
+pub use cache::EmbeddingCache;
 pub use endpoint::EmbeddingEndpoint;
 pub use model::EmbeddingModel;
 pub use response::EmbeddingResponse;
+pub(crate) use vector::similarity;
+pub use vector::{
+    cosine_similarity, dot, euclidean_distance, l2_norm, l2_normalize, Embedding, Scalar,
+};
@@ -19,6 +19,35 @@ pub enum EmbeddingModel {
     Tao8k,
 }
 
+impl EmbeddingModel {
+    /// how many texts Qianfan accepts in the input of one request
+    pub fn max_batch_size(&self) -> usize {
+        match self {
+            EmbeddingModel::EmbeddingV1
+            | EmbeddingModel::BgeLargeZh
+            | EmbeddingModel::BgeLagreEn => 16,
+            EmbeddingModel::Tao8k => 1,
+        }
+    }
+
+    /// the dimension of the embeddings computed by the model
+    pub fn dimension(&self) -> usize {
+        match self {
+            EmbeddingModel::EmbeddingV1 => 384,
+            EmbeddingModel::BgeLargeZh | EmbeddingModel::BgeLagreEn | EmbeddingModel::Tao8k => 1024,
+        }
+    }
+
+    /// how many tokens Qianfan accepts in each text of the input
+    pub fn max_input_tokens(&self) -> u64 {
+        match self {
+            EmbeddingModel::EmbeddingV1 => 384,
+            EmbeddingModel::BgeLargeZh | EmbeddingModel::BgeLagreEn => 512,
+            EmbeddingModel::Tao8k => 8192,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::EmbeddingModel;
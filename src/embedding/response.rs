@@ -1,3 +1,5 @@
+use super::model::EmbeddingModel;
+use super::vector::{Embedding, Scalar};
 use crate::errors::ErnieError;
 use serde::{Deserialize, Serialize};
 use serde_json::value;
@@ -6,11 +8,33 @@ use serde_json::value;
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct EmbeddingResponse {
     raw_response: value::Value,
+    /// the model that answered, set by `EmbeddingEndpoint`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<EmbeddingModel>,
 }
 
 impl EmbeddingResponse {
     pub fn new(raw_response: value::Value) -> Self {
-        EmbeddingResponse { raw_response }
+        EmbeddingResponse {
+            raw_response,
+            model: None,
+        }
+    }
+
+    /// record the model that answered, to check the dimension of the embeddings
+    pub(crate) fn with_model(mut self, model: EmbeddingModel) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    /// replace the embeddings of the response, keeping its other fields
+    pub(crate) fn with_data(mut self, data: Vec<value::Value>) -> Self {
+        self.raw_response["data"] = value::Value::Array(data);
+        self
+    }
+
+    pub fn get_model(&self) -> Option<&EmbeddingModel> {
+        self.model.as_ref()
     }
 
     pub fn get_raw_response(&self) -> &value::Value {
@@ -27,34 +51,108 @@ impl EmbeddingResponse {
 
     /// get the result of embedding response
     pub fn get_embedding_results(&self) -> Result<Vec<Vec<f64>>, ErnieError> {
-        match self.raw_response.get("data") {
-            Some(data) => {
-                let mut result = Vec::new();
-                let mut data_array = data
-                    .as_array()
+        Ok(self
+            .get_embeddings::<f64>()?
+            .into_iter()
+            .map(|embedding| embedding.vector)
+            .collect())
+    }
+
+    /** get the embeddings of the response in the order of the input, decoded into `f64` or `f32`.
+
+    When the response comes from an `EmbeddingEndpoint`, fails with `ErnieError::DimensionMismatchError` if an embedding does not have the dimension of the model.
+    */
+    pub fn get_embeddings<T: Scalar>(&self) -> Result<Vec<Embedding<T>>, ErnieError> {
+        let data = self
+            .raw_response
+            .get("data")
+            .ok_or(ErnieError::GetResponseError(
+                "embedding data is not found".to_string(),
+            ))?
+            .as_array()
+            .ok_or(ErnieError::GetResponseError(
+                "embedding data is not an array".to_string(),
+            ))?;
+        let mut embeddings = data
+            .iter()
+            .enumerate()
+            .map(|(position, embedding_data)| {
+                let index = match embedding_data.get("index") {
+                    Some(index) => index.as_u64().ok_or(ErnieError::GetResponseError(
+                        "embedding index is not an integer".to_string(),
+                    ))? as usize,
+                    None => position,
+                };
+                let embedding = embedding_data
+                    .get("embedding")
                     .ok_or(ErnieError::GetResponseError(
-                        "embedding data is not an array".to_string(),
-                    ))?
-                    .clone();
-                data_array.sort_by_key(|a| a.get("index").unwrap().as_i64().unwrap());
-                for embedding_data in data_array {
-                    let embedding =
-                        embedding_data
-                            .get("embedding")
-                            .ok_or(ErnieError::GetResponseError(
-                                "embedding is not found".to_string(),
-                            ))?;
-                    let embedding_array: Vec<f64> = serde_json::from_value(embedding.clone())
-                        .map_err(|e| ErnieError::GetResponseError(e.to_string()))?;
-                    result.push(embedding_array);
+                        "embedding is not found".to_string(),
+                    ))?;
+                let vector = Vec::<T>::deserialize(embedding)
+                    .map_err(|e| ErnieError::GetResponseError(e.to_string()))?;
+                if let Some(model) = &self.model {
+                    if vector.len() != model.dimension() {
+                        return Err(ErnieError::DimensionMismatchError(format!(
+                            "embedding {} has dimension {}, but {} computes embeddings of dimension {}",
+                            index,
+                            vector.len(),
+                            model,
+                            model.dimension()
+                        )));
+                    }
                 }
-                Ok(result)
+                Ok(Embedding {
+                    index,
+                    vector,
+                    model: self.model.clone(),
+                })
+            })
+            .collect::<Result<Vec<_>, ErnieError>>()?;
+        embeddings.sort_by_key(|embedding| embedding.index);
+        Ok(embeddings)
+    }
+
+    /// join the responses of consecutive batches of an input into the response of the whole input, numbering the embeddings in order and adding up the usage
+    pub(crate) fn concat(responses: Vec<EmbeddingResponse>) -> Result<Self, ErnieError> {
+        let mut responses = responses.into_iter();
+        let Some(first) = responses.next() else {
+            return Ok(EmbeddingResponse::new(serde_json::json!({
+                "object": "embedding_list",
+                "data": [],
+                "usage": {"prompt_tokens": 0, "total_tokens": 0},
+            })));
+        };
+        let mut raw_response = first.raw_response.clone();
+        let model = first.model.clone();
+        let mut data = Vec::new();
+        let (mut prompt_tokens, mut total_tokens) = (0, 0);
+        for response in std::iter::once(first).chain(responses) {
+            let mut batch = response
+                .get("data")
+                .and_then(|data| data.as_array())
+                .ok_or(ErnieError::GetResponseError(
+                    "embedding data is not found".to_string(),
+                ))?
+                .clone();
+            batch.sort_by_key(|a| a.get("index").and_then(|index| index.as_i64()));
+            for mut embedding_data in batch {
+                embedding_data["index"] = data.len().into();
+                data.push(embedding_data);
             }
-            None => Err(ErnieError::GetResponseError(
-                "embedding data is not found".to_string(),
-            )),
+            prompt_tokens += response.get_prompt_tokens().unwrap_or(0);
+            total_tokens += response.get_total_tokens().unwrap_or(0);
         }
+        raw_response["data"] = value::Value::Array(data);
+        raw_response["usage"] = serde_json::json!({
+            "prompt_tokens": prompt_tokens,
+            "total_tokens": total_tokens,
+        });
+        Ok(EmbeddingResponse {
+            raw_response,
+            model,
+        })
     }
+
     /// get tokens used by prompt
     pub fn get_prompt_tokens(&self) -> Option<u64> {
         let usage = self.get("usage")?.as_object()?;
@@ -68,3 +166,58 @@ impl EmbeddingResponse {
         Some(total_tokens)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::EmbeddingResponse;
+    use crate::embedding::EmbeddingModel;
+    use crate::errors::ErnieError;
+    use serde_json::json;
+
+    #[test]
+    fn test_concat() {
+        let first = EmbeddingResponse::new(json!({
+            "object": "embedding_list",
+            "data": [
+                {"object": "embedding", "embedding": [0.2], "index": 1},
+                {"object": "embedding", "embedding": [0.1], "index": 0},
+            ],
+            "usage": {"prompt_tokens": 4, "total_tokens": 4},
+        }));
+        let second = EmbeddingResponse::new(json!({
+            "object": "embedding_list",
+            "data": [{"object": "embedding", "embedding": [0.3], "index": 0}],
+            "usage": {"prompt_tokens": 2, "total_tokens": 2},
+        }));
+        let response = EmbeddingResponse::concat(vec![first, second]).unwrap();
+        assert_eq!(
+            response.get_embedding_results().unwrap(),
+            vec![vec![0.1], vec![0.2], vec![0.3]]
+        );
+        assert_eq!(response.get("data").unwrap()[2]["index"], 2);
+        assert_eq!(response.get_prompt_tokens(), Some(6));
+        assert_eq!(response.get_total_tokens(), Some(6));
+    }
+
+    #[test]
+    fn test_get_embeddings() {
+        let raw = json!({
+            "data": [
+                {"object": "embedding", "embedding": [0.5, 0.25], "index": 1},
+                {"object": "embedding", "embedding": [1.0, 0.0], "index": 0},
+            ],
+        });
+        let embeddings = EmbeddingResponse::new(raw.clone())
+            .get_embeddings::<f32>()
+            .unwrap();
+        assert_eq!(embeddings[0].index, 0);
+        assert_eq!(embeddings[1].vector, vec![0.5f32, 0.25]);
+        assert_eq!(embeddings[1].dimension(), 2);
+
+        let response = EmbeddingResponse::new(raw).with_model(EmbeddingModel::EmbeddingV1);
+        assert!(matches!(
+            response.get_embeddings::<f64>(),
+            Err(ErnieError::DimensionMismatchError(_))
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+use super::model::EmbeddingModel;
+use crate::errors::ErnieError;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+/// The floating point types embeddings can be decoded into: `f64`, as returned by Qianfan, or `f32`, taking half the memory.
+pub trait Scalar:
+    Copy
+    + Default
+    + PartialOrd
+    + Debug
+    + Into<f64>
+    + Serialize
+    + DeserializeOwned
+    + Send
+    + Sync
+    + 'static
+{
+    fn from_f64(value: f64) -> Self;
+}
+
+impl Scalar for f32 {
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+}
+
+impl Scalar for f64 {
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+}
+
+fn check_dimensions<T: Scalar>(a: &[T], b: &[T]) -> Result<(), ErnieError> {
+    if a.len() != b.len() {
+        return Err(ErnieError::DimensionMismatchError(format!(
+            "cannot compare vectors of dimensions {} and {}",
+            a.len(),
+            b.len()
+        )));
+    }
+    Ok(())
+}
+
+/// dot product of two vectors of the same dimension
+pub fn dot<T: Scalar>(a: &[T], b: &[T]) -> Result<f64, ErnieError> {
+    check_dimensions(a, b)?;
+    Ok(similarity(a, b))
+}
+
+/// cosine similarity of two normalized vectors known to have the same dimension, that is their dot product
+pub(crate) fn similarity<T: Scalar>(a: &[T], b: &[T]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (*x).into() * (*y).into())
+        .sum()
+}
+
+/// cosine similarity of two vectors of the same dimension, between -1 and 1, or 0 if one of them is zero
+pub fn cosine_similarity<T: Scalar>(a: &[T], b: &[T]) -> Result<f64, ErnieError> {
+    let norms = l2_norm(a) * l2_norm(b);
+    if norms == 0.0 {
+        check_dimensions(a, b)?;
+        return Ok(0.0);
+    }
+    Ok(dot(a, b)? / norms)
+}
+
+/// euclidean distance between two vectors of the same dimension
+pub fn euclidean_distance<T: Scalar>(a: &[T], b: &[T]) -> Result<f64, ErnieError> {
+    check_dimensions(a, b)?;
+    Ok(a.iter()
+        .zip(b)
+        .map(|(x, y)| ((*x).into() - (*y).into()).powi(2))
+        .sum::<f64>()
+        .sqrt())
+}
+
+/// euclidean norm of a vector
+pub fn l2_norm<T: Scalar>(vector: &[T]) -> f64 {
+    vector
+        .iter()
+        .map(|x| (*x).into().powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// scale a vector to a norm of 1, so that the dot product of normalized vectors is their cosine similarity. A zero vector is left as is.
+pub fn l2_normalize<T: Scalar>(vector: &mut [T]) {
+    let norm = l2_norm(vector);
+    if norm > 0.0 {
+        vector
+            .iter_mut()
+            .for_each(|x| *x = T::from_f64((*x).into() / norm));
+    }
+}
+
+/** One embedding of an `EmbeddingResponse`, decoded into `f64` or `f32`.
+```
+use erniebot_rs::embedding::Embedding;
+let a = Embedding::<f32>::new(0, vec![3.0, 4.0]);
+let b = Embedding::<f32>::new(1, vec![4.0, 3.0]);
+assert_eq!(a.dimension(), 2);
+assert!((a.cosine_similarity(&b).unwrap() - 0.96).abs() < 1e-6);
+assert_eq!(a.normalized().vector, vec![0.6, 0.8]);
+```
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Embedding<T: Scalar = f64> {
+    /// the position of the embedded text in the input
+    pub index: usize,
+    pub vector: Vec<T>,
+    /// the model that computed the embedding, if known
+    pub model: Option<EmbeddingModel>,
+}
+
+impl<T: Scalar> Embedding<T> {
+    pub fn new(index: usize, vector: Vec<T>) -> Self {
+        Embedding {
+            index,
+            vector,
+            model: None,
+        }
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.vector.len()
+    }
+
+    pub fn norm(&self) -> f64 {
+        l2_norm(&self.vector)
+    }
+
+    pub fn normalize(&mut self) {
+        l2_normalize(&mut self.vector);
+    }
+
+    pub fn normalized(mut self) -> Self {
+        self.normalize();
+        self
+    }
+
+    pub fn dot(&self, other: &Self) -> Result<f64, ErnieError> {
+        dot(&self.vector, &other.vector)
+    }
+
+    pub fn cosine_similarity(&self, other: &Self) -> Result<f64, ErnieError> {
+        cosine_similarity(&self.vector, &other.vector)
+    }
+
+    pub fn euclidean_distance(&self, other: &Self) -> Result<f64, ErnieError> {
+        euclidean_distance(&self.vector, &other.vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cosine_similarity, dot, euclidean_distance, l2_normalize};
+    use crate::errors::ErnieError;
+
+    #[test]
+    fn test_similarity() {
+        let a = [1.0f32, 0.0, 1.0];
+        let b = [0.0f32, 2.0, 2.0];
+        assert_eq!(dot(&a, &b).unwrap(), 2.0);
+        assert!((cosine_similarity(&a, &b).unwrap() - 0.5).abs() < 1e-9);
+        assert!((euclidean_distance(&a, &b).unwrap() - 6f64.sqrt()).abs() < 1e-9);
+        assert_eq!(cosine_similarity(&[0.0f64, 0.0], &[1.0, 0.0]).unwrap(), 0.0);
+        assert!(matches!(
+            dot(&a, &[1.0, 2.0]),
+            Err(ErnieError::DimensionMismatchError(_))
+        ));
+
+        let mut c = vec![0.0f64, 3.0, 4.0];
+        l2_normalize(&mut c);
+        assert_eq!(c, vec![0.0, 0.6, 0.8]);
+    }
+}
@@ -1,6 +1,6 @@
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum ErnieError {
     #[error("GetResponseError: {0}")]
     GetResponseError(String),
@@ -14,6 +14,98 @@ pub enum ErnieError {
     GenerateBodyError(String),
     #[error("RemoteAPIError: {0}")]
     RemoteAPIError(String),
+    #[error("BudgetExceededError: {0}")]
+    BudgetExceededError(String),
+    #[error("ConnectTimeoutError: {0}")]
+    ConnectTimeoutError(String),
+    #[error("ReadTimeoutError: {0}")]
+    ReadTimeoutError(String),
+    #[error("FirstTokenTimeoutError: {0}")]
+    FirstTokenTimeoutError(String),
+    #[error("IdleTimeoutError: {0}")]
+    IdleTimeoutError(String),
+    #[error("CircuitOpenError: {0}")]
+    CircuitOpenError(String),
+    #[error("CacheError: {0}")]
+    CacheError(String),
+    #[error("DimensionMismatchError: {0}")]
+    DimensionMismatchError(String),
+    #[error("VectorStoreError: {0}")]
+    VectorStoreError(String),
+    #[error("InvalidArgumentError: {0}")]
+    InvalidArgumentError(String),
     #[error("BuildUrlError: {0}")]
     BuildUrlError(#[from] url::ParseError),
 }
+
+/// Qianfan error codes meaning a rate limit was hit: 4 (cluster QPS), 18 (QPS), 336501 (RPM) and 336502 (TPM).
+pub(crate) static RATE_LIMIT_ERROR_CODES: [i64; 4] = [4, 18, 336501, 336502];
+/// Qianfan error codes meaning a temporary failure on the server side: unknown error, service unavailable, internal error and "try again".
+static SERVER_ERROR_CODES: [i64; 4] = [1, 2, 336000, 336100];
+/// Qianfan error codes meaning the credentials were rejected: no permission, token service failure, IAM failure, invalid or expired access token.
+static AUTH_ERROR_CODES: [i64; 5] = [6, 13, 14, 110, 111];
+
+impl ErnieError {
+    /// the `error_code` returned by Qianfan, for a `RemoteAPIError`
+    pub fn remote_error_code(&self) -> Option<i64> {
+        match self {
+            ErnieError::RemoteAPIError(response) => {
+                serde_json::from_str::<serde_json::Value>(response)
+                    .ok()?
+                    .get("error_code")?
+                    .as_i64()
+            }
+            _ => None,
+        }
+    }
+
+    /// whether Qianfan rejected the call because a rate limit was hit
+    pub fn is_rate_limited(&self) -> bool {
+        self.remote_error_code()
+            .is_some_and(|code| RATE_LIMIT_ERROR_CODES.contains(&code))
+    }
+
+    /// whether Qianfan rejected the credentials of the call
+    pub fn is_auth_error(&self) -> bool {
+        self.remote_error_code()
+            .is_some_and(|code| AUTH_ERROR_CODES.contains(&code))
+    }
+
+    /// whether the call may succeed when tried again, possibly with another model: rate limits, server side failures, network errors, timeouts and open circuits
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ErnieError::RemoteAPIError(_) => self.remote_error_code().is_some_and(|code| {
+                RATE_LIMIT_ERROR_CODES.contains(&code) || SERVER_ERROR_CODES.contains(&code)
+            }),
+            ErnieError::InvokeError(_)
+            | ErnieError::StreamError(_)
+            | ErnieError::ConnectTimeoutError(_)
+            | ErnieError::ReadTimeoutError(_)
+            | ErnieError::FirstTokenTimeoutError(_)
+            | ErnieError::IdleTimeoutError(_)
+            | ErnieError::CircuitOpenError(_) => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ErnieError;
+    #[test]
+    fn test_error_codes() {
+        let error = ErnieError::RemoteAPIError(
+            r#"{"error_code":336501,"error_msg":"Rate limit reached for RPM"}"#.to_string(),
+        );
+        assert_eq!(error.remote_error_code(), Some(336501));
+        assert!(error.is_rate_limited());
+        assert!(error.is_retryable());
+        let error = ErnieError::RemoteAPIError(
+            r#"{"error_code":110,"error_msg":"Access token invalid or no longer valid"}"#
+                .to_string(),
+        );
+        assert!(error.is_auth_error());
+        assert!(!error.is_retryable());
+        assert!(!ErnieError::GenerateBodyError("bad".to_string()).is_retryable());
+    }
+}
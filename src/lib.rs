@@ -1,9 +1,37 @@
+/// Caching of responses in memory or on disk
+pub mod cache;
 /// Toolset to interact with LLM chat model in Qianfan platform
 pub mod chat;
+/// Circuit breaker failing fast while an endpoint keeps failing
+pub mod circuit_breaker;
+/// Near-duplicate detection and clustering of embeddings
+pub mod clustering;
+/// Load balancing across the credentials of several Qianfan applications
+pub mod credentials;
 /// Toolset to interact with embedding model in Qianfan platform
 pub mod embedding;
 pub mod errors;
+/// Metrics hook receiving the measurements of every call
+pub mod metrics;
+/// Hooks to change or audit the bodies of requests and responses
+pub mod middleware;
+/// Retrieval augmented generation combining a retriever, a reranker and a chat endpoint
+pub mod rag;
+/// Client-side rate limiting for the requests and tokens per minute quotas
+pub mod rate_limiter;
 pub mod reranker;
+/// Coalescing of concurrent identical async calls into one request
+pub mod single_flight;
 /// Toolset to interact with text2image model in Qianfan platform
 pub mod text2image;
+/// Splitting of documents into chunks small enough to be embedded
+pub mod text_splitter;
+/// Connect, read and stream timeouts of endpoints
+pub mod timeout;
+mod trace;
+mod transport;
+/// Usage and cost accounting shared by all endpoints
+pub mod usage;
 pub mod utils;
+/// In-memory vector store for retrieval augmented generation
+pub mod vector_store;
@@ -0,0 +1,153 @@
+use crate::errors::ErnieError;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The measurements of one finished call, reported to a `MetricsHook`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestMetrics {
+    /// "chat", "embedding", "reranker" or "text2image"
+    pub endpoint: &'static str,
+    /// the last part of the endpoint url, e.g. "completions_pro"
+    pub model: String,
+    /// "invoke", "stream", "ainvoke" or "astream"
+    pub call: &'static str,
+    /// see `outcome`, "cache_hit" for calls served by a `ResponseCache`, or "coalesced" for calls served by a `SingleFlight`
+    pub outcome: &'static str,
+    /// the Qianfan `error_code`, if the call failed with one
+    pub error_code: Option<i64>,
+    /// time from the call to its end, including the time spent waiting for rate limiters and retries
+    pub latency: Duration,
+    /// time until the first chunk, for streams
+    pub time_to_first_token: Option<Duration>,
+    /// tokens billed for the call, 0 for cache hits and coalesced calls
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// how many times the request was sent again after a rate limit error
+    pub retries: u32,
+}
+
+/// the outcome label of a call that reached the API: "success", "rate_limited", "timeout", "circuit_open" or "error"
+pub fn outcome(error: Option<&ErnieError>) -> &'static str {
+    match error {
+        None => "success",
+        Some(e) if e.is_rate_limited() => "rate_limited",
+        Some(
+            ErnieError::ConnectTimeoutError(_)
+            | ErnieError::ReadTimeoutError(_)
+            | ErnieError::FirstTokenTimeoutError(_)
+            | ErnieError::IdleTimeoutError(_),
+        ) => "timeout",
+        Some(ErnieError::CircuitOpenError(_)) => "circuit_open",
+        Some(_) => "error",
+    }
+}
+
+/** A MetricsHook receives the measurements of every call made by the endpoints it is attached to with `with_metrics`.
+
+Implement it to feed your own metrics system. With the `metrics` feature, `MetricsFacade` reports into the `metrics` crate facade, for example to a Prometheus exporter.
+```
+use erniebot_rs::metrics::{MetricsHook, RequestMetrics};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Default)]
+struct TokenCounter(AtomicU64);
+
+impl MetricsHook for TokenCounter {
+    fn record_request(&self, metrics: &RequestMetrics) {
+        self.0.fetch_add(
+            metrics.prompt_tokens + metrics.completion_tokens,
+            Ordering::Relaxed,
+        );
+    }
+}
+```
+*/
+pub trait MetricsHook: Send + Sync {
+    /// called once per call, when it ends
+    fn record_request(&self, metrics: &RequestMetrics);
+}
+
+/// The hook attached to an endpoint
+#[derive(Clone)]
+pub(crate) struct Metrics(pub(crate) Arc<dyn MetricsHook>);
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+/** MetricsFacade reports every call into the `metrics` crate facade:
+
+- `erniebot_requests_total` counter, labelled by endpoint, model, call and outcome
+- `erniebot_request_duration_seconds` histogram, labelled by endpoint, model and call
+- `erniebot_time_to_first_token_seconds` histogram for streams, labelled by endpoint, model and call
+- `erniebot_prompt_tokens_total` and `erniebot_completion_tokens_total` counters, labelled by endpoint and model
+- `erniebot_retries_total` counter, labelled by endpoint and model
+*/
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsFacade;
+
+#[cfg(feature = "metrics")]
+impl MetricsHook for MetricsFacade {
+    fn record_request(&self, metrics: &RequestMetrics) {
+        let endpoint = metrics.endpoint;
+        let model = metrics.model.clone();
+        let call = metrics.call;
+        ::metrics::counter!(
+            "erniebot_requests_total",
+            "endpoint" => endpoint,
+            "model" => model.clone(),
+            "call" => call,
+            "outcome" => metrics.outcome,
+        )
+        .increment(1);
+        ::metrics::histogram!(
+            "erniebot_request_duration_seconds",
+            "endpoint" => endpoint,
+            "model" => model.clone(),
+            "call" => call,
+        )
+        .record(metrics.latency.as_secs_f64());
+        if let Some(time_to_first_token) = metrics.time_to_first_token {
+            ::metrics::histogram!(
+                "erniebot_time_to_first_token_seconds",
+                "endpoint" => endpoint,
+                "model" => model.clone(),
+                "call" => call,
+            )
+            .record(time_to_first_token.as_secs_f64());
+        }
+        ::metrics::counter!("erniebot_prompt_tokens_total", "endpoint" => endpoint, "model" => model.clone())
+            .increment(metrics.prompt_tokens);
+        ::metrics::counter!("erniebot_completion_tokens_total", "endpoint" => endpoint, "model" => model.clone())
+            .increment(metrics.completion_tokens);
+        ::metrics::counter!("erniebot_retries_total", "endpoint" => endpoint, "model" => model)
+            .increment(metrics.retries as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::outcome;
+    use crate::errors::ErnieError;
+
+    #[test]
+    fn test_outcome() {
+        assert_eq!(outcome(None), "success");
+        let rate_limited = ErnieError::RemoteAPIError(
+            r#"{"error_code":336502,"error_msg":"Rate limit reached for TPM"}"#.to_string(),
+        );
+        assert_eq!(outcome(Some(&rate_limited)), "rate_limited");
+        assert_eq!(
+            outcome(Some(&ErnieError::IdleTimeoutError("slow".to_string()))),
+            "timeout"
+        );
+        assert_eq!(
+            outcome(Some(&ErnieError::GenerateBodyError("bad".to_string()))),
+            "error"
+        );
+    }
+}
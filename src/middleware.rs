@@ -0,0 +1,122 @@
+use crate::errors::ErnieError;
+use serde_json::Value;
+use std::fmt;
+use std::sync::Arc;
+
+/// What a middleware knows about the call it runs for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestContext<'a> {
+    /// "chat", "embedding", "reranker" or "text2image"
+    pub endpoint: &'static str,
+    /// the last part of the endpoint url, e.g. "completions_pro"
+    pub model: &'a str,
+    /// "invoke", "stream", "ainvoke" or "astream"
+    pub call: &'static str,
+}
+
+/** A Middleware sees, and may change, the json bodies going through an endpoint.
+
+Register middlewares with `with_middleware` on any endpoint. They run for every call, blocking or async:
+- `before_request` runs on the request body before anything else, including the lookup of a `ResponseCache`, so the budget of a `UsageTracker` applies to the `user_id` a middleware sets. Middlewares run in the order they were registered.
+- `after_response` runs on the response of non-stream calls, `on_stream_chunk` on each chunk of stream calls. Middlewares run in the reverse order, so the first registered one sees the response last.
+
+Returning an error from a hook fails the call with that error.
+```no_run
+use erniebot_rs::chat::{ChatEndpoint, ChatModel};
+use erniebot_rs::errors::ErnieError;
+use erniebot_rs::middleware::{Middleware, RequestContext};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// sends every chat call on behalf of the same user
+struct UserId(String);
+
+impl Middleware for UserId {
+    fn before_request(&self, context: &RequestContext, body: &mut Value) -> Result<(), ErnieError> {
+        if context.endpoint == "chat" {
+            body["user_id"] = Value::String(self.0.clone());
+        }
+        Ok(())
+    }
+}
+
+let chat = ChatEndpoint::new(ChatModel::ErnieBotTurbo)
+    .unwrap()
+    .with_middleware(Arc::new(UserId("alice".to_string())));
+```
+*/
+pub trait Middleware: Send + Sync {
+    /// change or check the body of a request before it is sent
+    fn before_request(&self, context: &RequestContext, body: &mut Value) -> Result<(), ErnieError> {
+        let _ = (context, body);
+        Ok(())
+    }
+
+    /// change or check the response of a non-stream call
+    fn after_response(
+        &self,
+        context: &RequestContext,
+        response: &mut Value,
+    ) -> Result<(), ErnieError> {
+        let _ = (context, response);
+        Ok(())
+    }
+
+    /// change or check one chunk of a stream call
+    fn on_stream_chunk(
+        &self,
+        context: &RequestContext,
+        chunk: &mut Value,
+    ) -> Result<(), ErnieError> {
+        let _ = (context, chunk);
+        Ok(())
+    }
+}
+
+/// The middlewares registered on an endpoint
+#[derive(Clone, Default)]
+pub(crate) struct Middlewares(pub(crate) Vec<Arc<dyn Middleware>>);
+
+impl fmt::Debug for Middlewares {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Middlewares({})", self.0.len())
+    }
+}
+
+impl Middlewares {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn before_request(
+        &self,
+        context: &RequestContext,
+        body: &mut Value,
+    ) -> Result<(), ErnieError> {
+        self.0
+            .iter()
+            .try_for_each(|middleware| middleware.before_request(context, body))
+    }
+
+    pub(crate) fn after_response(
+        &self,
+        context: &RequestContext,
+        response: &mut Value,
+    ) -> Result<(), ErnieError> {
+        self.0
+            .iter()
+            .rev()
+            .try_for_each(|middleware| middleware.after_response(context, response))
+    }
+
+    pub(crate) fn on_stream_chunk(
+        &self,
+        context: &RequestContext,
+        chunk: &mut Value,
+    ) -> Result<(), ErnieError> {
+        self.0
+            .iter()
+            .rev()
+            .try_for_each(|middleware| middleware.on_stream_chunk(context, chunk))
+    }
+}
@@ -0,0 +1,529 @@
+use crate::chat::{
+    parse_citations, ChatEndpoint, ChatOpt, CitedSpan, Message, Response, Responses, Role,
+    StreamResponse,
+};
+use crate::errors::ErnieError;
+use crate::reranker::{Reranked, RerankerEndpoint};
+use crate::vector_store::{Filter, HybridStore, ScoredDocument, VectorStore};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// The future returned by `Retriever::aretrieve`.
+pub type RetrieveFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<ScoredDocument>, ErnieError>> + Send + 'a>>;
+
+/** A source of candidate documents for a `RagPipeline`.
+
+`VectorStore` and `HybridStore` are retrievers. Implement this trait to retrieve from a search engine, a database... The default `aretrieve` runs `retrieve`, override it when the source has an async client.
+*/
+pub trait Retriever: Send + Sync {
+    /// the `k` documents most relevant to `query`, among the ones matching `filter`, the most relevant first
+    fn retrieve(
+        &self,
+        query: &str,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<ScoredDocument>, ErnieError>;
+
+    /// async version of `retrieve`
+    fn aretrieve<'a>(
+        &'a self,
+        query: &'a str,
+        k: usize,
+        filter: Option<&'a Filter>,
+    ) -> RetrieveFuture<'a> {
+        Box::pin(std::future::ready(self.retrieve(query, k, filter)))
+    }
+}
+
+impl Retriever for VectorStore {
+    fn retrieve(
+        &self,
+        query: &str,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<ScoredDocument>, ErnieError> {
+        self.search(query, k, filter)
+    }
+
+    fn aretrieve<'a>(
+        &'a self,
+        query: &'a str,
+        k: usize,
+        filter: Option<&'a Filter>,
+    ) -> RetrieveFuture<'a> {
+        Box::pin(self.asearch(query, k, filter))
+    }
+}
+
+impl Retriever for HybridStore {
+    fn retrieve(
+        &self,
+        query: &str,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<ScoredDocument>, ErnieError> {
+        self.search(query, k, filter)
+    }
+
+    fn aretrieve<'a>(
+        &'a self,
+        query: &'a str,
+        k: usize,
+        filter: Option<&'a Filter>,
+    ) -> RetrieveFuture<'a> {
+        Box::pin(self.asearch(query, k, filter))
+    }
+}
+
+/// Replace every `{name}` of `template` by its value in a single pass, so that braces in the values are kept as they are.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    'outer: while let Some(open) = rest.find('{') {
+        filled.push_str(&rest[..open]);
+        rest = &rest[open..];
+        for (name, value) in values {
+            if let Some(after) = rest
+                .strip_prefix('{')
+                .and_then(|r| r.strip_prefix(name))
+                .and_then(|r| r.strip_prefix('}'))
+            {
+                filled.push_str(value);
+                rest = after;
+                continue 'outer;
+            }
+        }
+        filled.push('{');
+        rest = &rest[1..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+static DEFAULT_TEMPLATE: &str = "Answer the question using only the documents below. \
+Cite the documents you use with markers like ^[1]^ right after the sentences relying on them. \
+If the documents do not contain the answer, say that you do not know.\n\n\
+{context}\n\nQuestion: {question}";
+
+static DEFAULT_DOCUMENT_FORMAT: &str = "[{index}] {text}";
+
+/** The prompt sent to the chat endpoint by a `RagPipeline`.
+
+The template must contain `{context}` and `{question}`. The context is made of the selected documents, each formatted with the document format (placeholders `{index}`, `{id}` and `{text}`) and separated by blank lines. The index of a document starts at 1 and is the number the answer is expected to cite with `^[n]^` markers.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    template: String,
+    document_format: String,
+}
+
+impl Default for PromptTemplate {
+    fn default() -> Self {
+        PromptTemplate {
+            template: DEFAULT_TEMPLATE.to_string(),
+            document_format: DEFAULT_DOCUMENT_FORMAT.to_string(),
+        }
+    }
+}
+
+impl PromptTemplate {
+    /// create a template, failing with `ErnieError::InvalidArgumentError` if `{context}` or `{question}` is missing
+    pub fn new(template: &str) -> Result<Self, ErnieError> {
+        let missing: Vec<&str> = ["{context}", "{question}"]
+            .into_iter()
+            .filter(|placeholder| !template.contains(placeholder))
+            .collect();
+        if !missing.is_empty() {
+            return Err(ErnieError::InvalidArgumentError(format!(
+                "the prompt template lacks the placeholder {}",
+                missing.join(" and ")
+            )));
+        }
+        Ok(PromptTemplate {
+            template: template.to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// set how each document is written in the context, e.g. `"<doc id={id}>{text}</doc>"`
+    pub fn with_document_format(mut self, document_format: &str) -> Self {
+        self.document_format = document_format.to_string();
+        self
+    }
+
+    pub fn render(&self, question: &str, sources: &[ScoredDocument]) -> String {
+        let context = sources
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                fill(
+                    &self.document_format,
+                    &[
+                        ("index", &(i + 1).to_string()),
+                        ("id", &source.document.id),
+                        ("text", &source.document.text),
+                    ],
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        fill(
+            &self.template,
+            &[("context", &context), ("question", question)],
+        )
+    }
+}
+
+/// A piece of a RAG answer together with the source documents cited right after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RagCitation {
+    /// the span of the answer, see `parse_citations`
+    pub span: CitedSpan,
+    /// the sources matching `span.indices`. Indices without a matching source are skipped.
+    pub documents: Vec<ScoredDocument>,
+}
+
+/// The answer of a `RagPipeline`, together with the documents given to the chat model.
+#[derive(Debug)]
+pub struct RagResponse<T> {
+    pub response: T,
+    /// the documents of the prompt, in order: the one cited as `^[1]^` first. The score is the relevance given by the reranker, or the retrieval score without reranker.
+    pub sources: Vec<ScoredDocument>,
+}
+
+impl<T> RagResponse<T> {
+    /// split an answer into spans at its citation markers, mapping each marker back to the source documents
+    pub fn cite(&self, answer: &str) -> Vec<RagCitation> {
+        parse_citations(answer, &[])
+            .into_iter()
+            .map(|span| RagCitation {
+                documents: span
+                    .indices
+                    .iter()
+                    .filter_map(|index| self.sources.get((*index as usize).checked_sub(1)?))
+                    .cloned()
+                    .collect(),
+                span,
+            })
+            .collect()
+    }
+}
+
+impl RagResponse<Response> {
+    /// the citations of the answer, see `cite`
+    pub fn citations(&self) -> Result<Vec<RagCitation>, ErnieError> {
+        Ok(self.cite(&self.response.get_chat_result()?))
+    }
+}
+
+impl RagResponse<Responses> {
+    /// the citations of the whole answer, see `cite`
+    pub fn citations(&self) -> Result<Vec<RagCitation>, ErnieError> {
+        Ok(self.cite(&self.response.get_whole_result()?))
+    }
+}
+
+/** RagPipeline answers questions from documents: it retrieves candidates, optionally reranks them, writes the best ones into a prompt and calls a chat endpoint.
+
+Documents are kept when the reranker scores them at least `min_relevance`, and at most `top_n` of them go into the prompt. Without reranker, the first `top_n` retrieved documents are used and `min_relevance` is ignored, since retrieval scores (cosine similarity, BM25, fused ranks) have no common scale.
+```no_run
+use erniebot_rs::chat::{ChatEndpoint, ChatModel};
+use erniebot_rs::embedding::{EmbeddingEndpoint, EmbeddingModel};
+use erniebot_rs::rag::RagPipeline;
+use erniebot_rs::reranker::{RerankerEndpoint, RerankerModel};
+use erniebot_rs::vector_store::{Document, VectorStore};
+use std::sync::Arc;
+let mut store = VectorStore::new(EmbeddingEndpoint::new(EmbeddingModel::EmbeddingV1).unwrap());
+store
+    .upsert(vec![Document::new("rust", "Rust is a systems programming language.")])
+    .unwrap();
+let pipeline = RagPipeline::new(Arc::new(store), ChatEndpoint::new(ChatModel::ErnieBotTurbo).unwrap())
+    .with_reranker(RerankerEndpoint::new(RerankerModel::BceRerankerBaseV1).unwrap())
+    .with_min_relevance(0.3);
+let answer = pipeline.invoke("What is Rust?", &vec![]).unwrap();
+println!("{}", answer.response.get_chat_result().unwrap());
+for citation in answer.citations().unwrap() {
+    let ids: Vec<_> = citation.documents.iter().map(|d| d.document.id.as_str()).collect();
+    println!("{} {:?}", citation.span.text, ids);
+}
+```
+*/
+#[derive(Clone)]
+pub struct RagPipeline {
+    retriever: Arc<dyn Retriever>,
+    chat: ChatEndpoint,
+    reranker: Option<RerankerEndpoint>,
+    candidates: usize,
+    top_n: usize,
+    min_relevance: Option<f64>,
+    filter: Option<Filter>,
+    template: PromptTemplate,
+}
+
+impl RagPipeline {
+    pub fn new(retriever: Arc<dyn Retriever>, chat: ChatEndpoint) -> Self {
+        RagPipeline {
+            retriever,
+            chat,
+            reranker: None,
+            candidates: 20,
+            top_n: 4,
+            min_relevance: None,
+            filter: None,
+            template: PromptTemplate::default(),
+        }
+    }
+
+    /// rerank the retrieved candidates before selecting the documents of the prompt
+    pub fn with_reranker(mut self, reranker: RerankerEndpoint) -> Self {
+        self.reranker = Some(reranker);
+        self
+    }
+
+    /// set how many candidates are retrieved (20 by default)
+    pub fn with_candidates(mut self, candidates: usize) -> Self {
+        self.candidates = candidates.max(1);
+        self
+    }
+
+    /// set how many documents at most go into the prompt (4 by default)
+    pub fn with_top_n(mut self, top_n: usize) -> Self {
+        self.top_n = top_n.max(1);
+        self
+    }
+
+    /// drop the candidates the reranker scores below `min_relevance`. Needs `with_reranker`: without reranker, no candidate is dropped.
+    pub fn with_min_relevance(mut self, min_relevance: f64) -> Self {
+        self.min_relevance = Some(min_relevance);
+        self
+    }
+
+    /// only retrieve the documents whose metadata match `filter`
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn with_template(mut self, template: PromptTemplate) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// the reranked candidates, scored by the reranker
+    fn reranked(reranked: Vec<Reranked<ScoredDocument>>) -> Vec<ScoredDocument> {
+        reranked
+            .into_iter()
+            .map(|reranked| ScoredDocument {
+                score: reranked.relevance_score,
+                ..reranked.item
+            })
+            .collect()
+    }
+
+    /// the documents that go into the prompt for `question`: retrieved, then reranked when a reranker is set
+    pub fn retrieve(&self, question: &str) -> Result<Vec<ScoredDocument>, ErnieError> {
+        let mut candidates =
+            self.retriever
+                .retrieve(question, self.candidates, self.filter.as_ref())?;
+        let Some(reranker) = &self.reranker else {
+            candidates.truncate(self.top_n);
+            return Ok(candidates);
+        };
+        let reranked = reranker.rerank(
+            question,
+            candidates,
+            |candidate| &candidate.document.text,
+            Some(self.top_n as u64),
+            self.min_relevance,
+            None,
+        )?;
+        Ok(RagPipeline::reranked(reranked))
+    }
+
+    /// async version of `retrieve`
+    pub async fn aretrieve(&self, question: &str) -> Result<Vec<ScoredDocument>, ErnieError> {
+        let mut candidates = self
+            .retriever
+            .aretrieve(question, self.candidates, self.filter.as_ref())
+            .await?;
+        let Some(reranker) = &self.reranker else {
+            candidates.truncate(self.top_n);
+            return Ok(candidates);
+        };
+        let reranked = reranker
+            .arerank(
+                question,
+                candidates,
+                |candidate| &candidate.document.text,
+                Some(self.top_n as u64),
+                self.min_relevance,
+                None,
+            )
+            .await?;
+        Ok(RagPipeline::reranked(reranked))
+    }
+
+    /// the messages sent to the chat endpoint for `question` and its sources
+    pub fn messages(&self, question: &str, sources: &[ScoredDocument]) -> Vec<Message> {
+        vec![Message {
+            role: Role::User,
+            content: self.template.render(question, sources),
+            ..Default::default()
+        }]
+    }
+
+    /// blocking non-stream call, see `ChatEndpoint::invoke`
+    pub fn invoke(
+        &self,
+        question: &str,
+        options: &Vec<ChatOpt>,
+    ) -> Result<RagResponse<Response>, ErnieError> {
+        let sources = self.retrieve(question)?;
+        let response = self
+            .chat
+            .invoke(&self.messages(question, &sources), options)?;
+        Ok(RagResponse { response, sources })
+    }
+
+    /// blocking stream call, see `ChatEndpoint::stream`
+    pub fn stream(
+        &self,
+        question: &str,
+        options: &Vec<ChatOpt>,
+    ) -> Result<RagResponse<Responses>, ErnieError> {
+        let sources = self.retrieve(question)?;
+        let response = self
+            .chat
+            .stream(&self.messages(question, &sources), options)?;
+        Ok(RagResponse { response, sources })
+    }
+
+    /// async non-stream call, see `ChatEndpoint::ainvoke`
+    pub async fn ainvoke(
+        &self,
+        question: &str,
+        options: &Vec<ChatOpt>,
+    ) -> Result<RagResponse<Response>, ErnieError> {
+        let sources = self.aretrieve(question).await?;
+        let response = self
+            .chat
+            .ainvoke(&self.messages(question, &sources), options)
+            .await?;
+        Ok(RagResponse { response, sources })
+    }
+
+    /// async stream call, see `ChatEndpoint::astream`. The citations can be mapped with `RagResponse::cite` once the answer is collected.
+    pub async fn astream(
+        &self,
+        question: &str,
+        options: &Vec<ChatOpt>,
+    ) -> Result<RagResponse<StreamResponse>, ErnieError> {
+        let sources = self.aretrieve(question).await?;
+        let response = self
+            .chat
+            .astream(&self.messages(question, &sources), options)
+            .await?;
+        Ok(RagResponse { response, sources })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ChatModel;
+    use crate::credentials::offline_pool;
+    use crate::vector_store::Document;
+
+    struct Fixed(Vec<ScoredDocument>);
+
+    impl Retriever for Fixed {
+        fn retrieve(
+            &self,
+            _query: &str,
+            k: usize,
+            filter: Option<&Filter>,
+        ) -> Result<Vec<ScoredDocument>, ErnieError> {
+            Ok(self
+                .0
+                .iter()
+                .filter(|source| filter.is_none_or(|f| f.matches(&source.document.metadata)))
+                .take(k)
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn source(id: &str, text: &str, score: f64) -> ScoredDocument {
+        ScoredDocument {
+            document: Document::new(id, text),
+            score,
+        }
+    }
+
+    fn pipeline(sources: Vec<ScoredDocument>) -> RagPipeline {
+        RagPipeline::new(
+            Arc::new(Fixed(sources)),
+            ChatEndpoint::new_with_credential_pool(ChatModel::ErnieBotTurbo, offline_pool())
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_render() {
+        let template = PromptTemplate::new("{context}\n---\n{question}")
+            .unwrap()
+            .with_document_format("<{id}> {text}");
+        let sources = vec![
+            source("a", "braces {question} stay", 0.9),
+            source("b", "two", 0.5),
+        ];
+        assert_eq!(
+            template.render("why?", &sources),
+            "<a> braces {question} stay\n\n<b> two\n---\nwhy?"
+        );
+        assert!(matches!(
+            PromptTemplate::new("{context} without the question"),
+            Err(ErnieError::InvalidArgumentError(message)) if message.ends_with("{question}")
+        ));
+        let default = PromptTemplate::default().render("why?", &sources);
+        assert!(default.contains("[1] braces {question} stay\n\n[2] two"));
+        assert!(default.ends_with("Question: why?"));
+    }
+
+    #[test]
+    fn test_select() {
+        let sources = vec![
+            source("a", "one", 0.9),
+            source("b", "two", 0.8),
+            source("c", "three", 0.7),
+        ];
+        let pipeline = pipeline(sources.clone()).with_top_n(2);
+        let retrieved = pipeline.retrieve("question").unwrap();
+        assert_eq!(retrieved, sources[..2]);
+
+        let reranked = RagPipeline::reranked(vec![Reranked {
+            item: sources[2].clone(),
+            relevance_score: 0.95,
+            index: 2,
+        }]);
+        assert_eq!(reranked[0].document, sources[2].document);
+        assert_eq!(reranked[0].score, 0.95);
+    }
+
+    #[test]
+    fn test_cite() {
+        let response = RagResponse {
+            response: (),
+            sources: vec![source("a", "one", 0.9), source("b", "two", 0.8)],
+        };
+        let citations = response.cite("One^[1]^. Both^[1][2]^. Unknown^[3]^.");
+        assert_eq!(citations.len(), 4);
+        assert_eq!(citations[0].span.text, "One");
+        assert_eq!(citations[0].documents, response.sources[..1]);
+        assert_eq!(citations[1].documents, response.sources);
+        assert!(citations[2].documents.is_empty());
+        assert_eq!(citations[3].span.text, ".");
+    }
+}
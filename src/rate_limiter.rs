@@ -0,0 +1,406 @@
+use crate::utils::estimate_tokens;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// first wait after a rate limit error for a model without a bucket to empty, doubled on every error in a row
+static BACKOFF_BASE: Duration = Duration::from_secs(1);
+/// longest wait after rate limit errors for a model without a bucket to empty
+static BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Quota of a Qianfan application for one model. `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RateLimit {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+
+/// The rate limit state reported by the server in the `X-Ratelimit-*` response headers.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RateLimitHeaders {
+    pub limit_requests: Option<f64>,
+    pub remaining_requests: Option<f64>,
+    pub limit_tokens: Option<f64>,
+    pub remaining_tokens: Option<f64>,
+}
+
+impl RateLimitHeaders {
+    /// read the headers with a lookup function, so that it works with both `ureq` and `reqwest` responses
+    pub fn from_lookup<'a>(lookup: impl Fn(&str) -> Option<&'a str>) -> Self {
+        let number = |name: &str| lookup(name).and_then(|v| v.trim().parse::<f64>().ok());
+        RateLimitHeaders {
+            limit_requests: number("X-Ratelimit-Limit-Requests"),
+            remaining_requests: number("X-Ratelimit-Remaining-Requests"),
+            limit_tokens: number("X-Ratelimit-Limit-Tokens"),
+            remaining_tokens: number("X-Ratelimit-Remaining-Tokens"),
+        }
+    }
+}
+
+/// A token bucket refilled continuously at `capacity` per minute. The balance may go negative, which is how waiting callers queue up.
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    capacity: f64,
+    balance: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute: u32) -> Self {
+        TokenBucket {
+            capacity: per_minute as f64,
+            balance: per_minute as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.balance = (self.balance + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// take `amount` out of the bucket and return how long the caller has to wait for it
+    fn reserve(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.balance -= amount.min(self.capacity);
+        if self.balance >= 0.0 || self.capacity <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.balance * 60.0 / self.capacity)
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: f64) {
+        if capacity > 0.0 && capacity != self.capacity {
+            self.balance = self.balance.min(capacity);
+            self.capacity = capacity;
+        }
+    }
+}
+
+/// The wait imposed on a model without limits after the server rejected its requests for exceeding a quota.
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    /// rate limit errors in a row
+    errors: u32,
+    until: Instant,
+}
+
+#[derive(Debug, Default)]
+struct ModelBuckets {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+    backoff: Option<Backoff>,
+}
+
+impl ModelBuckets {
+    fn new(limit: RateLimit) -> Self {
+        ModelBuckets {
+            requests: limit.requests_per_minute.map(TokenBucket::new),
+            tokens: limit.tokens_per_minute.map(TokenBucket::new),
+            backoff: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    default_limit: RateLimit,
+    limits: HashMap<String, RateLimit>,
+    buckets: HashMap<String, ModelBuckets>,
+}
+
+impl LimiterState {
+    fn buckets(&mut self, model: &str) -> &mut ModelBuckets {
+        if !self.buckets.contains_key(model) {
+            let limit = self
+                .limits
+                .get(model)
+                .copied()
+                .unwrap_or(self.default_limit);
+            self.buckets
+                .insert(model.to_string(), ModelBuckets::new(limit));
+        }
+        self.buckets.get_mut(model).unwrap()
+    }
+}
+
+/** RateLimiter keeps calls under the requests-per-minute and tokens-per-minute quotas of a Qianfan application.
+
+Each model has its own pair of token buckets. The limiter is cheap to clone and all clones share the same buckets, so one limiter can be attached to several endpoints with `with_rate_limiter`.
+Attached endpoints wait before sending a request when the quota is used up: blocking calls sleep, async calls await. The tokens of a request are estimated with `estimate_tokens` before it is sent and corrected with the actual usage afterwards.
+The limiter also follows the `X-Ratelimit-*` headers returned by the server, and when the server still answers with a rate limit error, the request is retried after waiting (at most `max_retries` times).
+```
+use erniebot_rs::rate_limiter::{RateLimit, RateLimiter};
+let limiter = RateLimiter::new(RateLimit {
+    requests_per_minute: Some(300),
+    tokens_per_minute: Some(300_000),
+})
+.with_model_limit(
+    "completions_pro",
+    RateLimit {
+        requests_per_minute: Some(120),
+        tokens_per_minute: Some(120_000),
+    },
+);
+```
+*/
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    state: Arc<Mutex<LimiterState>>,
+    max_retries: u32,
+}
+
+impl RateLimiter {
+    /// create a limiter applying `default_limit` to every model without a specific limit
+    pub fn new(default_limit: RateLimit) -> Self {
+        RateLimiter {
+            state: Arc::new(Mutex::new(LimiterState {
+                default_limit,
+                ..Default::default()
+            })),
+            max_retries: 3,
+        }
+    }
+
+    /// set the limit of one model, by the last part of its endpoint url (e.g. "completions_pro")
+    pub fn with_model_limit(self, model: &str, limit: RateLimit) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.limits.insert(model.to_string(), limit);
+            state.buckets.remove(model);
+        }
+        self
+    }
+
+    /// set how many times a request rejected by the server with a rate limit error is retried, 3 by default
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// reserve one request and `tokens` tokens of the quota of `model`, returning how long to wait before sending it
+    pub fn reserve(&self, model: &str, tokens: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let buckets = state.buckets(model);
+        let now = Instant::now();
+        let request_wait = buckets
+            .requests
+            .as_mut()
+            .map(|bucket| bucket.reserve(1.0, now))
+            .unwrap_or_default();
+        let token_wait = buckets
+            .tokens
+            .as_mut()
+            .map(|bucket| bucket.reserve(tokens as f64, now))
+            .unwrap_or_default();
+        let backoff_wait = buckets
+            .backoff
+            .map(|backoff| backoff.until.saturating_duration_since(now))
+            .unwrap_or_default();
+        request_wait.max(token_wait).max(backoff_wait)
+    }
+
+    /// wait for the quota in a blocking way
+    pub fn acquire_blocking(&self, model: &str, tokens: u64) {
+        let wait = self.reserve(model, tokens);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// wait for the quota in an async way
+    pub async fn acquire(&self, model: &str, tokens: u64) {
+        let wait = self.reserve(model, tokens);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// correct the token bucket once the actual number of tokens of a request is known
+    pub fn adjust_tokens(&self, model: &str, estimated: u64, actual: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(bucket) = state.buckets(model).tokens.as_mut() {
+            bucket.balance -= actual as f64 - estimated as f64;
+            bucket.balance = bucket.balance.min(bucket.capacity);
+        }
+    }
+
+    /// adapt the buckets of `model` to the state reported by the server
+    pub fn observe_headers(&self, model: &str, headers: &RateLimitHeaders) {
+        let mut state = self.state.lock().unwrap();
+        let buckets = state.buckets(model);
+        let now = Instant::now();
+        for (bucket, limit, remaining) in [
+            (
+                &mut buckets.requests,
+                headers.limit_requests,
+                headers.remaining_requests,
+            ),
+            (
+                &mut buckets.tokens,
+                headers.limit_tokens,
+                headers.remaining_tokens,
+            ),
+        ] {
+            if bucket.is_none() {
+                match limit {
+                    Some(limit) if limit > 0.0 => *bucket = Some(TokenBucket::new(limit as u32)),
+                    _ => continue,
+                }
+            }
+            let bucket = bucket.as_mut().unwrap();
+            bucket.refill(now);
+            if let Some(limit) = limit {
+                bucket.set_capacity(limit);
+            }
+            if let Some(remaining) = remaining {
+                bucket.balance = bucket.balance.min(remaining);
+            }
+        }
+    }
+
+    /** empty the request and token buckets of `model` after the server rejected a request for exceeding one of its quotas.
+
+    A model without any bucket, because no limit is set for it and no `X-Ratelimit-*` header was seen yet, waits instead from 1 second, doubled on every rate limit error in a row, up to 30 seconds.
+    */
+    pub fn penalize(&self, model: &str) {
+        let mut state = self.state.lock().unwrap();
+        let buckets = state.buckets(model);
+        if buckets.requests.is_none() && buckets.tokens.is_none() {
+            let now = Instant::now();
+            // errors more than `BACKOFF_MAX` after the end of the last wait are not in a row
+            let errors = match buckets.backoff {
+                Some(backoff) if now < backoff.until + BACKOFF_MAX => backoff.errors + 1,
+                _ => 1,
+            };
+            let wait = BACKOFF_BASE
+                .saturating_mul(1 << (errors - 1).min(16))
+                .min(BACKOFF_MAX);
+            buckets.backoff = Some(Backoff {
+                errors,
+                until: now + wait,
+            });
+            return;
+        }
+        for bucket in [buckets.requests.as_mut(), buckets.tokens.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            bucket.balance = bucket.balance.min(0.0);
+        }
+    }
+}
+
+/// estimate the tokens of a request body from the texts sent to the model: the content of the messages, the system prompt, and the input, query or documents
+pub(crate) fn estimate_body_tokens(body: &Value) -> u64 {
+    fn text_tokens(value: &Value) -> u64 {
+        match value {
+            Value::String(text) => estimate_tokens(text),
+            Value::Array(values) => values.iter().map(text_tokens).sum(),
+            _ => 0,
+        }
+    }
+    let messages: u64 = body
+        .get("messages")
+        .and_then(|messages| messages.as_array())
+        .map(|messages| {
+            messages
+                .iter()
+                .filter_map(|message| message.get("content"))
+                .map(text_tokens)
+                .sum()
+        })
+        .unwrap_or(0);
+    messages
+        + ["system", "input", "query", "documents"]
+            .iter()
+            .filter_map(|field| body.get(field))
+            .map(text_tokens)
+            .sum::<u64>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_body_tokens, RateLimit, RateLimitHeaders, RateLimiter};
+    use std::time::Duration;
+
+    #[test]
+    fn test_reserve() {
+        let limiter = RateLimiter::new(RateLimit {
+            requests_per_minute: Some(60),
+            tokens_per_minute: None,
+        });
+        for _ in 0..60 {
+            assert_eq!(limiter.reserve("eb-instant", 0), Duration::ZERO);
+        }
+        let wait = limiter.reserve("eb-instant", 0);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        // other models have their own buckets
+        assert_eq!(limiter.reserve("completions", 0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_token_quota() {
+        let limiter = RateLimiter::new(RateLimit::default()).with_model_limit(
+            "completions_pro",
+            RateLimit {
+                requests_per_minute: None,
+                tokens_per_minute: Some(600),
+            },
+        );
+        assert_eq!(limiter.reserve("completions_pro", 500), Duration::ZERO);
+        limiter.adjust_tokens("completions_pro", 500, 620);
+        let wait = limiter.reserve("completions_pro", 60);
+        assert!(wait > Duration::from_secs(7) && wait <= Duration::from_secs(8));
+        assert_eq!(limiter.reserve("eb-instant", 1_000_000), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_observe_headers() {
+        let limiter = RateLimiter::new(RateLimit::default());
+        let headers = RateLimitHeaders::from_lookup(|name| match name {
+            "X-Ratelimit-Limit-Requests" => Some("60"),
+            "X-Ratelimit-Remaining-Requests" => Some("0"),
+            _ => None,
+        });
+        limiter.observe_headers("eb-instant", &headers);
+        assert!(limiter.reserve("eb-instant", 0) > Duration::from_millis(900));
+    }
+
+    #[test]
+    fn test_penalize_without_limit() {
+        let limiter = RateLimiter::new(RateLimit::default());
+        assert_eq!(limiter.reserve("eb-instant", 0), Duration::ZERO);
+        limiter.penalize("eb-instant");
+        let wait = limiter.reserve("eb-instant", 0);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+        limiter.penalize("eb-instant");
+        let wait = limiter.reserve("eb-instant", 0);
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+        for _ in 0..10 {
+            limiter.penalize("eb-instant");
+        }
+        assert!(limiter.reserve("eb-instant", 0) <= Duration::from_secs(30));
+        assert_eq!(limiter.reserve("completions", 0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_estimate_body_tokens() {
+        let body = serde_json::json!({
+            "messages": [{"role": "user", "content": "你好"}],
+            "system": "be brief",
+            "user_id": "someone",
+            "temperature": 0.5,
+        });
+        assert_eq!(estimate_body_tokens(&body), 5);
+        let body = serde_json::json!({"query": "你好", "documents": ["a b", "c"]});
+        assert_eq!(estimate_body_tokens(&body), 7);
+    }
+}
@@ -1,10 +1,22 @@
 use super::model::RerankerModel;
-use super::response::RerankerResponse;
+use super::response::{pick, Reranked, RerankerResponse};
+use crate::cache::ResponseCache;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::credentials::CredentialPool;
 use crate::errors::ErnieError;
-use crate::utils::{build_url, get_access_token};
+use crate::metrics::{Metrics, MetricsHook};
+use crate::middleware::Middleware;
+use crate::rate_limiter::RateLimiter;
+use crate::single_flight::SingleFlight;
+use crate::timeout::Timeouts;
+use crate::transport::{EndpointKind, Transport};
+use crate::usage::UsageTracker;
+use crate::utils::estimate_tokens;
 use json_value_merge::Merge;
-use serde_json::Value;
-use url::Url;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
 static RERANKER_BASE_URL: &str =
     "https://aip.baidubce.com/rpc/2.0/ai_custom/v1/wenxinworkshop/reranker/";
@@ -13,18 +25,132 @@ static RERANKER_BASE_URL: &str =
 */
 #[derive(Debug, Clone)]
 pub struct RerankerEndpoint {
-    url: Url,
-    access_token: String,
+    transport: Transport,
+    model: RerankerModel,
+    batch_concurrency: usize,
+}
+
+/// split documents into consecutive batches accepted by `model` with `query`, failing if a document is too long for it
+fn batches(
+    model: &RerankerModel,
+    query: &str,
+    documents: &[String],
+) -> Result<Vec<Range<usize>>, ErnieError> {
+    let max_tokens = model.max_input_tokens();
+    let query_tokens = estimate_tokens(query);
+    let mut batches = Vec::new();
+    let (mut start, mut tokens) = (0, query_tokens);
+    for (index, document) in documents.iter().enumerate() {
+        let document_tokens = estimate_tokens(document);
+        if query_tokens + document_tokens > max_tokens {
+            return Err(ErnieError::GenerateBodyError(format!(
+                "document {} has about {} tokens, more than the {} tokens accepted by {} with the query",
+                index, document_tokens, max_tokens, model
+            )));
+        }
+        if index - start == model.max_documents() || tokens + document_tokens > max_tokens {
+            batches.push(start..index);
+            (start, tokens) = (index, query_tokens);
+        }
+        tokens += document_tokens;
+    }
+    if start < documents.len() {
+        batches.push(start..documents.len());
+    }
+    Ok(batches)
 }
 
 impl RerankerEndpoint {
     // create a new embedding instance using pre-defined model
     pub fn new(model: RerankerModel) -> Result<Self, ErnieError> {
         Ok(RerankerEndpoint {
-            url: build_url(RERANKER_BASE_URL, model.to_string().as_str())?,
-            access_token: get_access_token()?,
+            transport: Transport::new(
+                EndpointKind::Reranker,
+                RERANKER_BASE_URL,
+                &model.to_string(),
+            )?,
+            model,
+            batch_concurrency: 4,
         })
     }
+
+    /// create a new reranker instance using pre-defined model, spreading the calls over the credentials of a pool
+    pub fn new_with_credential_pool(
+        model: RerankerModel,
+        pool: CredentialPool,
+    ) -> Result<Self, ErnieError> {
+        Ok(RerankerEndpoint {
+            transport: Transport::new_with_credential_pool(
+                EndpointKind::Reranker,
+                RERANKER_BASE_URL,
+                &model.to_string(),
+                pool,
+            )?,
+            model,
+            batch_concurrency: 4,
+        })
+    }
+    /// attach a usage tracker, which records the tokens of every call and enforces its budget before sending requests
+    pub fn with_usage_tracker(mut self, tracker: UsageTracker) -> Self {
+        self.transport.usage_tracker = Some(tracker);
+        self
+    }
+
+    /// attach a rate limiter, which makes calls wait until they fit in the requests and tokens per minute quota of the model
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.transport.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// attach a circuit breaker, which makes calls fail fast with `ErnieError::CircuitOpenError` while the endpoint keeps failing
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.transport.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// attach a metrics hook, which receives the latency, tokens, retries and outcome of every call
+    pub fn with_metrics(mut self, hook: Arc<dyn MetricsHook>) -> Self {
+        self.transport.metrics = Some(Metrics(hook));
+        self
+    }
+
+    /// add a middleware at the end of the chain, see `Middleware`
+    pub fn with_middleware(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.transport.middlewares.0.push(middleware);
+        self
+    }
+
+    /// attach a response cache, which serves identical requests without calling the API again, see `ResponseCache`
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.transport.cache = Some(cache);
+        self
+    }
+
+    /// coalesce concurrent identical `ainvoke` calls into one request, see `SingleFlight`
+    pub fn with_single_flight(mut self, single_flight: SingleFlight) -> Self {
+        self.transport.single_flight = Some(single_flight);
+        self
+    }
+
+    /// set how many batches `arerank_all` sends at the same time, 4 by default
+    pub fn with_batch_concurrency(mut self, batch_concurrency: usize) -> Self {
+        self.batch_concurrency = batch_concurrency.max(1);
+        self
+    }
+
+    /// set the timeouts of every call made by this endpoint
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.transport.timeouts = timeouts;
+        self
+    }
+
+    /// return a copy of this endpoint for a single call, with the timeouts set in `overrides` taking precedence over the endpoint ones
+    pub fn with_timeout_overrides(&self, overrides: &Timeouts) -> Self {
+        let mut endpoint = self.clone();
+        endpoint.transport.timeouts = self.transport.timeouts.merge(overrides);
+        endpoint
+    }
+
     /// sync invoke
     pub fn invoke(
         &self,
@@ -43,17 +169,7 @@ impl RerankerEndpoint {
         if let Some(user_id) = user_id {
             body.merge(&serde_json::json!({"user_id": user_id}));
         }
-        let response: Value = ureq::post(self.url.as_str())
-            .query("access_token", self.access_token.as_str())
-            .send_json(body)
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?
-            .into_json()
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?;
-        //if error_code key in response, means RemoteAPIError
-        if response.get("error_code").is_some() {
-            return Err(ErnieError::RemoteAPIError(response.to_string()));
-        }
-
+        let response = self.transport.post(body)?;
         Ok(RerankerResponse::new(response))
     }
     ///async invoke
@@ -74,23 +190,170 @@ impl RerankerEndpoint {
         if let Some(user_id) = user_id {
             body.merge(&serde_json::json!({"user_id": user_id}));
         }
-        let client = reqwest::Client::new();
-        let response: Value = client
-            .post(self.url.as_str())
-            .query(&[("access_token", self.access_token.as_str())])
-            .json(&body)
-            .send()
-            .await
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?
-            .json()
-            .await
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?;
-
-        //if error_code key in response, means RemoteAPIError
-        if response.get("error_code").is_some() {
-            return Err(ErnieError::RemoteAPIError(response.to_string()));
+        let response = self.transport.apost(body).await?;
+        Ok(RerankerResponse::new(response))
+    }
+
+    /** sync invoke on any number of documents: the documents are split into batches accepted by the model, sent one after the other.
+
+    The results of all the batches are merged, sorted by decreasing `relevance_score` and cut to `top_n`. Their `index` is the position of the document in `documents` and the usage is the sum of all the batches. Fails before sending anything if the query and a document are longer than `RerankerModel::max_input_tokens`, as estimated by `estimate_tokens`.
+    */
+    pub fn rerank_all(
+        &self,
+        query: &str,
+        documents: &[String],
+        top_n: Option<u64>,
+        user_id: Option<&str>,
+    ) -> Result<RerankerResponse, ErnieError> {
+        let responses = self.rerank_batches(query, documents, top_n, user_id)?;
+        RerankerResponse::concat(responses, top_n)
+    }
+
+    /// async version of `rerank_all`, sending up to `with_batch_concurrency` batches at the same time
+    pub async fn arerank_all(
+        &self,
+        query: &str,
+        documents: &[String],
+        top_n: Option<u64>,
+        user_id: Option<&str>,
+    ) -> Result<RerankerResponse, ErnieError> {
+        let responses = self
+            .arerank_batches(query, documents, top_n, user_id)
+            .await?;
+        RerankerResponse::concat(responses, top_n)
+    }
+
+    /// the response of each batch of documents, with the position of its first document
+    fn rerank_batches(
+        &self,
+        query: &str,
+        documents: &[String],
+        top_n: Option<u64>,
+        user_id: Option<&str>,
+    ) -> Result<Vec<(usize, RerankerResponse)>, ErnieError> {
+        batches(&self.model, query, documents)?
+            .into_iter()
+            .map(|batch| {
+                let response =
+                    self.invoke(query, &documents[batch.clone()].to_vec(), top_n, user_id)?;
+                Ok((batch.start, response))
+            })
+            .collect()
+    }
+
+    /// async version of `rerank_batches`
+    async fn arerank_batches(
+        &self,
+        query: &str,
+        documents: &[String],
+        top_n: Option<u64>,
+        user_id: Option<&str>,
+    ) -> Result<Vec<(usize, RerankerResponse)>, ErnieError> {
+        let batches = batches(&self.model, query, documents)?;
+        let permits = Arc::new(Semaphore::new(self.batch_concurrency));
+        let mut tasks = JoinSet::new();
+        for batch in batches {
+            let endpoint = self.clone();
+            let query = query.to_string();
+            let offset = batch.start;
+            let batch = documents[batch].to_vec();
+            let user_id = user_id.map(str::to_string);
+            let permits = permits.clone();
+            tasks.spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                let response = endpoint
+                    .ainvoke(&query, &batch, top_n, user_id.as_deref())
+                    .await;
+                (offset, response)
+            });
+        }
+        let mut responses = Vec::with_capacity(tasks.len());
+        while let Some(joined) = tasks.join_next().await {
+            let (offset, response) = joined.map_err(|e| ErnieError::InvokeError(e.to_string()))?;
+            responses.push((offset, response?));
         }
+        responses.sort_by_key(|(offset, _)| *offset);
+        Ok(responses)
+    }
 
-        Ok(RerankerResponse::new(response))
+    fn texts<T>(items: &[T], text: &impl Fn(&T) -> &str) -> Vec<String> {
+        items.iter().map(|item| text(item).to_string()).collect()
+    }
+
+    /** rerank any items, `text` giving the text of an item sent to the reranker.
+
+    Returns the items themselves, the most relevant first, without copying the documents of the response. Items scored below `min_score` are dropped and at most `top_n` are kept. Any number of items can be reranked, see `rerank_all`.
+    ```no_run
+    use erniebot_rs::reranker::{RerankerEndpoint, RerankerModel};
+    struct Page {
+        url: String,
+        body: String,
+    }
+    let pages = vec![Page { url: "https://www.rust-lang.org".to_string(), body: "Rust is fast.".to_string() }];
+    let reranker = RerankerEndpoint::new(RerankerModel::BceRerankerBaseV1).unwrap();
+    let reranked = reranker
+        .rerank("is rust fast?", pages, |page| &page.body, Some(10), Some(0.3), None)
+        .unwrap();
+    for page in reranked {
+        println!("{} {}", page.relevance_score, page.item.url);
+    }
+    ```
+    */
+    pub fn rerank<T>(
+        &self,
+        query: &str,
+        items: Vec<T>,
+        text: impl Fn(&T) -> &str,
+        top_n: Option<u64>,
+        min_score: Option<f64>,
+        user_id: Option<&str>,
+    ) -> Result<Vec<Reranked<T>>, ErnieError> {
+        let texts = RerankerEndpoint::texts(&items, &text);
+        let responses = self.rerank_batches(query, &texts, top_n, user_id)?;
+        let scores = RerankerResponse::merge_scores(&responses, top_n)?;
+        Ok(pick(items, scores, min_score))
+    }
+
+    /// async version of `rerank`
+    pub async fn arerank<T>(
+        &self,
+        query: &str,
+        items: Vec<T>,
+        text: impl Fn(&T) -> &str,
+        top_n: Option<u64>,
+        min_score: Option<f64>,
+        user_id: Option<&str>,
+    ) -> Result<Vec<Reranked<T>>, ErnieError> {
+        let texts = RerankerEndpoint::texts(&items, &text);
+        let responses = self.arerank_batches(query, &texts, top_n, user_id).await?;
+        let scores = RerankerResponse::merge_scores(&responses, top_n)?;
+        Ok(pick(items, scores, min_score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::batches;
+    use crate::reranker::RerankerModel;
+
+    #[test]
+    fn test_batches() {
+        let model = RerankerModel::BceRerankerBaseV1;
+        let documents: Vec<String> = (0..150).map(|i| format!("document {}", i)).collect();
+        assert_eq!(
+            batches(&model, "query", &documents).unwrap(),
+            vec![0..64, 64..128, 128..150]
+        );
+
+        let long = "word ".repeat(2000);
+        let documents = vec![long.clone(), long.clone(), "short".to_string(), long];
+        assert_eq!(
+            batches(&model, "query", &documents).unwrap(),
+            vec![0..1, 1..3, 3..4]
+        );
+
+        let documents = vec!["short".to_string(), "word ".repeat(5000)];
+        assert!(batches(&model, "query", &documents).is_err());
+        assert!(batches(&model, "query", &[]).unwrap().is_empty());
     }
 }
@@ -4,4 +4,4 @@ mod response;
 
 pub use endpoint::RerankerEndpoint;
 pub use model::RerankerModel;
-pub use response::{RerankData, RerankerResponse};
+pub use response::{RerankData, RerankScore, Reranked, RerankerResponse};
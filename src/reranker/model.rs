@@ -9,3 +9,17 @@ pub enum RerankerModel {
     #[serde(rename = "bce_reranker_base")]
     BceRerankerBaseV1,
 }
+
+impl RerankerModel {
+    /// how many documents Qianfan accepts in one request
+    pub fn max_documents(&self) -> usize {
+        64
+    }
+
+    /// how many tokens Qianfan accepts in one request, the query and all the documents together
+    pub fn max_input_tokens(&self) -> u64 {
+        match self {
+            RerankerModel::BceRerankerBaseV1 => 4096,
+        }
+    }
+}
@@ -25,30 +25,105 @@ impl RerankerResponse {
         self.raw_response.get_mut(key)
     }
 
+    /// the array of results of the response
+    fn results(&self) -> Result<&Vec<value::Value>, ErnieError> {
+        self.raw_response
+            .get("results")
+            .ok_or(ErnieError::GetResponseError(
+                "reranker results is not found".to_string(),
+            ))?
+            .as_array()
+            .ok_or(ErnieError::GetResponseError(
+                "reranker results is not an array".to_string(),
+            ))
+    }
+
     /// get the result of reranker response
     pub fn get_reranker_response(&self) -> Result<Vec<RerankData>, ErnieError> {
-        match self.raw_response.get("results") {
-            Some(data) => {
-                let data_array = data
-                    .as_array()
-                    .ok_or(ErnieError::GetResponseError(
-                        "reranker results is not an array".to_string(),
-                    ))?
-                    .clone();
-                let results = data_array
-                    .into_iter()
-                    .map(|x| {
-                        serde_json::from_value(x)
-                            .map_err(|e| ErnieError::GetResponseError(e.to_string()))
-                    })
-                    .collect::<Result<Vec<RerankData>, ErnieError>>()?;
-                Ok(results)
+        self.results()?
+            .iter()
+            .map(|x| {
+                RerankData::deserialize(x).map_err(|e| ErnieError::GetResponseError(e.to_string()))
+            })
+            .collect()
+    }
+
+    /// get the index and score of each result, without copying the documents
+    pub fn get_scores(&self) -> Result<Vec<RerankScore>, ErnieError> {
+        self.results()?
+            .iter()
+            .map(|x| {
+                RerankScore::deserialize(x).map_err(|e| ErnieError::GetResponseError(e.to_string()))
+            })
+            .collect()
+    }
+
+    /// join the responses of batches of documents into the response of all the documents, `offset` being the position of the first document of a batch. The results are sorted by decreasing relevance and cut to `top_n`, the usage is added up.
+    pub(crate) fn concat(
+        responses: Vec<(usize, RerankerResponse)>,
+        top_n: Option<u64>,
+    ) -> Result<Self, ErnieError> {
+        let mut raw_response = responses
+            .first()
+            .map(|(_, response)| response.raw_response.clone())
+            .unwrap_or_else(|| serde_json::json!({"object": "reranker_list"}));
+        let mut results = Vec::new();
+        let (mut prompt_tokens, mut total_tokens) = (0, 0);
+        for (offset, response) in &responses {
+            let batch = response
+                .get("results")
+                .and_then(|results| results.as_array())
+                .ok_or(ErnieError::GetResponseError(
+                    "reranker results is not found".to_string(),
+                ))?;
+            for result in batch {
+                let mut result = result.clone();
+                let index = result.get("index").and_then(|index| index.as_u64()).ok_or(
+                    ErnieError::GetResponseError("reranker result has no index".to_string()),
+                )?;
+                result["index"] = (index + *offset as u64).into();
+                results.push(result);
             }
-            None => Err(ErnieError::GetResponseError(
-                "reranker results is not found".to_string(),
-            )),
+            prompt_tokens += response.get_prompt_tokens().unwrap_or(0);
+            total_tokens += response.get_total_tokens().unwrap_or(0);
         }
+        let score = |result: &value::Value| {
+            result
+                .get("relevance_score")
+                .and_then(|score| score.as_f64())
+                .unwrap_or(f64::NEG_INFINITY)
+        };
+        results.sort_by(|a, b| score(b).total_cmp(&score(a)));
+        if let Some(top_n) = top_n {
+            results.truncate(top_n as usize);
+        }
+        raw_response["results"] = value::Value::Array(results);
+        raw_response["usage"] = serde_json::json!({
+            "prompt_tokens": prompt_tokens,
+            "total_tokens": total_tokens,
+        });
+        Ok(RerankerResponse { raw_response })
     }
+
+    /// merge the scores of the responses of batches of documents, `offset` being the position of the first document of a batch, without building the response of all the documents. The scores are sorted by decreasing relevance and cut to `top_n`.
+    pub(crate) fn merge_scores(
+        responses: &[(usize, RerankerResponse)],
+        top_n: Option<u64>,
+    ) -> Result<Vec<RerankScore>, ErnieError> {
+        let mut scores = Vec::new();
+        for (offset, response) in responses {
+            scores.extend(response.get_scores()?.into_iter().map(|score| RerankScore {
+                index: score.index + *offset as u64,
+                ..score
+            }));
+        }
+        scores.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+        if let Some(top_n) = top_n {
+            scores.truncate(top_n as usize);
+        }
+        Ok(scores)
+    }
+
     /// get tokens used by prompt
     pub fn get_prompt_tokens(&self) -> Option<u64> {
         let usage = self.get("usage")?.as_object()?;
@@ -69,3 +144,103 @@ pub struct RerankData {
     pub relevance_score: f64,
     pub index: u64,
 }
+
+/// The score of a document in a reranker response, see `RerankerResponse::get_scores`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct RerankScore {
+    pub relevance_score: f64,
+    pub index: u64,
+}
+
+/// An item reranked by `RerankerEndpoint::rerank`, with its score.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reranked<T> {
+    pub item: T,
+    pub relevance_score: f64,
+    /// the position of the item in the input
+    pub index: usize,
+}
+
+/// move the scored items out of `items`, in the order of `scores`, dropping the ones scored below `min_score`
+pub(crate) fn pick<T>(
+    items: Vec<T>,
+    scores: Vec<RerankScore>,
+    min_score: Option<f64>,
+) -> Vec<Reranked<T>> {
+    let mut items: Vec<Option<T>> = items.into_iter().map(Some).collect();
+    scores
+        .into_iter()
+        .filter(|score| min_score.is_none_or(|min| score.relevance_score >= min))
+        .filter_map(|score| {
+            let index = score.index as usize;
+            Some(Reranked {
+                item: items.get_mut(index)?.take()?,
+                relevance_score: score.relevance_score,
+                index,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pick, RerankScore, RerankerResponse};
+    use serde_json::json;
+
+    #[test]
+    fn test_concat() {
+        let batch = |results: serde_json::Value| {
+            RerankerResponse::new(json!({
+                "object": "reranker_list",
+                "results": results,
+                "usage": {"prompt_tokens": 10, "total_tokens": 10},
+            }))
+        };
+        let first = batch(json!([
+            {"document": "a", "relevance_score": 0.2, "index": 0},
+            {"document": "b", "relevance_score": 0.9, "index": 1},
+        ]));
+        let second = batch(json!([
+            {"document": "d", "relevance_score": 0.5, "index": 1},
+            {"document": "c", "relevance_score": 0.1, "index": 0},
+        ]));
+        let responses = vec![(0, first), (2, second)];
+        let scores = RerankerResponse::merge_scores(&responses, Some(3)).unwrap();
+        let order: Vec<_> = scores.iter().map(|score| score.index).collect();
+        assert_eq!(order, [1, 3, 0]);
+        let merged = RerankerResponse::concat(responses, Some(3)).unwrap();
+        let results = merged.get_reranker_response().unwrap();
+        let order: Vec<_> = results
+            .iter()
+            .map(|data| (data.document.as_str(), data.index))
+            .collect();
+        assert_eq!(order, [("b", 1), ("d", 3), ("a", 0)]);
+        assert_eq!(merged.get_total_tokens(), Some(20));
+        let empty = RerankerResponse::concat(vec![], None).unwrap();
+        assert!(empty.get_reranker_response().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pick() {
+        let response = RerankerResponse::new(json!({
+            "results": [
+                {"document": "c", "relevance_score": 0.95, "index": 2},
+                {"document": "a", "relevance_score": 0.6, "index": 0},
+                {"document": "b", "relevance_score": 0.2, "index": 1},
+            ],
+        }));
+        let scores = response.get_scores().unwrap();
+        assert_eq!(
+            scores[0],
+            RerankScore {
+                relevance_score: 0.95,
+                index: 2
+            }
+        );
+        let items = vec![("a", 1), ("b", 2), ("c", 3)];
+        let picked = pick(items, scores, Some(0.5));
+        let order: Vec<_> = picked.iter().map(|r| (r.item, r.index)).collect();
+        assert_eq!(order, [(("c", 3), 2), (("a", 1), 0)]);
+        assert_eq!(picked[0].relevance_score, 0.95);
+    }
+}
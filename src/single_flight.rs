@@ -0,0 +1,111 @@
+use crate::errors::ErnieError;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+type Flight = Arc<OnceCell<Result<Value, ErnieError>>>;
+
+/** SingleFlight coalesces concurrent identical async calls into one request to the API.
+
+Attach it with `with_single_flight` to an endpoint. While an `ainvoke` call is waiting for its response, any `ainvoke` call with the same url and body, made through an endpoint sharing the same `SingleFlight`, waits for that response instead of sending its own request, and gets a copy of it, or of its error. Calls are coalesced only while they overlap: once the response arrives, the next identical call sends a new request (attach a `ResponseCache` to keep answers for longer).
+
+Calls are keyed by their body after the `before_request` middlewares ran. The request is sent, with the budget check, rate limiter and circuit breaker, by the first of the calls. The others only run the `after_response` middlewares on their copy and are reported to the metrics hook with the outcome "coalesced" and no tokens. If the first call is dropped before its response arrives, one of the waiting calls sends the request instead.
+
+Blocking and stream calls are never coalesced.
+```no_run
+use erniebot_rs::embedding::{EmbeddingEndpoint, EmbeddingModel};
+use erniebot_rs::single_flight::SingleFlight;
+# async fn run() {
+let embedding = EmbeddingEndpoint::new(EmbeddingModel::EmbeddingV1)
+    .unwrap()
+    .with_single_flight(SingleFlight::new());
+let input = vec!["a chunk shared by many documents".to_string()];
+// one request is sent for the three calls
+let (a, b, c) = tokio::join!(
+    embedding.ainvoke(&input, None),
+    embedding.ainvoke(&input, None),
+    embedding.ainvoke(&input, None),
+);
+# }
+```
+*/
+#[derive(Debug, Clone, Default)]
+pub struct SingleFlight {
+    flights: Arc<Mutex<HashMap<String, Flight>>>,
+}
+
+impl SingleFlight {
+    pub fn new() -> Self {
+        SingleFlight::default()
+    }
+
+    /// how many distinct requests are waiting for their response
+    pub fn in_flight(&self) -> usize {
+        self.flights.lock().unwrap().len()
+    }
+
+    /// run `request`, unless an identical one is already running, and tell whether this call sent it
+    pub(crate) async fn run<F>(&self, key: &str, request: F) -> (Result<Value, ErnieError>, bool)
+    where
+        F: Future<Output = Result<Value, ErnieError>>,
+    {
+        let flight = self
+            .flights
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let mut sent = false;
+        let result = flight
+            .get_or_init(|| {
+                sent = true;
+                request
+            })
+            .await
+            .clone();
+        let mut flights = self.flights.lock().unwrap();
+        if flights
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, &flight))
+        {
+            flights.remove(key);
+        }
+        (result, sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SingleFlight;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_run() {
+        let single_flight = SingleFlight::new();
+        let sent = AtomicUsize::new(0);
+        let request = || async {
+            sent.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(json!({"result": "a"}))
+        };
+        let (a, b, c) = tokio::join!(
+            single_flight.run("key", request()),
+            single_flight.run("key", request()),
+            single_flight.run("other", request()),
+        );
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+        assert_eq!(a.0.unwrap(), json!({"result": "a"}));
+        assert_eq!(b.0.unwrap(), json!({"result": "a"}));
+        assert!(a.1 && !b.1 && c.1);
+        assert_eq!(single_flight.in_flight(), 0);
+
+        // not in flight anymore
+        let (_, sent_again) = single_flight.run("key", request()).await;
+        assert!(sent_again);
+    }
+}
@@ -1,39 +1,119 @@
 use json_value_merge::Merge;
-use serde_json::Value;
-use url::Url;
 
 use super::model::Text2ImageModel;
 use super::option::Text2ImageOpt;
 use super::response::Text2ImageResponse;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::credentials::CredentialPool;
 use crate::errors::ErnieError;
-use crate::utils::{build_url, get_access_token};
+use crate::metrics::{Metrics, MetricsHook};
+use crate::middleware::Middleware;
+use crate::rate_limiter::RateLimiter;
+use crate::timeout::Timeouts;
+use crate::transport::{EndpointKind, Transport};
+use crate::usage::UsageTracker;
+use std::sync::Arc;
 
 static TEXT2IMAGE_BASE_URL: &str =
     "https://aip.baidubce.com/rpc/2.0/ai_custom/v1/wenxinworkshop/text2image/";
 /// Text2ImageEndpoint is a struct that represents the text2image endpoint of erniebot API
 #[derive(Debug, Clone)]
 pub struct Text2ImageEndpoint {
-    url: Url,
-    access_token: String,
+    transport: Transport,
 }
 
 impl Text2ImageEndpoint {
     /// create a new text2image instance using pre-defined model
     pub fn new(model: Text2ImageModel) -> Result<Self, ErnieError> {
         Ok(Text2ImageEndpoint {
-            url: build_url(TEXT2IMAGE_BASE_URL, model.to_string().as_str())?,
-            access_token: get_access_token()?,
+            transport: Transport::new(
+                EndpointKind::Text2Image,
+                TEXT2IMAGE_BASE_URL,
+                &model.to_string(),
+            )?,
         })
     }
 
     /// create a new text2image instance using custom endpoint
     pub fn new_with_custom_endpoint(endpoint: &str) -> Result<Self, ErnieError> {
         Ok(Text2ImageEndpoint {
-            url: build_url(TEXT2IMAGE_BASE_URL, endpoint)?,
-            access_token: get_access_token()?,
+            transport: Transport::new(EndpointKind::Text2Image, TEXT2IMAGE_BASE_URL, endpoint)?,
         })
     }
 
+    /// create a new text2image instance using pre-defined model, spreading the calls over the credentials of a pool
+    pub fn new_with_credential_pool(
+        model: Text2ImageModel,
+        pool: CredentialPool,
+    ) -> Result<Self, ErnieError> {
+        Ok(Text2ImageEndpoint {
+            transport: Transport::new_with_credential_pool(
+                EndpointKind::Text2Image,
+                TEXT2IMAGE_BASE_URL,
+                &model.to_string(),
+                pool,
+            )?,
+        })
+    }
+
+    /// create a new text2image instance using custom endpoint, spreading the calls over the credentials of a pool
+    pub fn new_with_custom_endpoint_and_credential_pool(
+        endpoint: &str,
+        pool: CredentialPool,
+    ) -> Result<Self, ErnieError> {
+        Ok(Text2ImageEndpoint {
+            transport: Transport::new_with_credential_pool(
+                EndpointKind::Text2Image,
+                TEXT2IMAGE_BASE_URL,
+                endpoint,
+                pool,
+            )?,
+        })
+    }
+
+    /// attach a usage tracker, which records the tokens of every call and enforces its budget before sending requests
+    pub fn with_usage_tracker(mut self, tracker: UsageTracker) -> Self {
+        self.transport.usage_tracker = Some(tracker);
+        self
+    }
+
+    /// attach a rate limiter, which makes calls wait until they fit in the requests and tokens per minute quota of the model
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.transport.rate_limiter = Some(limiter);
+        self
+    }
+
+    /// attach a circuit breaker, which makes calls fail fast with `ErnieError::CircuitOpenError` while the endpoint keeps failing
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.transport.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// attach a metrics hook, which receives the latency, tokens, retries and outcome of every call
+    pub fn with_metrics(mut self, hook: Arc<dyn MetricsHook>) -> Self {
+        self.transport.metrics = Some(Metrics(hook));
+        self
+    }
+
+    /// add a middleware at the end of the chain, see `Middleware`
+    pub fn with_middleware(mut self, middleware: Arc<dyn Middleware>) -> Self {
+        self.transport.middlewares.0.push(middleware);
+        self
+    }
+
+    /// set the timeouts of every call made by this endpoint
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.transport.timeouts = timeouts;
+        self
+    }
+
+    /// return a copy of this endpoint for a single call, with the timeouts set in `overrides` taking precedence over the endpoint ones
+    pub fn with_timeout_overrides(&self, overrides: &Timeouts) -> Self {
+        let mut endpoint = self.clone();
+        endpoint.transport.timeouts = self.transport.timeouts.merge(overrides);
+        endpoint
+    }
+
     fn generate_body(prompt: &str, options: &Vec<Text2ImageOpt>) -> serde_json::Value {
         let mut body = serde_json::json!({
             "prompt": prompt,
@@ -51,17 +131,7 @@ impl Text2ImageEndpoint {
         options: &Vec<Text2ImageOpt>,
     ) -> Result<Text2ImageResponse, ErnieError> {
         let body = Text2ImageEndpoint::generate_body(prompt, options);
-        let response: Value = ureq::post(self.url.as_str())
-            .query("access_token", self.access_token.as_str())
-            .send_json(body)
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?
-            .into_json()
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?;
-        //if error_code key in response, means RemoteAPIError
-        if response.get("error_code").is_some() {
-            return Err(ErnieError::RemoteAPIError(response.to_string()));
-        }
-
+        let response = self.transport.post(body)?;
         Ok(Text2ImageResponse::new(response))
     }
 
@@ -72,22 +142,7 @@ impl Text2ImageEndpoint {
         options: &Vec<Text2ImageOpt>,
     ) -> Result<Text2ImageResponse, ErnieError> {
         let body = Text2ImageEndpoint::generate_body(prompt, options);
-        let client = reqwest::Client::new();
-        let response: Value = client
-            .post(self.url.as_str())
-            .query(&[("access_token", self.access_token.as_str())])
-            .json(&body)
-            .send()
-            .await
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?
-            .json()
-            .await
-            .map_err(|e| ErnieError::InvokeError(e.to_string()))?;
-
-        //if error_code key in response, means RemoteAPIError
-        if response.get("error_code").is_some() {
-            return Err(ErnieError::RemoteAPIError(response.to_string()));
-        }
+        let response = self.transport.apost(body).await?;
         Ok(Text2ImageResponse::new(response))
     }
 }
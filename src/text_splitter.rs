@@ -0,0 +1,392 @@
+use crate::embedding::EmbeddingModel;
+use crate::utils::{estimate_tokens, is_cjk};
+use std::ops::Range;
+
+/// Strength of the boundary before a piece of text, the lowest the strongest.
+const HEADING: u8 = 0;
+const PARAGRAPH: u8 = 1;
+const LINE: u8 = 2;
+const SENTENCE: u8 = 3;
+const CLAUSE: u8 = 4;
+const WORD: u8 = 5;
+
+/// characters ending a sentence, in Chinese and English text
+const SENTENCE_ENDS: [char; 8] = ['。', '！', '？', '；', '…', '!', '?', ';'];
+/// characters ending a clause
+const CLAUSE_ENDS: [char; 5] = ['，', '、', '：', ',', ':'];
+/// characters closing a quote or a bracket, kept with the sentence they end
+const CLOSING: [char; 11] = ['”', '’', '」', '』', '）', '】', '》', '"', '\'', ')', ']'];
+
+/// A part of a text, small enough to be embedded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub text: String,
+    /// byte offset of the chunk in the source text
+    pub start: usize,
+    /// byte offset of the end of the chunk in the source text, so that `source[start..end] == text`
+    pub end: usize,
+    /// estimated tokens, see `estimate_tokens`, plus one per punctuation mark, symbol or emoji
+    pub tokens: u64,
+    /// the last Markdown heading before the chunk, without its `#`
+    pub heading: Option<String>,
+}
+
+/// A piece of text that is never split further, and the strength of the boundary before it.
+#[derive(Debug)]
+struct Piece {
+    range: Range<usize>,
+    tokens: u64,
+    level: u8,
+}
+
+fn heading_text(line: &str) -> Option<&str> {
+    let hashes = line.chars().take_while(|&c| c == '#').count();
+    let rest = &line[hashes..];
+    if (1..=6).contains(&hashes) && rest.starts_with([' ', '\t']) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+/** TextSplitter cuts documents into chunks under a token limit, for `EmbeddingEndpoint`.
+
+Chunks end at the strongest boundary available: a Markdown heading, which always starts a new chunk, then a paragraph, a line, a sentence (ended by Chinese or English punctuation, with its closing quotes), a clause, and a word or a Chinese character at worst. Their length is measured with `estimate_tokens`, adding one token for each punctuation mark, symbol or emoji, which it leaves out but models do count. With `with_overlap`, each chunk starts with the last sentences of the previous one, so that no sentence loses its context.
+```
+use erniebot_rs::embedding::EmbeddingModel;
+use erniebot_rs::text_splitter::TextSplitter;
+let text = "# 账户\n\n点击“忘记密码”即可重置密码。重置后请重新登录！\n\n# 费用\n\n会员费用每月30元。";
+let chunks = TextSplitter::for_model(&EmbeddingModel::EmbeddingV1).split(text);
+assert_eq!(chunks.len(), 2);
+assert_eq!(chunks[1].heading.as_deref(), Some("费用"));
+assert_eq!(&text[chunks[1].start..chunks[1].end], chunks[1].text);
+```
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextSplitter {
+    max_tokens: u64,
+    overlap_tokens: u64,
+}
+
+impl TextSplitter {
+    /// create a splitter making chunks of at most `max_tokens`, without overlap
+    pub fn new(max_tokens: u64) -> Self {
+        TextSplitter {
+            max_tokens: max_tokens.max(2),
+            overlap_tokens: 0,
+        }
+    }
+
+    /// create a splitter making chunks accepted by an embedding model, see `EmbeddingModel::max_input_tokens`
+    pub fn for_model(model: &EmbeddingModel) -> Self {
+        TextSplitter::new(model.max_input_tokens())
+    }
+
+    /// repeat up to `overlap_tokens` of the end of each chunk at the start of the next one, at most half of the chunk size
+    pub fn with_overlap(mut self, overlap_tokens: u64) -> Self {
+        self.overlap_tokens = overlap_tokens.min(self.max_tokens / 2);
+        self
+    }
+
+    /// split `range` of `text` after each character matching `ends`, given the character after it
+    fn split_range(
+        text: &str,
+        range: Range<usize>,
+        ends: impl Fn(char, Option<char>) -> bool,
+    ) -> Vec<Range<usize>> {
+        let mut ranges = Vec::new();
+        let mut start = range.start;
+        let mut chars = text[range.clone()].char_indices().peekable();
+        while let Some((offset, c)) = chars.next() {
+            let next = chars.peek().map(|&(_, next)| next);
+            if !ends(c, next) {
+                continue;
+            }
+            let mut end = range.start + offset + c.len_utf8();
+            // keep closing quotes and spaces with the piece they end
+            while let Some(&(offset, next)) = chars.peek() {
+                if !(CLOSING.contains(&next) || next.is_whitespace()) {
+                    break;
+                }
+                end = range.start + offset + next.len_utf8();
+                chars.next();
+            }
+            if end > start && end < range.end {
+                ranges.push(start..end);
+                start = end;
+            }
+        }
+        if start < range.end {
+            ranges.push(start..range.end);
+        }
+        ranges
+    }
+
+    /// the estimated tokens of a piece of text, counting punctuation, symbols and emoji on top of `estimate_tokens`
+    fn count_tokens(text: &str) -> u64 {
+        let symbols = text
+            .chars()
+            .filter(|c| !c.is_alphanumeric() && !c.is_whitespace())
+            .count();
+        estimate_tokens(text) + symbols as u64
+    }
+
+    /// push a range as one piece, or, while it is too long, split it at the boundaries weaker than `split_level`
+    fn push_pieces(
+        &self,
+        text: &str,
+        range: Range<usize>,
+        level: u8,
+        split_level: u8,
+        pieces: &mut Vec<Piece>,
+    ) {
+        let tokens = TextSplitter::count_tokens(&text[range.clone()]);
+        if tokens <= self.max_tokens || split_level >= WORD {
+            pieces.push(Piece {
+                range,
+                tokens,
+                level,
+            });
+            return;
+        }
+        let next_level = split_level + 1;
+        let ranges = if next_level == CLAUSE {
+            TextSplitter::split_range(text, range, |c, next| {
+                CLAUSE_ENDS.contains(&c) && (!c.is_ascii() || next.is_none_or(char::is_whitespace))
+            })
+        } else {
+            TextSplitter::split_range(text, range, |c, next| {
+                is_cjk(c)
+                    || !(c.is_alphanumeric() || c.is_whitespace())
+                    || (c.is_whitespace() && !next.is_some_and(char::is_whitespace))
+            })
+        };
+        for (i, range) in ranges.into_iter().enumerate() {
+            let piece_level = if i == 0 { level } else { next_level };
+            self.push_pieces(text, range, piece_level, next_level, pieces);
+        }
+    }
+
+    /// cut a text into pieces, each with the strength of the boundary before it
+    fn pieces(&self, text: &str) -> Vec<Piece> {
+        let mut pieces: Vec<Piece> = Vec::new();
+        let mut offset = 0;
+        let mut after_blank = false;
+        for line in text.split_inclusive('\n') {
+            let range = offset..offset + line.len();
+            offset += line.len();
+            if line.trim().is_empty() {
+                // blank lines stay with the previous piece
+                match pieces.last_mut() {
+                    Some(piece) => piece.range.end = range.end,
+                    None => pieces.push(Piece {
+                        range,
+                        tokens: 0,
+                        level: PARAGRAPH,
+                    }),
+                }
+                after_blank = true;
+                continue;
+            }
+            let level = if heading_text(line).is_some() {
+                HEADING
+            } else if after_blank {
+                PARAGRAPH
+            } else {
+                LINE
+            };
+            after_blank = false;
+            let sentences = TextSplitter::split_range(text, range, |c, next| {
+                SENTENCE_ENDS.contains(&c) || (c == '.' && next.is_none_or(char::is_whitespace))
+            });
+            for (i, sentence) in sentences.into_iter().enumerate() {
+                let sentence_level = if i == 0 { level } else { SENTENCE };
+                self.push_pieces(text, sentence, sentence_level, SENTENCE, &mut pieces);
+            }
+        }
+        pieces
+    }
+
+    /// how many of `pieces` to put in a chunk, given the boundary before the next piece: as many as up to the strongest boundary in the second half
+    fn best_cut(&self, pieces: &[&Piece], next_level: u8) -> usize {
+        let total: u64 = pieces.iter().map(|piece| piece.tokens).sum();
+        let mut prefix = 0;
+        let mut best: Option<(u8, usize)> = None;
+        for cut in 1..=pieces.len() {
+            prefix += pieces[cut - 1].tokens;
+            if prefix * 2 < total.min(self.max_tokens) {
+                continue;
+            }
+            let level = pieces.get(cut).map_or(next_level, |piece| piece.level);
+            if best.is_none_or(|(best_level, _)| level <= best_level) {
+                best = Some((level, cut));
+            }
+        }
+        best.map_or(pieces.len(), |(_, cut)| cut)
+    }
+
+    fn chunk(text: &str, pieces: &[&Piece], headings: &[(usize, &str)]) -> Option<Chunk> {
+        let range = pieces.first()?.range.start..pieces.last()?.range.end;
+        let raw = &text[range.clone()];
+        let trimmed = raw.trim_start();
+        let start = range.start + raw.len() - trimmed.len();
+        let trimmed = trimmed.trim_end();
+        if trimmed.is_empty() {
+            return None;
+        }
+        let heading = headings
+            .iter()
+            .take_while(|(offset, _)| *offset <= start)
+            .last()
+            .map(|(_, heading)| heading.to_string());
+        Some(Chunk {
+            text: trimmed.to_string(),
+            start,
+            end: start + trimmed.len(),
+            tokens: TextSplitter::count_tokens(trimmed),
+            heading,
+        })
+    }
+
+    /// the last pieces of a chunk, to repeat at the start of the next one
+    fn overlap<'a>(&self, pieces: &[&'a Piece]) -> Vec<&'a Piece> {
+        let mut tokens = 0;
+        let mut overlap = Vec::new();
+        for piece in pieces.iter().rev() {
+            if piece.level == HEADING || tokens + piece.tokens > self.overlap_tokens {
+                break;
+            }
+            tokens += piece.tokens;
+            overlap.insert(0, *piece);
+        }
+        // a chunk made only of the overlap would repeat the previous one
+        if overlap.len() == pieces.len() {
+            overlap.remove(0);
+        }
+        overlap
+    }
+
+    /// split a text into chunks, in order
+    pub fn split(&self, text: &str) -> Vec<Chunk> {
+        let pieces = self.pieces(text);
+        let mut offset = 0;
+        let headings: Vec<(usize, &str)> = text
+            .split_inclusive('\n')
+            .filter_map(|line| {
+                let start = offset;
+                offset += line.len();
+                Some((start, heading_text(line)?))
+            })
+            .collect();
+        let mut chunks = Vec::new();
+        let mut current: Vec<&Piece> = Vec::new();
+        let tokens = |pieces: &[&Piece]| pieces.iter().map(|piece| piece.tokens).sum::<u64>();
+        for piece in &pieces {
+            while !current.is_empty()
+                && (piece.level == HEADING || tokens(&current) + piece.tokens > self.max_tokens)
+            {
+                let cut = if piece.level == HEADING {
+                    current.len()
+                } else {
+                    self.best_cut(&current, piece.level)
+                };
+                chunks.extend(TextSplitter::chunk(text, &current[..cut], &headings));
+                let rest = current.split_off(cut);
+                let mut next = self.overlap(&current);
+                if piece.level == HEADING
+                    || tokens(&next) + tokens(&rest) + piece.tokens > self.max_tokens
+                {
+                    next.clear();
+                }
+                next.extend(rest);
+                current = next;
+            }
+            current.push(piece);
+        }
+        chunks.extend(TextSplitter::chunk(text, &current, &headings));
+        chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TextSplitter;
+    use crate::embedding::EmbeddingModel;
+
+    #[test]
+    fn test_symbols() {
+        assert_eq!(TextSplitter::count_tokens("你好，世界！🙂"), 7);
+        let text = "🙂，".repeat(300) + &"字".repeat(300);
+        let chunks = TextSplitter::for_model(&EmbeddingModel::EmbeddingV1).split(&text);
+        assert!(chunks.len() > 2);
+        assert!(chunks.iter().all(|chunk| chunk.tokens <= 384));
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| chunk.text.as_str())
+                .collect::<String>(),
+            text
+        );
+    }
+
+    #[test]
+    fn test_sentences() {
+        let text = "第一句话很长很长。第二句话也很长！第三句“引用结束。”第四句？";
+        let chunks = TextSplitter::new(14).split(text);
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "第一句话很长很长。",
+                "第二句话也很长！",
+                "第三句“引用结束。”第四句？"
+            ]
+        );
+        for chunk in &chunks {
+            assert_eq!(&text[chunk.start..chunk.end], chunk.text);
+            assert!(chunk.tokens <= 14);
+        }
+    }
+
+    #[test]
+    fn test_structure() {
+        let text = "# Intro\nRust is fast. It is safe.\n\nSecond paragraph here.\n## 用法\n调用 invoke 即可。";
+        let chunks = TextSplitter::new(100).split(text);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].heading.as_deref(), Some("Intro"));
+        assert!(chunks[0].text.ends_with("Second paragraph here."));
+        assert_eq!(chunks[1].text, "## 用法\n调用 invoke 即可。");
+        assert_eq!(chunks[1].heading.as_deref(), Some("用法"));
+
+        // the paragraph break is preferred to the sentence ones
+        let chunks = TextSplitter::new(10).split("One two three. Four five.\n\nSix seven eight.");
+        assert_eq!(chunks[0].text, "One two three. Four five.");
+        assert_eq!(chunks[1].text, "Six seven eight.");
+    }
+
+    #[test]
+    fn test_overlap_and_long_sentences() {
+        let text = "甲乙丙丁。戊己庚辛。壬癸子丑。寅卯辰巳。";
+        let chunks = TextSplitter::new(10).with_overlap(5).split(text);
+        let texts: Vec<&str> = chunks.iter().map(|chunk| chunk.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "甲乙丙丁。戊己庚辛。",
+                "戊己庚辛。壬癸子丑。",
+                "壬癸子丑。寅卯辰巳。"
+            ]
+        );
+
+        let long = "这是一个没有标点的非常长的句子".repeat(5);
+        let chunks = TextSplitter::new(16).split(&long);
+        assert!(chunks.iter().all(|chunk| chunk.tokens <= 16));
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| chunk.text.as_str())
+                .collect::<String>(),
+            long
+        );
+    }
+}
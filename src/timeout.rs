@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+/** Timeouts applied to the requests of an endpoint. `None` means no timeout, which is the default.
+
+Each timeout surfaces as its own `ErnieError` variant: `ConnectTimeoutError`, `ReadTimeoutError`, `FirstTokenTimeoutError` and `IdleTimeoutError`.
+```
+use erniebot_rs::timeout::Timeouts;
+use std::time::Duration;
+let timeouts = Timeouts {
+    connect: Some(Duration::from_secs(5)),
+    read: Some(Duration::from_secs(60)),
+    ..Default::default()
+};
+// a tighter first token timeout for one call
+let overrides = Timeouts {
+    first_token: Some(Duration::from_secs(3)),
+    ..Default::default()
+};
+let merged = timeouts.merge(&overrides);
+assert_eq!(merged.connect, Some(Duration::from_secs(5)));
+assert_eq!(merged.first_token, Some(Duration::from_secs(3)));
+```
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timeouts {
+    /// time allowed to establish the connection
+    pub connect: Option<Duration>,
+    /// time allowed for each read of a non-stream response
+    pub read: Option<Duration>,
+    /// time allowed between sending a stream request and receiving its first chunk
+    pub first_token: Option<Duration>,
+    /// time allowed between two chunks of a stream
+    pub idle: Option<Duration>,
+}
+
+impl Timeouts {
+    /// return these timeouts with the ones set in `overrides` taking precedence
+    pub fn merge(&self, overrides: &Timeouts) -> Timeouts {
+        Timeouts {
+            connect: overrides.connect.or(self.connect),
+            read: overrides.read.or(self.read),
+            first_token: overrides.first_token.or(self.first_token),
+            idle: overrides.idle.or(self.idle),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+/*! Spans recorded with the `tracing` crate when the `tracing` feature is enabled.
+
+Without the feature, `Span` is an empty type and every function here does nothing, so that the call sites do not need any `cfg`.
+*/
+use crate::errors::ErnieError;
+use serde_json::Value;
+use std::future::Future;
+use std::time::Instant;
+use url::Url;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        f()
+    }
+
+    pub(crate) fn entered(self) -> Self {
+        self
+    }
+}
+
+/// the span of one call to an endpoint. `call` is one of "invoke", "stream", "ainvoke" and "astream". The url never holds the access token, which is added as a query parameter when sending.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn request_span(call: &'static str, endpoint: &str, model: &str, url: &Url) -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!(
+        "erniebot.request",
+        call,
+        endpoint,
+        model,
+        url = %url,
+        latency_ms = tracing::field::Empty,
+        time_to_first_token_ms = tracing::field::Empty,
+        id = tracing::field::Empty,
+        prompt_tokens = tracing::field::Empty,
+        completion_tokens = tracing::field::Empty,
+        total_tokens = tracing::field::Empty,
+        error_code = tracing::field::Empty,
+        error = tracing::field::Empty,
+    );
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
+
+/// the span of fetching an access token
+pub(crate) fn access_token_span() -> Span {
+    #[cfg(feature = "tracing")]
+    return tracing::info_span!(
+        "erniebot.access_token",
+        latency_ms = tracing::field::Empty,
+        error = tracing::field::Empty,
+    );
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
+
+/// the span of one chunk of a stream, a child of the current span. `data` is the json of the chunk.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn chunk_span(data: &str, start: Instant) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        let chunk: Value = serde_json::from_str(data).unwrap_or_default();
+        let is_end = chunk.get("is_end").and_then(|v| v.as_bool());
+        if is_end == Some(true) {
+            record_response(&Span::current(), &chunk);
+        }
+        tracing::debug_span!(
+            "erniebot.chunk",
+            id = chunk.get("id").and_then(|v| v.as_str()),
+            sentence_id = chunk.get("sentence_id").and_then(|v| v.as_u64()),
+            is_end,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+        )
+    }
+    #[cfg(not(feature = "tracing"))]
+    Span
+}
+
+/// run `future` inside `span`
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn instrument<F: Future>(future: F, span: Span) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    return tracing::Instrument::instrument(future, span);
+    #[cfg(not(feature = "tracing"))]
+    future
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn record_first_token(span: &Span, start: Instant) {
+    #[cfg(feature = "tracing")]
+    span.record("time_to_first_token_ms", start.elapsed().as_millis() as u64);
+}
+
+/// record the request id and the token usage of a response
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn record_response(span: &Span, response: &Value) {
+    #[cfg(feature = "tracing")]
+    {
+        if let Some(id) = response.get("id").and_then(|v| v.as_str()) {
+            span.record("id", id);
+        }
+        if let Some(usage) = response.get("usage") {
+            for field in ["prompt_tokens", "completion_tokens", "total_tokens"] {
+                if let Some(tokens) = usage.get(field).and_then(|v| v.as_u64()) {
+                    span.record(field, tokens);
+                }
+            }
+        }
+    }
+}
+
+/// record the error of a call, with the Qianfan error code if any
+#[cfg(feature = "tracing")]
+fn record_error(span: &Span, error: &ErnieError) {
+    if let Some(code) = error.remote_error_code() {
+        span.record("error_code", code);
+    }
+    span.record("error", tracing::field::display(error));
+}
+
+/// record the latency of a finished call, and its error if it failed
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn record_end(span: &Span, start: Instant, error: Option<&ErnieError>) {
+    #[cfg(feature = "tracing")]
+    {
+        span.record("latency_ms", start.elapsed().as_millis() as u64);
+        if let Some(error) = error {
+            record_error(span, error);
+        }
+    }
+}
+
+/// log an error that does not fail the call, like an entry that a cache could not write
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn record_cache_error(error: &ErnieError) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(error = %error, "erniebot.cache_error");
+}
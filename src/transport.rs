@@ -0,0 +1,1076 @@
+use crate::cache::{replay_chunks, ResponseCache};
+use crate::circuit_breaker::{CircuitBreaker, Permit};
+use crate::credentials::{CredentialPool, Lease};
+use crate::errors::ErnieError;
+use crate::metrics::{outcome, Metrics, RequestMetrics};
+use crate::middleware::{Middlewares, RequestContext};
+use crate::rate_limiter::{estimate_body_tokens, RateLimitHeaders, RateLimiter};
+use crate::single_flight::SingleFlight;
+use crate::timeout::Timeouts;
+use crate::trace::{self, Span};
+use crate::usage::{Usage, UsageTracker};
+use crate::utils::{build_url, get_access_token};
+use reqwest_eventsource::{Error as EventSourceError, EventSource, RequestBuilderExt};
+use serde_json::Value;
+use std::io::{BufRead, BufReader};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// The kind of endpoint a transport serves, used to interpret responses in a generic way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EndpointKind {
+    Chat,
+    Embedding,
+    Reranker,
+    Text2Image,
+}
+
+impl EndpointKind {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            EndpointKind::Chat => "chat",
+            EndpointKind::Embedding => "embedding",
+            EndpointKind::Reranker => "reranker",
+            EndpointKind::Text2Image => "text2image",
+        }
+    }
+}
+
+/// How a transport authenticates its requests.
+#[derive(Debug, Clone)]
+enum Credentials {
+    AccessToken(String),
+    Pool(CredentialPool),
+}
+
+/// Transport is the part shared by all endpoints: it knows where to send a request body, how to authenticate it, and what to do around each call.
+#[derive(Debug, Clone)]
+pub(crate) struct Transport {
+    kind: EndpointKind,
+    url: Url,
+    model: String,
+    credentials: Credentials,
+    pub(crate) usage_tracker: Option<UsageTracker>,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) circuit_breaker: Option<CircuitBreaker>,
+    pub(crate) metrics: Option<Metrics>,
+    pub(crate) middlewares: Middlewares,
+    pub(crate) cache: Option<ResponseCache>,
+    pub(crate) single_flight: Option<SingleFlight>,
+}
+
+/// The measurements of one call to an endpoint, reported to its tracing span and to the metrics hook when it ends.
+pub(crate) struct Call {
+    name: &'static str,
+    pub(crate) span: Span,
+    pub(crate) start: Instant,
+    first_token: Option<Duration>,
+    retries: u32,
+    /// whether the call was served by the response cache
+    cached: bool,
+    /// whether the call waited for the response of an identical call, see `SingleFlight`
+    coalesced: bool,
+}
+
+impl Call {
+    /// mark the arrival of the first chunk of a stream
+    pub(crate) fn first_token(&mut self) {
+        if self.first_token.is_none() {
+            self.first_token = Some(self.start.elapsed());
+            trace::record_first_token(&self.span, self.start);
+        }
+    }
+}
+
+/// whether an error was caused by an io timeout, looking through its sources
+fn is_io_timeout(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+            if matches!(
+                io_error.kind(),
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+            ) {
+                return true;
+            }
+        }
+        source = error.source();
+    }
+    false
+}
+
+fn ureq_error(error: ureq::Error) -> ErnieError {
+    match &error {
+        ureq::Error::Transport(transport) if is_io_timeout(transport) => {
+            if transport.kind() == ureq::ErrorKind::ConnectionFailed {
+                ErnieError::ConnectTimeoutError(error.to_string())
+            } else {
+                ErnieError::ReadTimeoutError(error.to_string())
+            }
+        }
+        _ => ErnieError::InvokeError(error.to_string()),
+    }
+}
+
+fn io_error(error: std::io::Error) -> ErnieError {
+    if is_io_timeout(&error) {
+        ErnieError::ReadTimeoutError(error.to_string())
+    } else {
+        ErnieError::InvokeError(error.to_string())
+    }
+}
+
+fn reqwest_error(error: reqwest::Error) -> ErnieError {
+    if error.is_timeout() && error.is_connect() {
+        ErnieError::ConnectTimeoutError(error.to_string())
+    } else if error.is_timeout() {
+        ErnieError::ReadTimeoutError(error.to_string())
+    } else {
+        ErnieError::InvokeError(error.to_string())
+    }
+}
+
+/// the `RemoteAPIError` of a response holding an `error_code`, if any
+fn remote_error(response: &Value) -> Option<ErnieError> {
+    response
+        .get("error_code")
+        .map(|_| ErnieError::RemoteAPIError(response.to_string()))
+}
+
+impl Transport {
+    /// create a transport authenticated with the application set in the QIANFAN_AK and QIANFAN_SK environment variables
+    pub(crate) fn new(kind: EndpointKind, base_url: &str, model: &str) -> Result<Self, ErnieError> {
+        Transport::with_credentials(
+            kind,
+            base_url,
+            model,
+            Credentials::AccessToken(get_access_token()?),
+        )
+    }
+
+    /// create a transport picking the credential of each call from a pool
+    pub(crate) fn new_with_credential_pool(
+        kind: EndpointKind,
+        base_url: &str,
+        model: &str,
+        pool: CredentialPool,
+    ) -> Result<Self, ErnieError> {
+        Transport::with_credentials(kind, base_url, model, Credentials::Pool(pool))
+    }
+
+    fn with_credentials(
+        kind: EndpointKind,
+        base_url: &str,
+        model: &str,
+        credentials: Credentials,
+    ) -> Result<Self, ErnieError> {
+        Ok(Transport {
+            kind,
+            url: build_url(base_url, model)?,
+            model: model.to_string(),
+            credentials,
+            usage_tracker: None,
+            rate_limiter: None,
+            timeouts: Timeouts::default(),
+            circuit_breaker: None,
+            metrics: None,
+            middlewares: Middlewares::default(),
+            cache: None,
+            single_flight: None,
+        })
+    }
+
+    pub(crate) fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// start measuring a call. `name` is one of "invoke", "stream", "ainvoke" and "astream".
+    pub(crate) fn start_call(&self, name: &'static str) -> Call {
+        Call {
+            name,
+            span: trace::request_span(name, self.kind.name(), &self.model, &self.url),
+            start: Instant::now(),
+            first_token: None,
+            retries: 0,
+            cached: false,
+            coalesced: false,
+        }
+    }
+
+    /// report a finished call, with its response (or the last chunk of its stream) if it succeeded
+    pub(crate) fn end_call(
+        &self,
+        call: &Call,
+        response: Option<&Value>,
+        error: Option<&ErnieError>,
+    ) {
+        if let Some(response) = response {
+            trace::record_response(&call.span, response);
+        }
+        trace::record_end(&call.span, call.start, error);
+        let Some(Metrics(hook)) = &self.metrics else {
+            return;
+        };
+        let usage = response
+            .filter(|_| !call.cached && !call.coalesced)
+            .and_then(|response| response.get("usage"));
+        let tokens = |field: &str| {
+            usage
+                .and_then(|usage| usage.get(field))
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0)
+        };
+        hook.record_request(&RequestMetrics {
+            endpoint: self.kind.name(),
+            model: self.model.clone(),
+            call: call.name,
+            outcome: match (call.cached, call.coalesced, error) {
+                (true, _, _) => "cache_hit",
+                (_, true, None) => "coalesced",
+                _ => outcome(error),
+            },
+            error_code: error.and_then(|e| e.remote_error_code()),
+            latency: call.start.elapsed(),
+            time_to_first_token: call.first_token,
+            prompt_tokens: tokens("prompt_tokens"),
+            completion_tokens: tokens("completion_tokens"),
+            retries: call.retries,
+        });
+    }
+
+    /** build a blocking agent. Stream requests do not get the read timeout, they are watched chunk by chunk instead.
+
+    The reader of a stream still gets the longest of the first token and idle timeouts as its read timeout, so that it does not keep the connection open once the stream timed out.
+    */
+    fn agent(&self, stream: bool) -> ureq::Agent {
+        let mut builder = ureq::AgentBuilder::new();
+        if let Some(connect) = self.timeouts.connect {
+            builder = builder.timeout_connect(connect);
+        }
+        let read = match stream {
+            true => self.timeouts.first_token.max(self.timeouts.idle),
+            false => self.timeouts.read,
+        };
+        if let Some(read) = read {
+            builder = builder.timeout_read(read);
+        }
+        builder.build()
+    }
+
+    /// build an async client. Stream requests do not get the read timeout, they are watched chunk by chunk instead.
+    fn client(&self, stream: bool) -> Result<reqwest::Client, ErnieError> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(connect) = self.timeouts.connect {
+            builder = builder.connect_timeout(connect);
+        }
+        if let (Some(read), false) = (self.timeouts.read, stream) {
+            builder = builder.read_timeout(read);
+        }
+        builder
+            .build()
+            .map_err(|e| ErnieError::InvokeError(e.to_string()))
+    }
+
+    fn context(&self, call: &Call) -> RequestContext<'_> {
+        RequestContext {
+            endpoint: self.kind.name(),
+            model: &self.model,
+            call: call.name,
+        }
+    }
+
+    /// run the middlewares on the body of a request. They run before the cache is looked up, so that the cache key is computed from the body actually sent.
+    pub(crate) fn before_request(&self, call: &Call, body: &mut Value) -> Result<(), ErnieError> {
+        self.middlewares.before_request(&self.context(call), body)
+    }
+
+    /// check the budget of the user of a request, once it missed the cache and is about to be sent
+    fn check_budget(&self, body: &Value) -> Result<(), ErnieError> {
+        match &self.usage_tracker {
+            Some(tracker) => tracker.check_budget(body.get("user_id").and_then(|v| v.as_str())),
+            None => Ok(()),
+        }
+    }
+
+    /// the cache key of a request, when a cache or a single flight is attached
+    fn cache_key(&self, body: &Value) -> Option<String> {
+        (self.cache.is_some() || self.single_flight.is_some())
+            .then(|| ResponseCache::key(self.url.as_str(), body))
+    }
+
+    fn cache_get(&self, key: Option<&str>) -> Option<Value> {
+        self.cache.as_ref()?.get(key?)
+    }
+
+    fn cache_put(&self, key: Option<&str>, response: &Value) {
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            cache.put(key, response);
+        }
+    }
+
+    /// the chunks replaying the cached answer of a stream request, with the middlewares run on them, or `None` on a cache miss. `body` must have been through `before_request`.
+    pub(crate) fn cached_chunks(
+        &self,
+        call: &mut Call,
+        body: &Value,
+    ) -> Result<Option<Vec<Value>>, ErnieError> {
+        let Some(response) = self.cache_get(self.cache_key(body).as_deref()) else {
+            return Ok(None);
+        };
+        call.cached = true;
+        let mut chunks = replay_chunks(&response);
+        for chunk in chunks.iter_mut() {
+            self.on_stream_chunk(call, chunk)?;
+        }
+        Ok(Some(chunks))
+    }
+
+    /// run the middlewares on the response of a non-stream call
+    fn after_response(&self, call: &Call, response: &mut Value) -> Result<(), ErnieError> {
+        self.middlewares
+            .after_response(&self.context(call), response)
+    }
+
+    /// run the middlewares on one chunk of a stream
+    pub(crate) fn on_stream_chunk(&self, call: &Call, chunk: &mut Value) -> Result<(), ErnieError> {
+        self.middlewares.on_stream_chunk(&self.context(call), chunk)
+    }
+
+    /// run the middlewares on one line of a blocking stream, which holds a chunk when it starts with "data:"
+    fn on_stream_line(&self, call: &Call, line: String) -> Result<String, ErnieError> {
+        if self.middlewares.is_empty() {
+            return Ok(line);
+        }
+        let Some(mut chunk) = line
+            .strip_prefix("data:")
+            .and_then(|data| serde_json::from_str::<Value>(data.trim()).ok())
+        else {
+            return Ok(line);
+        };
+        self.on_stream_chunk(call, &mut chunk)?;
+        Ok(format!("data: {}", chunk))
+    }
+
+    /// let a call through the circuit breaker, or fail fast if it is open
+    fn admit(&self) -> Result<Permit, ErnieError> {
+        match &self.circuit_breaker {
+            Some(breaker) => breaker.acquire(),
+            None => Ok(Permit::default()),
+        }
+    }
+
+    /// get the access token for one call, in a blocking way
+    fn lease(&self) -> Result<Lease, ErnieError> {
+        match &self.credentials {
+            Credentials::AccessToken(access_token) => Ok(Lease::from_access_token(access_token)),
+            Credentials::Pool(pool) => pool.lease(),
+        }
+    }
+
+    /// get the access token for one call, in an async way
+    async fn alease(&self) -> Result<Lease, ErnieError> {
+        match &self.credentials {
+            Credentials::AccessToken(access_token) => Ok(Lease::from_access_token(access_token)),
+            Credentials::Pool(pool) => pool.alease().await,
+        }
+    }
+
+    /// the rate limiters applying to a call: the one of the endpoint and the one of the credential
+    fn limiters<'a>(&'a self, lease: &'a Lease) -> impl Iterator<Item = &'a RateLimiter> {
+        self.rate_limiter.iter().chain(lease.limiter.iter())
+    }
+
+    /// wait for the rate limiters in a blocking way
+    fn acquire_blocking(&self, body: &Value, lease: &Lease) {
+        for limiter in self.limiters(lease) {
+            limiter.acquire_blocking(&self.model, estimate_body_tokens(body));
+        }
+    }
+
+    /// wait for the rate limiters in an async way
+    async fn acquire(&self, body: &Value, lease: &Lease) {
+        for limiter in self.limiters(lease) {
+            limiter
+                .acquire(&self.model, estimate_body_tokens(body))
+                .await;
+        }
+    }
+
+    fn observe_headers(&self, headers: &RateLimitHeaders, lease: &Lease) {
+        for limiter in self.limiters(lease) {
+            limiter.observe_headers(&self.model, headers);
+        }
+    }
+
+    /** whether a call that failed with `error` should be tried again.
+
+    Rate limit errors are retried after waiting for the rate limiters again (at most `RateLimiter::max_retries` times), and with a credential pool, once with each of the other credentials.
+    */
+    fn should_retry(&self, error: &ErnieError, attempts: u32, lease: &Lease) -> bool {
+        if !error.is_rate_limited() {
+            return false;
+        }
+        let mut max_retries = self
+            .rate_limiter
+            .as_ref()
+            .map(|limiter| limiter.max_retries())
+            .unwrap_or(0);
+        if let Credentials::Pool(pool) = &self.credentials {
+            max_retries += pool.len().saturating_sub(1) as u32;
+        }
+        if attempts >= max_retries {
+            return false;
+        }
+        for limiter in self.limiters(lease) {
+            limiter.penalize(&self.model);
+        }
+        true
+    }
+
+    /// record the usage of a response (or of the last chunk of a stream) with the attached tracker, and correct the token estimate of the rate limiter
+    pub(crate) fn record_usage(&self, body: &Value, response: &Value) {
+        if let Some(limiter) = &self.rate_limiter {
+            if let Some(total_tokens) = response
+                .get("usage")
+                .and_then(|usage| usage.get("total_tokens"))
+                .and_then(|v| v.as_u64())
+            {
+                limiter.adjust_tokens(&self.model, estimate_body_tokens(body), total_tokens);
+            }
+        }
+        let Some(tracker) = &self.usage_tracker else {
+            return;
+        };
+        let images = match self.kind {
+            EndpointKind::Text2Image => response
+                .get("data")
+                .and_then(|data| data.as_array())
+                .map(|data| data.len() as u64)
+                .unwrap_or(0),
+            _ => 0,
+        };
+        tracker.record(
+            &self.model,
+            body.get("user_id").and_then(|v| v.as_str()),
+            &Usage::from_response(response, images),
+        );
+    }
+
+    fn check_response(
+        &self,
+        body: &Value,
+        response: Value,
+        lease: &Lease,
+    ) -> Result<Value, ErnieError> {
+        if let Some(error) = remote_error(&response) {
+            lease.report_error(&error);
+            return Err(error);
+        }
+        self.record_usage(body, &response);
+        Ok(response)
+    }
+
+    /// send a blocking request and return the json response
+    pub(crate) fn post(&self, mut body: Value) -> Result<Value, ErnieError> {
+        let mut call = self.start_call("invoke");
+        let span = call.span.clone();
+        let result = span.in_scope(|| {
+            self.before_request(&call, &mut body)?;
+            let key = self.cache_key(&body);
+            let mut response = match self.cache_get(key.as_deref()) {
+                Some(response) => {
+                    call.cached = true;
+                    response
+                }
+                None => {
+                    self.check_budget(&body)?;
+                    let permit = self.admit()?;
+                    let result = self.send(body, &mut call);
+                    permit.record(result.as_ref().err());
+                    let response = result?;
+                    self.cache_put(key.as_deref(), &response);
+                    response
+                }
+            };
+            self.after_response(&call, &mut response)?;
+            Ok(response)
+        });
+        self.end_call(&call, result.as_ref().ok(), result.as_ref().err());
+        result
+    }
+
+    fn send(&self, body: Value, call: &mut Call) -> Result<Value, ErnieError> {
+        loop {
+            let lease = self.lease()?;
+            self.acquire_blocking(&body, &lease);
+            let response = self
+                .agent(false)
+                .post(self.url.as_str())
+                .set("Content-Type", "application/json")
+                .query("access_token", lease.access_token.as_str())
+                .send_json(&body)
+                .map_err(ureq_error)?;
+            self.observe_headers(
+                &RateLimitHeaders::from_lookup(|name| response.header(name)),
+                &lease,
+            );
+            let response: Value = response.into_json().map_err(io_error)?;
+            match self.check_response(&body, response, &lease) {
+                Err(e) if self.should_retry(&e, call.retries, &lease) => call.retries += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /** send a blocking stream request and return the whole event stream as text.
+
+    The response is read line by line in a separate thread, so that the first token and idle timeouts can be enforced on the chunks.
+    */
+    pub(crate) fn post_for_text(&self, mut body: Value) -> Result<String, ErnieError> {
+        let mut call = self.start_call("stream");
+        let span = call.span.clone();
+        let result = span.in_scope(|| {
+            self.before_request(&call, &mut body)?;
+            if let Some(chunks) = self.cached_chunks(&mut call, &body)? {
+                return Ok(chunks
+                    .iter()
+                    .map(|chunk| format!("data: {}\n\n", chunk))
+                    .collect());
+            }
+            self.check_budget(&body)?;
+            let permit = self.admit()?;
+            let result = self.send_for_text(&body, &mut call);
+            permit.record(result.as_ref().err());
+            result
+        });
+        let last_chunk = result
+            .as_ref()
+            .ok()
+            .and_then(|text| {
+                text.lines()
+                    .rev()
+                    .find_map(|line| line.strip_prefix("data:"))
+            })
+            .and_then(|data| serde_json::from_str::<Value>(data.trim()).ok());
+        if let (Some(last_chunk), false) = (&last_chunk, call.cached) {
+            self.record_usage(&body, last_chunk);
+        }
+        self.end_call(&call, last_chunk.as_ref(), result.as_ref().err());
+        result
+    }
+
+    fn send_for_text(&self, body: &Value, call: &mut Call) -> Result<String, ErnieError> {
+        loop {
+            let lease = self.lease()?;
+            self.acquire_blocking(body, &lease);
+            match self.read_stream(body, &lease, call) {
+                Err(e) if self.should_retry(&e, call.retries, &lease) => call.retries += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /// send a blocking stream request with the token of `lease` and read the whole event stream
+    fn read_stream(
+        &self,
+        body: &Value,
+        lease: &Lease,
+        call: &mut Call,
+    ) -> Result<String, ErnieError> {
+        let body = body.clone();
+        let request = self
+            .agent(true)
+            .post(self.url.as_str())
+            .set("Content-Type", "application/json")
+            .query("access_token", lease.access_token.as_str());
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let response = match request.send_json(&body) {
+                Ok(response) => response,
+                Err(e) => {
+                    let _ = sender.send(Err(ureq_error(e)));
+                    return;
+                }
+            };
+            for line in BufReader::new(response.into_reader()).lines() {
+                let failed = line.is_err();
+                if sender.send(line.map_err(io_error)).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        let mut text = String::new();
+        let mut first_token = true;
+        loop {
+            let timeout = if first_token {
+                self.timeouts.first_token
+            } else {
+                self.timeouts.idle
+            };
+            let line = match timeout {
+                Some(timeout) => receiver.recv_timeout(timeout),
+                None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let line = match line {
+                // the read timeout of the reader is the stream timeout running out at the same time
+                Ok(Err(ErnieError::ReadTimeoutError(_))) if timeout.is_some() => {
+                    Err(RecvTimeoutError::Timeout)
+                }
+                line => line,
+            };
+            match line {
+                Ok(line) => {
+                    let line = line?;
+                    // a failed request is answered with a json error instead of the stream
+                    if let Some(error) = serde_json::from_str::<Value>(&line)
+                        .ok()
+                        .as_ref()
+                        .and_then(remote_error)
+                    {
+                        lease.report_error(&error);
+                        return Err(error);
+                    }
+                    let line = self.on_stream_line(call, line)?;
+                    if first_token && !line.is_empty() {
+                        first_token = false;
+                        call.first_token();
+                    }
+                    match line.strip_prefix("data:") {
+                        Some(data) => trace::chunk_span(data.trim(), call.start).in_scope(|| {
+                            text.push_str(&line);
+                            text.push('\n');
+                        }),
+                        None => {
+                            text.push_str(&line);
+                            text.push('\n');
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) if first_token => {
+                    return Err(ErnieError::FirstTokenTimeoutError(format!(
+                        "no chunk received within {:?}",
+                        timeout.unwrap_or_default()
+                    )))
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(ErnieError::IdleTimeoutError(format!(
+                        "no chunk received within {:?} after the previous one",
+                        timeout.unwrap_or_default()
+                    )))
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        Ok(text)
+    }
+
+    /// send an async request and return the json response
+    pub(crate) async fn apost(&self, mut body: Value) -> Result<Value, ErnieError> {
+        let mut call = self.start_call("ainvoke");
+        let span = call.span.clone();
+        let result = trace::instrument(
+            async {
+                self.before_request(&call, &mut body)?;
+                let key = self.cache_key(&body);
+                let mut response = match self.cache_get(key.as_deref()) {
+                    Some(response) => {
+                        call.cached = true;
+                        response
+                    }
+                    None => {
+                        let request = async {
+                            self.check_budget(&body)?;
+                            let permit = self.admit()?;
+                            let result = self.asend(body, &mut call).await;
+                            permit.record(result.as_ref().err());
+                            let response = result?;
+                            self.cache_put(key.as_deref(), &response);
+                            Ok(response)
+                        };
+                        match (&self.single_flight, key.as_deref()) {
+                            (Some(single_flight), Some(key)) => {
+                                let (result, sent) = single_flight.run(key, request).await;
+                                call.coalesced = !sent;
+                                result?
+                            }
+                            _ => request.await?,
+                        }
+                    }
+                };
+                self.after_response(&call, &mut response)?;
+                Ok(response)
+            },
+            span,
+        )
+        .await;
+        self.end_call(&call, result.as_ref().ok(), result.as_ref().err());
+        result
+    }
+
+    async fn asend(&self, body: Value, call: &mut Call) -> Result<Value, ErnieError> {
+        let client = self.client(false)?;
+        loop {
+            let lease = self.alease().await?;
+            self.acquire(&body, &lease).await;
+            let response = client
+                .post(self.url.as_str())
+                .header("Content-Type", "application/json")
+                .query(&[("access_token", lease.access_token.as_str())])
+                .json(&body)
+                .send()
+                .await
+                .map_err(reqwest_error)?;
+            self.observe_headers(
+                &RateLimitHeaders::from_lookup(|name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                }),
+                &lease,
+            );
+            let response: Value = response.json().await.map_err(reqwest_error)?;
+            match self.check_response(&body, response, &lease) {
+                Err(e) if self.should_retry(&e, call.retries, &lease) => call.retries += 1,
+                result => return result,
+            }
+        }
+    }
+
+    /** open an async event source for a stream request, whose body must have been through `before_request`.
+
+    Once the event source is open, the stream must be closed with `OpenStream::close`, which reports its outcome.
+    */
+    pub(crate) async fn event_source(&self, body: &Value) -> Result<OpenStream, ErnieError> {
+        self.check_budget(body)?;
+        let permit = self.admit()?;
+        match self.open_event_source(body).await {
+            Ok((event_source, lease)) => Ok(OpenStream {
+                event_source,
+                lease,
+                permit,
+            }),
+            Err(e) => {
+                permit.record(Some(&e));
+                Err(e)
+            }
+        }
+    }
+
+    async fn open_event_source(&self, body: &Value) -> Result<(EventSource, Lease), ErnieError> {
+        let lease = self.alease().await?;
+        self.acquire(body, &lease).await;
+        let client = self.client(true)?;
+        let event_source = client
+            .post(self.url.as_str())
+            .header("Content-Type", "application/json")
+            .query(&[("access_token", lease.access_token.as_str())])
+            .json(body)
+            .eventsource()
+            .map_err(|e| ErnieError::StreamError(e.to_string()))?;
+        Ok((event_source, lease))
+    }
+}
+
+/// An open async event source, holding the lease of its credential and the permit of the circuit breaker for as long as the stream lasts.
+pub(crate) struct OpenStream {
+    pub(crate) event_source: EventSource,
+    lease: Lease,
+    permit: Permit,
+}
+
+impl OpenStream {
+    /// close the event source, and report the error that ended the stream, if any, to the credential pool and the circuit breaker
+    pub(crate) fn close(mut self, error: Option<&ErnieError>) {
+        self.event_source.close();
+        if let Some(error) = error {
+            self.lease.report_error(error);
+        }
+        self.permit.record(error);
+    }
+}
+
+/// the error of an event source, read from the json error answered instead of the stream when there is one
+pub(crate) async fn event_source_error(error: EventSourceError) -> ErnieError {
+    let message = error.to_string();
+    match error {
+        EventSourceError::InvalidContentType(_, response)
+        | EventSourceError::InvalidStatusCode(_, response) => response
+            .json::<Value>()
+            .await
+            .ok()
+            .as_ref()
+            .and_then(remote_error)
+            .unwrap_or(ErnieError::StreamError(message)),
+        _ => ErnieError::StreamError(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Credentials, EndpointKind, Transport};
+    use crate::cache::ResponseCache;
+    use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+    use crate::errors::ErnieError;
+    use crate::metrics::{Metrics, MetricsHook, RequestMetrics};
+    use crate::middleware::{Middleware, Middlewares, RequestContext};
+    use crate::rate_limiter::{RateLimit, RateLimiter};
+    use crate::single_flight::SingleFlight;
+    use crate::timeout::Timeouts;
+    use serde_json::Value;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// start a server answering every connection with `response` and then keeping it open for a while
+    fn serve(response: &'static str) -> Transport {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/chat/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    let mut buffer = [0u8; 4096];
+                    let _ = stream.read(&mut buffer);
+                    let _ = stream.write_all(response.as_bytes());
+                    std::thread::sleep(Duration::from_secs(2));
+                });
+            }
+        });
+        Transport {
+            kind: EndpointKind::Chat,
+            url: url::Url::parse(&url).unwrap(),
+            model: "test".to_string(),
+            credentials: Credentials::AccessToken("token".to_string()),
+            usage_tracker: None,
+            rate_limiter: None,
+            timeouts: Timeouts::default(),
+            circuit_breaker: None,
+            metrics: None,
+            middlewares: Middlewares::default(),
+            cache: None,
+            single_flight: None,
+        }
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let mut transport = serve("");
+        transport.timeouts.read = Some(Duration::from_millis(200));
+        let result = transport.post(serde_json::json!({}));
+        assert!(matches!(result, Err(ErnieError::ReadTimeoutError(_))));
+    }
+
+    #[test]
+    fn test_stream_timeouts() {
+        let mut transport = serve("");
+        transport.timeouts.first_token = Some(Duration::from_millis(200));
+        let result = transport.post_for_text(serde_json::json!({}));
+        assert!(matches!(result, Err(ErnieError::FirstTokenTimeoutError(_))));
+
+        let mut transport = serve(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\ndata: {\"result\": \"a\"}\n\n",
+        );
+        transport.timeouts.idle = Some(Duration::from_millis(200));
+        let result = transport.post_for_text(serde_json::json!({}));
+        assert!(matches!(result, Err(ErnieError::IdleTimeoutError(_))));
+    }
+
+    #[test]
+    fn test_stream_error() {
+        let recorder = Arc::new(Recorder::default());
+        let mut transport = serve(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 62\r\n\r\n{\"error_code\":336501,\"error_msg\":\"Rate limit reached for RPM\"}",
+        );
+        transport.metrics = Some(Metrics(recorder.clone()));
+        transport.rate_limiter = Some(RateLimiter::new(RateLimit::default()).with_max_retries(1));
+        let start = Instant::now();
+        let result = transport.post_for_text(serde_json::json!({}));
+        assert!(result.is_err_and(|e| e.is_rate_limited()));
+        assert_eq!(recorder.0.lock().unwrap()[0].retries, 1);
+        // without a limit to wait for, the retry backs off
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_stream_ignores_read_timeout() {
+        let mut transport = serve(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\ndata: {\"result\": \"a\"}\n\n",
+        );
+        transport.timeouts.read = Some(Duration::from_millis(100));
+        transport.timeouts.idle = Some(Duration::from_millis(500));
+        let result = transport.post_for_text(serde_json::json!({}));
+        assert!(matches!(result, Err(ErnieError::IdleTimeoutError(_))));
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<RequestMetrics>>);
+
+    impl MetricsHook for Recorder {
+        fn record_request(&self, metrics: &RequestMetrics) {
+            self.0.lock().unwrap().push(metrics.clone());
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let recorder = Arc::new(Recorder::default());
+        let mut transport = serve(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 80\r\n\r\n{\"id\":\"as-1\",\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":5,\"total_tokens\":8}}",
+        );
+        transport.metrics = Some(Metrics(recorder.clone()));
+        transport.post(serde_json::json!({})).unwrap();
+        let mut failing = serve("");
+        failing.metrics = transport.metrics.clone();
+        failing.timeouts.read = Some(Duration::from_millis(200));
+        assert!(failing.post(serde_json::json!({})).is_err());
+
+        let recorded = recorder.0.lock().unwrap();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].endpoint, "chat");
+        assert_eq!(recorded[0].call, "invoke");
+        assert_eq!(recorded[0].outcome, "success");
+        assert_eq!(recorded[0].prompt_tokens, 3);
+        assert_eq!(recorded[0].completion_tokens, 5);
+        assert_eq!(recorded[1].outcome, "timeout");
+    }
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let recorder = Arc::new(Recorder::default());
+        let mut transport = serve(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 80\r\n\r\n{\"id\":\"as-1\",\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":5,\"total_tokens\":8}}",
+        );
+        transport.metrics = Some(Metrics(recorder.clone()));
+        transport.single_flight = Some(SingleFlight::new());
+        let (a, b) = tokio::join!(
+            transport.apost(serde_json::json!({"input": ["a"]})),
+            transport.apost(serde_json::json!({"input": ["a"]})),
+        );
+        assert_eq!(a.unwrap(), b.unwrap());
+
+        let recorded = recorder.0.lock().unwrap();
+        let outcomes: Vec<&str> = recorded.iter().map(|metrics| metrics.outcome).collect();
+        assert_eq!(outcomes, vec!["success", "coalesced"]);
+        assert_eq!(recorded[1].prompt_tokens, 0);
+    }
+
+    struct Tenant(Mutex<&'static str>);
+
+    impl Middleware for Tenant {
+        fn before_request(
+            &self,
+            _context: &RequestContext,
+            body: &mut Value,
+        ) -> Result<(), ErnieError> {
+            body["user_id"] = Value::from(*self.0.lock().unwrap());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_cache_key_after_middlewares() {
+        let recorder = Arc::new(Recorder::default());
+        let tenant = Arc::new(Tenant(Mutex::new("a")));
+        let mut transport = serve(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 80\r\n\r\n{\"id\":\"as-1\",\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":5,\"total_tokens\":8}}",
+        );
+        transport.metrics = Some(Metrics(recorder.clone()));
+        transport.middlewares = Middlewares(vec![tenant.clone()]);
+        transport.cache = Some(ResponseCache::in_memory(10));
+        transport.post(serde_json::json!({})).unwrap();
+        *tenant.0.lock().unwrap() = "b";
+        transport.post(serde_json::json!({})).unwrap();
+        *tenant.0.lock().unwrap() = "a";
+        transport.post(serde_json::json!({})).unwrap();
+
+        let recorded = recorder.0.lock().unwrap();
+        let outcomes: Vec<&str> = recorded.iter().map(|metrics| metrics.outcome).collect();
+        assert_eq!(outcomes, vec!["success", "success", "cache_hit"]);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_probe() {
+        let mut transport = serve("");
+        let breaker = CircuitBreaker::new()
+            .with_minimum_calls(1)
+            .with_open_duration(Duration::from_millis(20));
+        transport.circuit_breaker = Some(breaker.clone());
+        // a call cancelled by the caller says nothing about the endpoint
+        let cancelled = transport.apost(serde_json::json!({}));
+        assert!(tokio::time::timeout(Duration::from_millis(50), cancelled)
+            .await
+            .is_err());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker
+            .acquire()
+            .unwrap()
+            .record(Some(&ErnieError::ReadTimeoutError("slow".to_string())));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        let probe = transport.apost(serde_json::json!({}));
+        assert!(tokio::time::timeout(Duration::from_millis(100), probe)
+            .await
+            .is_err());
+        // the dropped probe opens the circuit again instead of keeping it half-open
+        assert_eq!(breaker.state(), CircuitState::Open);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(transport.admit().is_ok());
+    }
+
+    struct Redact;
+
+    impl Middleware for Redact {
+        fn before_request(
+            &self,
+            _context: &RequestContext,
+            body: &mut Value,
+        ) -> Result<(), ErnieError> {
+            match body.get("forbidden") {
+                Some(_) => Err(ErnieError::GenerateBodyError("forbidden".to_string())),
+                None => Ok(()),
+            }
+        }
+
+        fn after_response(
+            &self,
+            context: &RequestContext,
+            response: &mut Value,
+        ) -> Result<(), ErnieError> {
+            assert_eq!(context.call, "invoke");
+            response["id"] = Value::String("redacted".to_string());
+            Ok(())
+        }
+
+        fn on_stream_chunk(
+            &self,
+            _context: &RequestContext,
+            chunk: &mut Value,
+        ) -> Result<(), ErnieError> {
+            chunk["result"] = Value::String("redacted".to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_middlewares() {
+        let mut transport = serve(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 80\r\n\r\n{\"id\":\"as-1\",\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":5,\"total_tokens\":8}}",
+        );
+        transport.middlewares = Middlewares(vec![Arc::new(Redact)]);
+        let response = transport.post(serde_json::json!({})).unwrap();
+        assert_eq!(response["id"], "redacted");
+        let result = transport.post(serde_json::json!({"forbidden": true}));
+        assert!(matches!(result, Err(ErnieError::GenerateBodyError(_))));
+
+        let mut transport = serve(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nContent-Length: 23\r\n\r\ndata: {\"result\": \"a\"}\n\n",
+        );
+        transport.middlewares = Middlewares(vec![Arc::new(Redact)]);
+        let text = transport.post_for_text(serde_json::json!({})).unwrap();
+        assert!(text.contains(r#"data: {"result":"redacted"}"#));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::errors::ErnieError;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Accumulated usage of one model or one user.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct Usage {
+    /// number of successful requests
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    /// number of generated images (text2image only)
+    pub images: u64,
+    /// estimated cost, in the currency of the price table
+    pub cost: f64,
+}
+
+impl Usage {
+    /// extract the usage of a single raw response. `images` is the number of images in `data` for text2image responses.
+    pub fn from_response(response: &Value, images: u64) -> Self {
+        let usage = response.get("usage");
+        let tokens = |key: &str| {
+            usage
+                .and_then(|u| u.get(key))
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0)
+        };
+        Usage {
+            requests: 1,
+            prompt_tokens: tokens("prompt_tokens"),
+            completion_tokens: tokens("completion_tokens"),
+            total_tokens: tokens("total_tokens"),
+            images,
+            cost: 0.0,
+        }
+    }
+
+    fn add(&mut self, other: &Usage) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+        self.images += other.images;
+        self.cost += other.cost;
+    }
+}
+
+/// Price of a model. Qianfan bills tokens per thousand, so prices are given the same way.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct Price {
+    pub per_1k_prompt_tokens: f64,
+    pub per_1k_completion_tokens: f64,
+    pub per_image: f64,
+}
+
+impl Price {
+    fn cost(&self, usage: &Usage) -> f64 {
+        usage.prompt_tokens as f64 / 1000.0 * self.per_1k_prompt_tokens
+            + usage.completion_tokens as f64 / 1000.0 * self.per_1k_completion_tokens
+            + usage.images as f64 * self.per_image
+    }
+}
+
+/** Price table keyed by model name, the last part of the endpoint url (e.g. "completions_pro", "embedding-v1", or the name of a custom endpoint).
+
+Models missing from the table are counted at zero cost.
+```
+use erniebot_rs::usage::{Price, PriceTable};
+let prices = PriceTable::new().with_price(
+    "completions_pro",
+    Price {
+        per_1k_prompt_tokens: 0.03,
+        per_1k_completion_tokens: 0.09,
+        ..Default::default()
+    },
+);
+assert!(prices.get("completions_pro").is_some());
+```
+*/
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct PriceTable {
+    prices: HashMap<String, Price>,
+}
+
+impl PriceTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_price(mut self, model: &str, price: Price) -> Self {
+        self.prices.insert(model.to_string(), price);
+        self
+    }
+
+    pub fn get(&self, model: &str) -> Option<&Price> {
+        self.prices.get(model)
+    }
+}
+
+/// Limits enforced by a `UsageTracker`. Once a limit is reached, further calls fail with `ErnieError::BudgetExceededError` before any request is sent.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct Budget {
+    /// maximum tokens over all models and users
+    pub max_total_tokens: Option<u64>,
+    /// maximum estimated cost over all models and users
+    pub max_total_cost: Option<f64>,
+    /// maximum tokens for each `user_id`
+    pub max_tokens_per_user: Option<u64>,
+    /// maximum estimated cost for each `user_id`
+    pub max_cost_per_user: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    prices: PriceTable,
+    budget: Budget,
+    total: Usage,
+    by_model: HashMap<String, Usage>,
+    by_user: HashMap<String, Usage>,
+}
+
+/** UsageTracker aggregates the usage reported by every endpoint attached to it.
+
+It is cheap to clone, and all clones share the same counters, so one tracker can be attached to several endpoints with `with_usage_tracker`.
+Usage is recorded per model and per `user_id` (when the request has one).
+*/
+#[derive(Debug, Clone, Default)]
+pub struct UsageTracker {
+    state: Arc<Mutex<TrackerState>>,
+}
+
+impl UsageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_price_table(self, prices: PriceTable) -> Self {
+        self.state.lock().unwrap().prices = prices;
+        self
+    }
+
+    pub fn with_budget(self, budget: Budget) -> Self {
+        self.state.lock().unwrap().budget = budget;
+        self
+    }
+
+    /// record the usage of one call. The cost is computed from the price table, the `cost` field of `usage` is ignored.
+    pub fn record(&self, model: &str, user_id: Option<&str>, usage: &Usage) {
+        let mut state = self.state.lock().unwrap();
+        let mut usage = usage.clone();
+        usage.cost = state
+            .prices
+            .get(model)
+            .map(|price| price.cost(&usage))
+            .unwrap_or(0.0);
+        state.total.add(&usage);
+        state
+            .by_model
+            .entry(model.to_string())
+            .or_default()
+            .add(&usage);
+        if let Some(user_id) = user_id {
+            state
+                .by_user
+                .entry(user_id.to_string())
+                .or_default()
+                .add(&usage);
+        }
+    }
+
+    /// check the budget before sending a request for `user_id`
+    pub fn check_budget(&self, user_id: Option<&str>) -> Result<(), ErnieError> {
+        let state = self.state.lock().unwrap();
+        let budget = &state.budget;
+        if let Some(max) = budget.max_total_tokens {
+            if state.total.total_tokens >= max {
+                return Err(ErnieError::BudgetExceededError(format!(
+                    "total tokens {} reached the budget of {}",
+                    state.total.total_tokens, max
+                )));
+            }
+        }
+        if let Some(max) = budget.max_total_cost {
+            if state.total.cost >= max {
+                return Err(ErnieError::BudgetExceededError(format!(
+                    "total cost {} reached the budget of {}",
+                    state.total.cost, max
+                )));
+            }
+        }
+        let Some(user_usage) = user_id.and_then(|user_id| state.by_user.get(user_id)) else {
+            return Ok(());
+        };
+        if let Some(max) = budget.max_tokens_per_user {
+            if user_usage.total_tokens >= max {
+                return Err(ErnieError::BudgetExceededError(format!(
+                    "tokens {} of user {} reached the budget of {}",
+                    user_usage.total_tokens,
+                    user_id.unwrap_or_default(),
+                    max
+                )));
+            }
+        }
+        if let Some(max) = budget.max_cost_per_user {
+            if user_usage.cost >= max {
+                return Err(ErnieError::BudgetExceededError(format!(
+                    "cost {} of user {} reached the budget of {}",
+                    user_usage.cost,
+                    user_id.unwrap_or_default(),
+                    max
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// usage over all models and users
+    pub fn total(&self) -> Usage {
+        self.state.lock().unwrap().total.clone()
+    }
+
+    pub fn usage_by_model(&self) -> HashMap<String, Usage> {
+        self.state.lock().unwrap().by_model.clone()
+    }
+
+    pub fn usage_by_user(&self) -> HashMap<String, Usage> {
+        self.state.lock().unwrap().by_user.clone()
+    }
+
+    /// clear all counters, keeping the price table and the budget
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.total = Usage::default();
+        state.by_model.clear();
+        state.by_user.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Budget, Price, PriceTable, Usage, UsageTracker};
+    use crate::errors::ErnieError;
+
+    #[test]
+    fn test_record_and_cost() {
+        let prices = PriceTable::new()
+            .with_price(
+                "completions_pro",
+                Price {
+                    per_1k_prompt_tokens: 0.03,
+                    per_1k_completion_tokens: 0.09,
+                    ..Default::default()
+                },
+            )
+            .with_price(
+                "sd_xl",
+                Price {
+                    per_image: 0.06,
+                    ..Default::default()
+                },
+            );
+        let tracker = UsageTracker::new().with_price_table(prices);
+        let response = serde_json::json!({
+            "usage": {"prompt_tokens": 1000, "completion_tokens": 2000, "total_tokens": 3000}
+        });
+        tracker.record(
+            "completions_pro",
+            Some("alice"),
+            &Usage::from_response(&response, 0),
+        );
+        tracker.record("sd_xl", None, &Usage::from_response(&response, 2));
+        let by_model = tracker.usage_by_model();
+        assert!((by_model["completions_pro"].cost - 0.21).abs() < 1e-9);
+        assert!((by_model["sd_xl"].cost - 0.12).abs() < 1e-9);
+        assert_eq!(tracker.usage_by_user()["alice"].total_tokens, 3000);
+        assert_eq!(tracker.total().requests, 2);
+        assert_eq!(tracker.total().images, 2);
+    }
+
+    #[test]
+    fn test_budget() {
+        let tracker = UsageTracker::new().with_budget(Budget {
+            max_tokens_per_user: Some(100),
+            ..Default::default()
+        });
+        let usage = Usage {
+            requests: 1,
+            total_tokens: 100,
+            ..Default::default()
+        };
+        tracker.record("eb-instant", Some("bob"), &usage);
+        assert!(tracker.check_budget(None).is_ok());
+        assert!(tracker.check_budget(Some("alice")).is_ok());
+        assert!(matches!(
+            tracker.check_budget(Some("bob")),
+            Err(ErnieError::BudgetExceededError(_))
+        ));
+        tracker.reset();
+        assert!(tracker.check_budget(Some("bob")).is_ok());
+    }
+}
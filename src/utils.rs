@@ -1,24 +1,27 @@
 use super::errors::ErnieError;
+use crate::trace;
 use base64::prelude::*;
 use image::{DynamicImage, ImageResult};
 use serde_json::Value;
 use std::env::var;
+use std::time::{Duration, Instant};
 use url::{ParseError, Url};
 
+static ACCESS_TOKEN_URL: &str = "https://aip.baidubce.com/oauth/2.0/token";
+/// Lifetime of an access token when the response does not tell it (Qianfan tokens last 30 days)
+static DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// get an access token for the application whose AK/SK are set in the QIANFAN_AK and QIANFAN_SK environment variables
 pub fn get_access_token() -> Result<String, ErnieError> {
-    let url = "https://aip.baidubce.com/oauth/2.0/token";
     let ak = var("QIANFAN_AK")
         .map_err(|_| ErnieError::GetAccessTokenError("QIANFAN_AK is not set".to_string()))?;
     let sk = var("QIANFAN_SK")
         .map_err(|_| ErnieError::GetAccessTokenError("QIANFAN_SK is not set".to_string()))?;
-    let res: Value = ureq::post(url)
-        .query("grant_type", "client_credentials")
-        .query("client_id", ak.as_str())
-        .query("client_secret", sk.as_str())
-        .call()
-        .map_err(|e| ErnieError::GetAccessTokenError(e.to_string()))?
-        .into_json()
-        .map_err(|e| ErnieError::GetAccessTokenError(e.to_string()))?;
+    let (access_token, _) = fetch_access_token(&ak, &sk)?;
+    Ok(access_token)
+}
+
+fn parse_access_token(res: Value) -> Result<(String, Duration), ErnieError> {
     if let Some(error) = res.get("error") {
         let error_description = res.get("error_description").unwrap();
         Err(ErnieError::GetAccessTokenError(format!(
@@ -26,11 +29,66 @@ pub fn get_access_token() -> Result<String, ErnieError> {
             error, error_description
         )))
     } else {
-        let access_token = res.get("access_token").unwrap().as_str().unwrap();
-        Ok(access_token.to_string())
+        let access_token = res.get("access_token").and_then(|v| v.as_str()).ok_or(
+            ErnieError::GetAccessTokenError("access_token is not found".to_string()),
+        )?;
+        let lifetime = res
+            .get("expires_in")
+            .and_then(|v| v.as_u64())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+        Ok((access_token.to_string(), lifetime))
     }
 }
 
+/// get an access token and its lifetime for the application with the given AK/SK, in a blocking way
+pub fn fetch_access_token(ak: &str, sk: &str) -> Result<(String, Duration), ErnieError> {
+    let span = trace::access_token_span();
+    let start = Instant::now();
+    let result = span.in_scope(|| {
+        let res: Value = ureq::post(ACCESS_TOKEN_URL)
+            .query("grant_type", "client_credentials")
+            .query("client_id", ak)
+            .query("client_secret", sk)
+            .call()
+            .map_err(|e| ErnieError::GetAccessTokenError(e.to_string()))?
+            .into_json()
+            .map_err(|e| ErnieError::GetAccessTokenError(e.to_string()))?;
+        parse_access_token(res)
+    });
+    trace::record_end(&span, start, result.as_ref().err());
+    result
+}
+
+/// get an access token and its lifetime for the application with the given AK/SK, in an async way
+pub async fn afetch_access_token(ak: &str, sk: &str) -> Result<(String, Duration), ErnieError> {
+    let span = trace::access_token_span();
+    let start = Instant::now();
+    let result = trace::instrument(
+        async {
+            let client = reqwest::Client::new();
+            let res: Value = client
+                .post(ACCESS_TOKEN_URL)
+                .query(&[
+                    ("grant_type", "client_credentials"),
+                    ("client_id", ak),
+                    ("client_secret", sk),
+                ])
+                .send()
+                .await
+                .map_err(|e| ErnieError::GetAccessTokenError(e.to_string()))?
+                .json()
+                .await
+                .map_err(|e| ErnieError::GetAccessTokenError(e.to_string()))?;
+            parse_access_token(res)
+        },
+        span.clone(),
+    )
+    .await;
+    trace::record_end(&span, start, result.as_ref().err());
+    result
+}
+
 /// Build the url for the chat model
 pub fn build_url(url: &str, model: &str) -> Result<Url, ParseError> {
     let base = Url::parse(url)?;
@@ -38,6 +96,57 @@ pub fn build_url(url: &str, model: &str) -> Result<Url, ParseError> {
     Ok(joined)
 }
 
+/** Estimate the number of tokens of a text, following the rule given by Qianfan: one token per Chinese character and 1.3 tokens per English word.
+
+It is only an estimate, the exact count is reported in the `usage` of responses.
+```
+use erniebot_rs::utils::estimate_tokens;
+assert_eq!(estimate_tokens("你好"), 2);
+assert_eq!(estimate_tokens("hello world, 你好"), 5);
+```
+*/
+pub fn estimate_tokens(text: &str) -> u64 {
+    let mut chinese_chars = 0u64;
+    let mut words = 0u64;
+    let mut in_word = false;
+    for c in text.chars() {
+        if is_cjk(c) {
+            chinese_chars += 1;
+            in_word = false;
+        } else if c.is_alphanumeric() {
+            if !in_word {
+                words += 1;
+            }
+            in_word = true;
+        } else {
+            in_word = false;
+        }
+    }
+    chinese_chars + (words as f64 * 1.3).ceil() as u64
+}
+
+/// the state of a xorshift64* generator drawing numbers from `seed`, never 0
+pub(crate) fn rng_state(seed: u64) -> u64 {
+    (seed ^ 0x9E37_79B9_7F4A_7C15).max(1)
+}
+
+/// the next number of a xorshift64* generator, whose 53 high bits are uniform enough for an `f64`
+pub(crate) fn xorshift64star(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+/// whether a character is a CJK ideograph
+pub(crate) fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{F900}'..='\u{FAFF}'
+        | '\u{20000}'..='\u{2FA1F}')
+}
+
 pub fn base64_to_image(image_string: String) -> ImageResult<DynamicImage> {
     let bytes = BASE64_STANDARD.decode(image_string).unwrap();
     let img = image::load_from_memory(&bytes).unwrap();
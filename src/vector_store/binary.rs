@@ -0,0 +1,101 @@
+use crate::errors::ErnieError;
+
+pub(crate) fn store_error(error: impl ToString) -> ErnieError {
+    ErnieError::VectorStoreError(error.to_string())
+}
+
+/// Little endian encoding of the binary files of the vector store.
+pub(crate) trait Encode {
+    fn put_u8(&mut self, value: u8);
+    fn put_u32(&mut self, value: usize);
+    fn put_u64(&mut self, value: u64);
+    fn put_bytes(&mut self, bytes: &[u8]);
+    fn put_f32s(&mut self, values: &[f32]);
+}
+
+impl Encode for Vec<u8> {
+    fn put_u8(&mut self, value: u8) {
+        self.push(value);
+    }
+
+    fn put_u32(&mut self, value: usize) {
+        self.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+
+    fn put_u64(&mut self, value: u64) {
+        self.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// length prefixed bytes
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put_u32(bytes.len());
+        self.extend_from_slice(bytes);
+    }
+
+    fn put_f32s(&mut self, values: &[f32]) {
+        for value in values {
+            self.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+/// Decoding of what `Encode` encoded, failing on truncated input.
+pub(crate) struct Reader<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Reader { rest: bytes }
+    }
+
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], ErnieError> {
+        if self.rest.len() < n {
+            return Err(store_error("the file is truncated"));
+        }
+        let (taken, rest) = self.rest.split_at(n);
+        self.rest = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, ErnieError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<usize, ErnieError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, ErnieError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], ErnieError> {
+        let len = self.u32()?;
+        self.take(len)
+    }
+
+    pub(crate) fn f32s(&mut self, n: usize) -> Result<Vec<f32>, ErnieError> {
+        Ok(self
+            .take(n * 4)?
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes(x.try_into().unwrap()))
+            .collect())
+    }
+
+    /// check the magic bytes and version at the start of a file
+    pub(crate) fn header(&mut self, magic: &[u8; 4], version: u8) -> Result<(), ErnieError> {
+        if self.take(4)? != magic || self.u8()? != version {
+            return Err(store_error("not a file of a supported format or version"));
+        }
+        Ok(())
+    }
+}
+
+/// write a file through a temporary one, so that a crash never leaves a partial file
+pub(crate) fn write_file(path: &std::path::Path, bytes: &[u8]) -> Result<(), ErnieError> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, bytes).map_err(store_error)?;
+    std::fs::rename(&temporary, path).map_err(store_error)
+}
@@ -0,0 +1,286 @@
+use super::document::{Document, ScoredDocument};
+use super::filter::Filter;
+use crate::utils::is_cjk;
+use std::collections::{HashMap, HashSet};
+
+fn push_cjk_run(run: &mut Vec<char>, terms: &mut Vec<String>) {
+    match run.len() {
+        0 => {}
+        1 => terms.push(run[0].to_string()),
+        _ => terms.extend(run.windows(2).map(|pair| pair.iter().collect())),
+    }
+    run.clear();
+}
+
+fn push_word(word: &mut String, terms: &mut Vec<String>) {
+    if word.is_empty() {
+        return;
+    }
+    let parts: Vec<&str> = word.split(['-', '_', '.']).collect();
+    if parts.len() > 1 {
+        terms.extend(parts.iter().map(|part| part.to_string()));
+    }
+    terms.push(std::mem::take(word));
+}
+
+/** Split a text into the terms indexed by a `Bm25Index`.
+
+Words are lowercased. Codes joining words with `-`, `_` or `.` are kept whole, and their parts are added as well. Chinese, which has no spaces, is split into overlapping pairs of characters, so that any word of two characters or more is matched.
+```
+use erniebot_rs::vector_store::tokenize;
+assert_eq!(tokenize("Model XR-200"), vec!["model", "xr", "200", "xr-200"]);
+assert_eq!(tokenize("重置密码"), vec!["重置", "置密", "密码"]);
+```
+*/
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut word = String::new();
+    let mut run = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if is_cjk(c) {
+            push_word(&mut word, &mut terms);
+            run.push(c);
+        } else if c.is_alphanumeric() {
+            push_cjk_run(&mut run, &mut terms);
+            word.extend(c.to_lowercase());
+        } else if matches!(c, '-' | '_' | '.')
+            && !word.is_empty()
+            && chars
+                .peek()
+                .is_some_and(|next| next.is_alphanumeric() && !is_cjk(*next))
+        {
+            word.push(c);
+        } else {
+            push_word(&mut word, &mut terms);
+            push_cjk_run(&mut run, &mut terms);
+        }
+    }
+    push_word(&mut word, &mut terms);
+    push_cjk_run(&mut run, &mut terms);
+    terms
+}
+
+/** A keyword index ranking documents with BM25, complementing the embeddings of a `VectorStore` on exact matches like product codes and names.
+
+Texts are split into terms with `tokenize`.
+```
+use erniebot_rs::vector_store::{Bm25Index, Document};
+let mut index = Bm25Index::new();
+index.upsert(Document::new("1", "XR-200 的电池续航为10小时"));
+index.upsert(Document::new("2", "XR-300 支持快速充电"));
+let found = index.search("xr-300 充电", 1, None);
+assert_eq!(found[0].document.id, "2");
+```
+*/
+#[derive(Debug, Clone)]
+pub struct Bm25Index {
+    k1: f64,
+    b: f64,
+    /// document of each slot, `None` once deleted or replaced
+    documents: Vec<Option<Document>>,
+    /// number of terms of the document of each slot
+    lengths: Vec<usize>,
+    /// slot of each document by id
+    slots: HashMap<String, usize>,
+    /// slots containing each term, with the number of occurrences
+    postings: HashMap<String, Vec<(usize, usize)>>,
+    total_length: usize,
+}
+
+impl Default for Bm25Index {
+    fn default() -> Self {
+        Bm25Index {
+            k1: 1.2,
+            b: 0.75,
+            documents: Vec::new(),
+            lengths: Vec::new(),
+            slots: HashMap::new(),
+            postings: HashMap::new(),
+            total_length: 0,
+        }
+    }
+}
+
+impl Bm25Index {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// index documents, the last one winning when ids repeat
+    pub fn from_documents(documents: impl IntoIterator<Item = Document>) -> Self {
+        let mut index = Bm25Index::new();
+        for document in documents {
+            index.upsert(document);
+        }
+        index
+    }
+
+    /// set the term frequency saturation `k1` (1.2 by default) and the length normalization `b` (0.75 by default)
+    pub fn with_parameters(mut self, k1: f64, b: f64) -> Self {
+        self.k1 = k1.max(0.0);
+        self.b = b.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Document> {
+        self.documents[*self.slots.get(id)?].as_ref()
+    }
+
+    pub fn documents(&self) -> impl Iterator<Item = &Document> {
+        self.documents.iter().flatten()
+    }
+
+    /// add a document, replacing the indexed document with the same id
+    pub fn upsert(&mut self, document: Document) {
+        self.delete(&[&document.id]);
+        let slot = self.documents.len();
+        let terms = tokenize(&document.text);
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for term in &terms {
+            *counts.entry(term.clone()).or_default() += 1;
+        }
+        for (term, count) in counts {
+            self.postings.entry(term).or_default().push((slot, count));
+        }
+        self.lengths.push(terms.len());
+        self.total_length += terms.len();
+        self.slots.insert(document.id.clone(), slot);
+        self.documents.push(Some(document));
+    }
+
+    /// remove documents by id, returning how many were indexed
+    pub fn delete(&mut self, ids: &[&str]) -> usize {
+        let mut deleted = 0;
+        for id in ids {
+            let Some(slot) = self.slots.remove(*id) else {
+                continue;
+            };
+            let Some(document) = self.documents[slot].take() else {
+                continue;
+            };
+            let terms: HashSet<String> = tokenize(&document.text).into_iter().collect();
+            for term in terms {
+                if let Some(postings) = self.postings.get_mut(&term) {
+                    postings.retain(|(posting, _)| *posting != slot);
+                    if postings.is_empty() {
+                        self.postings.remove(&term);
+                    }
+                }
+            }
+            self.total_length -= self.lengths[slot];
+            deleted += 1;
+        }
+        deleted
+    }
+
+    pub fn clear(&mut self) {
+        *self = Bm25Index::new().with_parameters(self.k1, self.b);
+    }
+
+    /// the `k` documents matching best the terms of `query`, among the ones matching `filter`, the best first. Documents sharing no term with the query are not returned.
+    pub fn search(&self, query: &str, k: usize, filter: Option<&Filter>) -> Vec<ScoredDocument> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let count = self.len() as f64;
+        let average_length = (self.total_length as f64 / count).max(1.0);
+        let terms: HashSet<String> = tokenize(query).into_iter().collect();
+        let mut scores: HashMap<usize, f64> = HashMap::new();
+        for term in &terms {
+            let Some(postings) = self.postings.get(term) else {
+                continue;
+            };
+            let frequency = postings.len() as f64;
+            let idf = (1.0 + (count - frequency + 0.5) / (frequency + 0.5)).ln();
+            for &(slot, occurrences) in postings {
+                let occurrences = occurrences as f64;
+                let length = self.lengths[slot] as f64 / average_length;
+                *scores.entry(slot).or_default() += idf * occurrences * (self.k1 + 1.0)
+                    / (occurrences + self.k1 * (1.0 - self.b + self.b * length));
+            }
+        }
+        let mut found: Vec<(usize, f64)> = scores
+            .into_iter()
+            .filter(|(slot, _)| {
+                self.documents[*slot].as_ref().is_some_and(|document| {
+                    filter.is_none_or(|filter| filter.matches(&document.metadata))
+                })
+            })
+            .collect();
+        found.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        found
+            .into_iter()
+            .take(k)
+            .filter_map(|(slot, score)| {
+                Some(ScoredDocument {
+                    document: self.documents[slot].clone()?,
+                    score,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("Rust 1.75, rust-lang.org!"),
+            vec![
+                "rust",
+                "1",
+                "75",
+                "1.75",
+                "rust",
+                "lang",
+                "org",
+                "rust-lang.org"
+            ]
+        );
+        assert_eq!(tokenize("用Rust写"), vec!["用", "rust", "写"]);
+        assert_eq!(tokenize("电池-续航"), vec!["电池", "续航"]);
+        assert!(tokenize(" ,。 ").is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let mut index = Bm25Index::from_documents(vec![
+            Document::new("1", "the battery of the XR-200 lasts ten hours")
+                .with_metadata("lang", "en"),
+            Document::new("2", "the XR-300 supports fast charging").with_metadata("lang", "en"),
+            Document::new("3", "XR-300 支持快速充电").with_metadata("lang", "zh"),
+        ]);
+        let ids = |found: Vec<ScoredDocument>| -> Vec<String> {
+            found.into_iter().map(|f| f.document.id).collect()
+        };
+        assert_eq!(
+            ids(index.search("xr-200 battery", 3, None)),
+            ["1", "2", "3"]
+        );
+        assert_eq!(ids(index.search("快速充电", 3, None)), ["3"]);
+        assert_eq!(
+            ids(index.search("XR-300", 3, Some(&Filter::eq("lang", "en")))),
+            ["2", "1"]
+        );
+        assert!(index.search("unknown", 3, None).is_empty());
+
+        index.upsert(Document::new("1", "a phone with a large screen"));
+        assert_eq!(index.len(), 3);
+        assert_eq!(ids(index.search("battery screen", 3, None)), ["1"]);
+        assert_eq!(index.delete(&["1", "4"]), 1);
+        assert!(index.search("screen", 3, None).is_empty());
+        assert_eq!(index.total_length, index.lengths[1] + index.lengths[2]);
+    }
+}
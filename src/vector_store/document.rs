@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/** A text to store in a `VectorStore`, with its id and metadata.
+```
+use erniebot_rs::vector_store::Document;
+let document = Document::new("faq-1", "点击“忘记密码”即可重置密码")
+    .with_metadata("source", "faq.md")
+    .with_metadata("year", 2024);
+assert_eq!(document.metadata["year"], 2024);
+```
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct Document {
+    /// adding a document with the id of a stored one replaces it
+    pub id: String,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub metadata: Map<String, Value>,
+}
+
+impl Document {
+    pub fn new(id: impl Into<String>, text: impl Into<String>) -> Self {
+        Document {
+            id: id.into(),
+            text: text.into(),
+            metadata: Map::new(),
+        }
+    }
+
+    pub fn with_metadata(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.metadata.insert(key.to_string(), value.into());
+        self
+    }
+}
+
+/// A document found by a search, with its score, the higher the more relevant.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoredDocument {
+    pub document: Document,
+    pub score: f64,
+}
@@ -0,0 +1,86 @@
+use serde_json::{Map, Value};
+
+/** A condition on the metadata of documents, to restrict a search.
+```
+use erniebot_rs::vector_store::{Document, Filter};
+let filter = Filter::And(vec![
+    Filter::eq("source", "faq.md"),
+    Filter::gte("year", 2023),
+]);
+let document = Document::new("faq-1", "...")
+    .with_metadata("source", "faq.md")
+    .with_metadata("year", 2024);
+assert!(filter.matches(&document.metadata));
+```
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// the field equals the value
+    Eq(String, Value),
+    /// the field equals one of the values
+    In(String, Vec<Value>),
+    /// the field is a number at least the value
+    Gte(String, f64),
+    /// the field is a number at most the value
+    Lte(String, f64),
+    /// the field is set, to any value
+    Exists(String),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn eq(key: &str, value: impl Into<Value>) -> Self {
+        Filter::Eq(key.to_string(), value.into())
+    }
+
+    pub fn gte(key: &str, value: impl Into<f64>) -> Self {
+        Filter::Gte(key.to_string(), value.into())
+    }
+
+    pub fn lte(key: &str, value: impl Into<f64>) -> Self {
+        Filter::Lte(key.to_string(), value.into())
+    }
+
+    /// whether a document with this metadata satisfies the filter
+    pub fn matches(&self, metadata: &Map<String, Value>) -> bool {
+        let number = |key: &str| metadata.get(key).and_then(|value| value.as_f64());
+        match self {
+            Filter::Eq(key, value) => metadata.get(key) == Some(value),
+            Filter::In(key, values) => metadata.get(key).is_some_and(|v| values.contains(v)),
+            Filter::Gte(key, bound) => number(key).is_some_and(|v| v >= *bound),
+            Filter::Lte(key, bound) => number(key).is_some_and(|v| v <= *bound),
+            Filter::Exists(key) => metadata.contains_key(key),
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(metadata)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(metadata)),
+            Filter::Not(filter) => !filter.matches(metadata),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use serde_json::json;
+
+    #[test]
+    fn test_matches() {
+        let metadata = json!({"source": "faq.md", "year": 2024, "tags": "billing"});
+        let metadata = metadata.as_object().unwrap();
+        assert!(Filter::eq("source", "faq.md").matches(metadata));
+        assert!(!Filter::eq("source", "blog.md").matches(metadata));
+        assert!(
+            Filter::In("tags".to_string(), vec![json!("billing"), json!("account")])
+                .matches(metadata)
+        );
+        assert!(Filter::gte("year", 2024).matches(metadata));
+        assert!(!Filter::lte("year", 2023).matches(metadata));
+        assert!(!Filter::gte("source", 0).matches(metadata));
+        assert!(Filter::Or(vec![
+            Filter::Exists("author".to_string()),
+            Filter::Not(Box::new(Filter::eq("year", 2023))),
+        ])
+        .matches(metadata));
+    }
+}
@@ -0,0 +1,640 @@
+use super::binary::{store_error, write_file, Encode, Reader};
+use crate::embedding::{l2_normalize, similarity};
+use crate::errors::ErnieError;
+use crate::utils::{rng_state, xorshift64star};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::path::Path;
+
+static HNSW_MAGIC: &[u8; 4] = b"ERHN";
+static HNSW_VERSION: u8 = 1;
+/// highest layer a node can be drawn on, far above what millions of nodes need
+static MAX_LEVEL: usize = 16;
+
+/// The build and search parameters of an `HnswIndex`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HnswConfig {
+    /// links per node on the upper layers, twice as many on the bottom layer. More links raise recall and memory use.
+    pub m: usize,
+    /// breadth of the search for the neighbours of an inserted node. Higher builds a better graph, more slowly.
+    pub ef_construction: usize,
+    /// breadth of the search for a query, at least `k`. Higher raises recall and latency.
+    pub ef_search: usize,
+    /// seed drawing the layers of the nodes, so that building twice gives the same graph
+    pub seed: u64,
+}
+
+impl Default for HnswConfig {
+    fn default() -> Self {
+        HnswConfig {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+            seed: 0,
+        }
+    }
+}
+
+impl HnswConfig {
+    pub fn with_m(mut self, m: usize) -> Self {
+        self.m = m.max(2);
+        self
+    }
+
+    pub fn with_ef_construction(mut self, ef_construction: usize) -> Self {
+        self.ef_construction = ef_construction.max(1);
+        self
+    }
+
+    pub fn with_ef_search(mut self, ef_search: usize) -> Self {
+        self.ef_search = ef_search.max(1);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// A node and its similarity to the vector being searched, ordered by similarity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Scored(f64, u32);
+
+impl Eq for Scored {}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/** HnswIndex finds the nearest neighbours of a vector among many, approximately, with a hierarchical navigable small world graph.
+
+Vectors are normalized when inserted, and compared by cosine similarity. Each inserted vector gets a node id, counting from 0; removing a node only marks it as deleted, so that the graph stays navigable, and it is never returned again.
+A `VectorStore` uses it with `VectorStore::with_index`; it can also be used on its own.
+```
+use erniebot_rs::vector_store::{HnswConfig, HnswIndex};
+let mut index = HnswIndex::new(3, HnswConfig::default().with_ef_search(32));
+let a = index.insert(&[1.0, 0.0, 0.0]).unwrap();
+let b = index.insert(&[0.0, 1.0, 0.0]).unwrap();
+let found = index.search(&[0.9, 0.1, 0.0], 1).unwrap();
+assert_eq!(found[0].0, a);
+index.remove(a);
+assert_eq!(index.search(&[0.9, 0.1, 0.0], 1).unwrap()[0].0, b);
+```
+*/
+#[derive(Debug, Clone)]
+pub struct HnswIndex {
+    config: HnswConfig,
+    dimension: usize,
+    /// normalized vectors of all the nodes, one after the other
+    vectors: Vec<f32>,
+    /// neighbours of each node, on each of its layers from the bottom one
+    links: Vec<Vec<Vec<u32>>>,
+    deleted: Vec<bool>,
+    live: usize,
+    /// the node on the highest layer, where searches start
+    entry_point: Option<u32>,
+    rng: u64,
+}
+
+impl HnswIndex {
+    /// create an empty index. Parameters set directly in `config` below the minimums of its builders are raised to them, `m` to 2 and the breadths to 1.
+    pub fn new(dimension: usize, config: HnswConfig) -> Self {
+        let config = config
+            .with_m(config.m)
+            .with_ef_construction(config.ef_construction)
+            .with_ef_search(config.ef_search);
+        HnswIndex {
+            config,
+            dimension,
+            vectors: Vec::new(),
+            links: Vec::new(),
+            deleted: Vec::new(),
+            live: 0,
+            entry_point: None,
+            rng: rng_state(config.seed),
+        }
+    }
+
+    /// how many nodes are not deleted
+    pub fn len(&self) -> usize {
+        self.live
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live == 0
+    }
+
+    /// how many nodes were inserted, including the deleted ones
+    pub fn node_count(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    pub fn config(&self) -> &HnswConfig {
+        &self.config
+    }
+
+    /// change the breadth of the following searches
+    pub fn set_ef_search(&mut self, ef_search: usize) {
+        self.config.ef_search = ef_search.max(1);
+    }
+
+    /// the normalized vector of a node, unless it is deleted
+    pub fn vector(&self, node: usize) -> Option<&[f32]> {
+        match self.deleted.get(node) {
+            Some(false) => Some(self.node_vector(node as u32)),
+            _ => None,
+        }
+    }
+
+    fn node_vector(&self, node: u32) -> &[f32] {
+        let start = node as usize * self.dimension;
+        &self.vectors[start..start + self.dimension]
+    }
+
+    fn check_dimension(&self, vector: &[f32]) -> Result<(), ErnieError> {
+        if vector.len() != self.dimension {
+            return Err(ErnieError::DimensionMismatchError(format!(
+                "the index holds vectors of dimension {}, not {}",
+                self.dimension,
+                vector.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// draw the top layer of a new node, each layer holding about `1 / m` of the nodes of the layer below
+    fn random_level(&mut self) -> usize {
+        let bits = xorshift64star(&mut self.rng) >> 11;
+        let uniform = (bits as f64 + 1.0) / (1u64 << 53) as f64;
+        let level = -uniform.ln() / (self.config.m as f64).ln();
+        (level as usize).min(MAX_LEVEL)
+    }
+
+    fn max_links(&self, level: usize) -> usize {
+        if level == 0 {
+            2 * self.config.m
+        } else {
+            self.config.m
+        }
+    }
+
+    /// the `ef` nodes most similar to `query` found on a layer from `entry`, the most similar first; only the accepted nodes are returned, but all are traversed
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entry: &[Scored],
+        ef: usize,
+        level: usize,
+        accept: &dyn Fn(u32) -> bool,
+    ) -> Vec<Scored> {
+        let mut visited: HashSet<u32> = entry.iter().map(|scored| scored.1).collect();
+        let mut candidates: BinaryHeap<Scored> = entry.iter().copied().collect();
+        let mut found: BinaryHeap<Reverse<Scored>> = entry
+            .iter()
+            .filter(|scored| accept(scored.1))
+            .map(|scored| Reverse(*scored))
+            .collect();
+        while found.len() > ef {
+            found.pop();
+        }
+        let worst = |found: &BinaryHeap<Reverse<Scored>>| found.peek().map(|Reverse(s)| s.0);
+        while let Some(candidate) = candidates.pop() {
+            if found.len() >= ef && worst(&found).is_some_and(|worst| candidate.0 < worst) {
+                break;
+            }
+            for &neighbour in &self.links[candidate.1 as usize][level] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let scored = Scored(similarity(self.node_vector(neighbour), query), neighbour);
+                if found.len() < ef || worst(&found).is_some_and(|worst| scored.0 > worst) {
+                    candidates.push(scored);
+                    if accept(neighbour) {
+                        found.push(Reverse(scored));
+                        if found.len() > ef {
+                            found.pop();
+                        }
+                    }
+                }
+            }
+        }
+        let mut found: Vec<Scored> = found.into_iter().map(|Reverse(scored)| scored).collect();
+        found.sort_by(|a, b| b.cmp(a));
+        found
+    }
+
+    /// pick up to `m` neighbours among candidates sorted by similarity, preferring the ones in directions not covered yet (the heuristic of the HNSW paper)
+    fn select_neighbours(&self, candidates: &[Scored], m: usize) -> Vec<u32> {
+        let mut selected: Vec<u32> = Vec::with_capacity(m);
+        let mut pruned = Vec::new();
+        for candidate in candidates {
+            if selected.len() >= m {
+                break;
+            }
+            let vector = self.node_vector(candidate.1);
+            if selected
+                .iter()
+                .all(|&other| similarity(vector, self.node_vector(other)) < candidate.0)
+            {
+                selected.push(candidate.1);
+            } else {
+                pruned.push(candidate.1);
+            }
+        }
+        let missing = m.saturating_sub(selected.len());
+        selected.extend(pruned.into_iter().take(missing));
+        selected
+    }
+
+    /// normalize a vector and add it to the index, returning its node id
+    pub fn insert(&mut self, vector: &[f32]) -> Result<usize, ErnieError> {
+        self.check_dimension(vector)?;
+        let mut vector = vector.to_vec();
+        l2_normalize(&mut vector);
+        let node = self.links.len() as u32;
+        let level = self.random_level();
+        self.vectors.extend_from_slice(&vector);
+        self.links.push(vec![Vec::new(); level + 1]);
+        self.deleted.push(false);
+        self.live += 1;
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(node);
+            return Ok(node as usize);
+        };
+        let top = self.links[entry_point as usize].len() - 1;
+        let mut entry = vec![Scored(
+            similarity(self.node_vector(entry_point), &vector),
+            entry_point,
+        )];
+        for layer in (level + 1..=top).rev() {
+            entry = self.search_layer(&vector, &entry, 1, layer, &|_| true);
+        }
+        for layer in (0..=level.min(top)).rev() {
+            let found =
+                self.search_layer(&vector, &entry, self.config.ef_construction, layer, &|_| {
+                    true
+                });
+            let neighbours = self.select_neighbours(&found, self.config.m);
+            let max_links = self.max_links(layer);
+            for &neighbour in &neighbours {
+                self.links[neighbour as usize][layer].push(node);
+                if self.links[neighbour as usize][layer].len() > max_links {
+                    let base = self.node_vector(neighbour);
+                    let mut candidates: Vec<Scored> = self.links[neighbour as usize][layer]
+                        .iter()
+                        .map(|&other| Scored(similarity(base, self.node_vector(other)), other))
+                        .collect();
+                    candidates.sort_by(|a, b| b.cmp(a));
+                    self.links[neighbour as usize][layer] =
+                        self.select_neighbours(&candidates, max_links);
+                }
+            }
+            self.links[node as usize][layer] = neighbours;
+            entry = found;
+        }
+        if level > top {
+            self.entry_point = Some(node);
+        }
+        Ok(node as usize)
+    }
+
+    /// mark a node as deleted, returning whether it was live
+    pub fn remove(&mut self, node: usize) -> bool {
+        match self.deleted.get_mut(node) {
+            Some(deleted) if !*deleted => {
+                *deleted = true;
+                self.live -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// the `k` live nodes most similar to `query`, with their cosine similarity, the most similar first
+    pub fn search(&self, query: &[f32], k: usize) -> Result<Vec<(usize, f64)>, ErnieError> {
+        self.search_filtered(query, k, |_| true)
+    }
+
+    /// like `search`, among the nodes for which `accept` returns true
+    pub fn search_filtered(
+        &self,
+        query: &[f32],
+        k: usize,
+        accept: impl Fn(usize) -> bool,
+    ) -> Result<Vec<(usize, f64)>, ErnieError> {
+        self.check_dimension(query)?;
+        let Some(entry_point) = self.entry_point else {
+            return Ok(Vec::new());
+        };
+        let mut query = query.to_vec();
+        l2_normalize(&mut query);
+        let mut entry = vec![Scored(
+            similarity(self.node_vector(entry_point), &query),
+            entry_point,
+        )];
+        for layer in (1..self.links[entry_point as usize].len()).rev() {
+            entry = self.search_layer(&query, &entry, 1, layer, &|_| true);
+        }
+        let accept = |node: u32| !self.deleted[node as usize] && accept(node as usize);
+        let found = self.search_layer(&query, &entry, self.config.ef_search.max(k), 0, &accept);
+        Ok(found
+            .into_iter()
+            .take(k)
+            .map(|scored| (scored.1 as usize, scored.0))
+            .collect())
+    }
+
+    pub(crate) fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(HNSW_MAGIC);
+        bytes.put_u8(HNSW_VERSION);
+        bytes.put_u32(self.dimension);
+        bytes.put_u32(self.config.m);
+        bytes.put_u32(self.config.ef_construction);
+        bytes.put_u32(self.config.ef_search);
+        bytes.put_u64(self.config.seed);
+        bytes.put_u64(self.rng);
+        bytes.put_u64(self.entry_point.map_or(u64::MAX, u64::from));
+        bytes.put_u64(self.links.len() as u64);
+        for (node, layers) in self.links.iter().enumerate() {
+            bytes.put_u8(self.deleted[node] as u8);
+            bytes.put_f32s(self.node_vector(node as u32));
+            bytes.put_u8(layers.len() as u8);
+            for neighbours in layers {
+                bytes.put_u32(neighbours.len());
+                for &neighbour in neighbours {
+                    bytes.put_u32(neighbour as usize);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn decode(reader: &mut Reader) -> Result<Self, ErnieError> {
+        reader.header(HNSW_MAGIC, HNSW_VERSION)?;
+        let dimension = reader.u32()?;
+        let config = HnswConfig {
+            m: reader.u32()?,
+            ef_construction: reader.u32()?,
+            ef_search: reader.u32()?,
+            seed: reader.u64()?,
+        };
+        if config.m < 2 {
+            return Err(store_error("the index has fewer than 2 links per node"));
+        }
+        let mut index = HnswIndex::new(dimension, config);
+        index.rng = reader.u64()?;
+        let entry_point = reader.u64()?;
+        let count = reader.u64()? as usize;
+        for _ in 0..count {
+            let deleted = reader.u8()? != 0;
+            index.vectors.extend(reader.f32s(dimension)?);
+            let mut layers = Vec::new();
+            for _ in 0..reader.u8()? {
+                let mut neighbours = Vec::new();
+                for _ in 0..reader.u32()? {
+                    let neighbour = reader.u32()?;
+                    if neighbour >= count {
+                        return Err(store_error("the index links to a missing node"));
+                    }
+                    neighbours.push(neighbour as u32);
+                }
+                layers.push(neighbours);
+            }
+            if layers.is_empty() {
+                return Err(store_error("a node of the index has no layer"));
+            }
+            index.links.push(layers);
+            index.deleted.push(deleted);
+            index.live += !deleted as usize;
+        }
+        // searches follow the links of each layer without checking them
+        for layers in &index.links {
+            for (level, neighbours) in layers.iter().enumerate() {
+                if neighbours
+                    .iter()
+                    .any(|&neighbour| index.links[neighbour as usize].len() <= level)
+                {
+                    return Err(store_error(
+                        "the index links to a node missing from the layer of the link",
+                    ));
+                }
+            }
+        }
+        let top = index.links.iter().map(|layers| layers.len()).max();
+        match (entry_point, top) {
+            (u64::MAX, None) => {}
+            (u64::MAX, Some(_)) => {
+                return Err(store_error("the entry point of the index is missing"))
+            }
+            (entry_point, Some(top)) if (entry_point as usize) < count => {
+                if index.links[entry_point as usize].len() != top {
+                    return Err(store_error(
+                        "the entry point of the index is not on its top layer",
+                    ));
+                }
+                index.entry_point = Some(entry_point as u32);
+            }
+            _ => return Err(store_error("the entry point of the index is missing")),
+        }
+        Ok(index)
+    }
+
+    /// write the index to a file, replacing it atomically
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ErnieError> {
+        let mut bytes = Vec::new();
+        self.encode(&mut bytes);
+        write_file(path.as_ref(), &bytes)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ErnieError> {
+        let bytes = std::fs::read(path).map_err(store_error)?;
+        HnswIndex::decode(&mut Reader::new(&bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HnswConfig, HnswIndex, MAX_LEVEL};
+    use crate::embedding::{dot, l2_normalize};
+    use crate::errors::ErnieError;
+    use crate::vector_store::binary::Reader;
+
+    /// deterministic pseudo random vectors, normalized
+    fn vectors(count: usize, dimension: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                let mut vector: Vec<f32> = (0..dimension)
+                    .map(|_| {
+                        state = state
+                            .wrapping_mul(6364136223846793005)
+                            .wrapping_add(1442695040888963407);
+                        ((state >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+                    })
+                    .collect();
+                l2_normalize(&mut vector);
+                vector
+            })
+            .collect()
+    }
+
+    fn exact(data: &[Vec<f32>], query: &[f32], k: usize) -> Vec<usize> {
+        let mut scored: Vec<(f64, usize)> = data
+            .iter()
+            .enumerate()
+            .map(|(node, vector)| (dot(vector, query).unwrap(), node))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored.into_iter().take(k).map(|(_, node)| node).collect()
+    }
+
+    fn recall(index: &HnswIndex, data: &[Vec<f32>], queries: &[Vec<f32>], k: usize) -> f64 {
+        let mut hits = 0;
+        for query in queries {
+            let expected = exact(data, query, k);
+            let found = index.search(query, k).unwrap();
+            hits += found
+                .iter()
+                .filter(|(node, _)| expected.contains(node))
+                .count();
+        }
+        hits as f64 / (queries.len() * k) as f64
+    }
+
+    #[test]
+    fn test_recall() {
+        let data = vectors(1000, 24, 1);
+        let queries = vectors(50, 24, 2);
+        let mut index = HnswIndex::new(24, HnswConfig::default().with_ef_construction(64));
+        for vector in &data {
+            index.insert(vector).unwrap();
+        }
+        assert!(recall(&index, &data, &queries, 10) >= 0.9);
+
+        index.set_ef_search(10);
+        let narrow = recall(&index, &data, &queries, 10);
+        index.set_ef_search(200);
+        assert!(recall(&index, &data, &queries, 10) >= narrow);
+    }
+
+    #[test]
+    fn test_new_clamps_config() {
+        let data = vectors(50, 8, 4);
+        for m in [0, 1] {
+            let config = HnswConfig {
+                m,
+                ef_construction: 0,
+                ef_search: 0,
+                seed: 0,
+            };
+            let index = HnswIndex::new(8, config);
+            assert_eq!(index.config().m, 2);
+            assert_eq!(index.config().ef_construction, 1);
+            assert_eq!(index.config().ef_search, 1);
+
+            let mut index = HnswIndex::new(
+                8,
+                HnswConfig {
+                    m,
+                    ..HnswConfig::default()
+                },
+            );
+            for vector in &data {
+                index.insert(vector).unwrap();
+            }
+            assert!(index.links.iter().all(|layers| layers.len() <= MAX_LEVEL));
+            assert_eq!(index.search(&data[7], 1).unwrap()[0].0, 7);
+        }
+    }
+
+    #[test]
+    fn test_remove_and_filter() {
+        let data = vectors(300, 8, 3);
+        let mut index = HnswIndex::new(8, HnswConfig::default());
+        for vector in &data {
+            index.insert(vector).unwrap();
+        }
+        let nearest = index.search(&data[7], 1).unwrap()[0].0;
+        assert_eq!(nearest, 7);
+        assert!(index.remove(7));
+        assert!(!index.remove(7));
+        assert_eq!(index.len(), 299);
+        assert!(index
+            .search(&data[7], 20)
+            .unwrap()
+            .iter()
+            .all(|(node, _)| *node != 7));
+
+        let even = index
+            .search_filtered(&data[8], 10, |node| node % 2 == 0)
+            .unwrap();
+        assert_eq!(even.len(), 10);
+        assert!(even.iter().all(|(node, _)| node % 2 == 0));
+        assert!(index.insert(&[1.0]).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let data = vectors(200, 8, 4);
+        let mut index = HnswIndex::new(8, HnswConfig::default().with_m(8));
+        for vector in &data {
+            index.insert(vector).unwrap();
+        }
+        index.remove(3);
+        let path = std::env::temp_dir().join(format!("erniebot-hnsw-{}", std::process::id()));
+        index.save(&path).unwrap();
+        let loaded = HnswIndex::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 199);
+        assert_eq!(loaded.config(), index.config());
+        for query in vectors(10, 8, 5) {
+            assert_eq!(
+                loaded.search(&query, 5).unwrap(),
+                index.search(&query, 5).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_checks_graph() {
+        let decode = |index: &HnswIndex| {
+            let mut bytes = Vec::new();
+            index.encode(&mut bytes);
+            HnswIndex::decode(&mut Reader::new(&bytes))
+        };
+        let mut index = HnswIndex::new(2, HnswConfig::default());
+        index.insert(&[1.0, 0.0]).unwrap();
+        index.insert(&[0.0, 1.0]).unwrap();
+        index.links = vec![vec![vec![1]], vec![vec![0], vec![]]];
+        index.entry_point = Some(1);
+        assert!(decode(&index).is_ok());
+
+        let mut broken = index.clone();
+        broken.links[1][1].push(0);
+        assert!(matches!(
+            decode(&broken),
+            Err(ErnieError::VectorStoreError(_))
+        ));
+        let mut broken = index.clone();
+        broken.entry_point = Some(0);
+        assert!(decode(&broken).is_err());
+        let mut broken = index.clone();
+        broken.entry_point = None;
+        assert!(decode(&broken).is_err());
+        let mut broken = index.clone();
+        broken.config.m = 1;
+        assert!(decode(&broken).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use super::bm25::Bm25Index;
+use super::document::{Document, ScoredDocument};
+use super::filter::Filter;
+use super::store::{StoreFormat, VectorStore};
+use crate::embedding::EmbeddingEndpoint;
+use crate::errors::ErnieError;
+use std::collections::HashMap;
+use std::path::Path;
+
+/** Merge rankings of documents with reciprocal rank fusion: a document scores the sum of `1 / (k + rank)` over the rankings it appears in, its rank starting at 1.
+
+Only the ranks matter, so rankings with incomparable scores (cosine similarity, BM25...) can be merged. `k` is usually 60, a larger `k` flattening the advantage of the first ranks. Documents are matched by id.
+*/
+pub fn reciprocal_rank_fusion(rankings: &[Vec<ScoredDocument>], k: f64) -> Vec<ScoredDocument> {
+    let mut fused: Vec<ScoredDocument> = Vec::new();
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for ranking in rankings {
+        for (rank, found) in ranking.iter().enumerate() {
+            let score = 1.0 / (k + rank as f64 + 1.0);
+            match positions.get(found.document.id.as_str()) {
+                Some(&position) => fused[position].score += score,
+                None => {
+                    positions.insert(&found.document.id, fused.len());
+                    fused.push(ScoredDocument {
+                        document: found.document.clone(),
+                        score,
+                    });
+                }
+            }
+        }
+    }
+    fused.sort_by(|a, b| b.score.total_cmp(&a.score));
+    fused
+}
+
+/** A `VectorStore` and a `Bm25Index` kept in sync, searched together.
+
+Searches merge the documents most similar to the query and the ones matching best its keywords with `reciprocal_rank_fusion`, so that exact matches of product codes or names are found even when their embeddings are not close to the query. The scores are the fused scores; pass the results to `RerankerEndpoint::rerank` for a final ordering.
+```no_run
+use erniebot_rs::embedding::{EmbeddingEndpoint, EmbeddingModel};
+use erniebot_rs::reranker::{RerankerEndpoint, RerankerModel};
+use erniebot_rs::vector_store::{Document, HybridStore};
+let embedding = EmbeddingEndpoint::new(EmbeddingModel::EmbeddingV1).unwrap();
+let mut store = HybridStore::new(embedding);
+store
+    .upsert(vec![
+        Document::new("1", "XR-200 的电池续航为10小时"),
+        Document::new("2", "XR-300 支持快速充电"),
+    ])
+    .unwrap();
+let found = store.search("XR-300 能快充吗", 20, None).unwrap();
+let reranker = RerankerEndpoint::new(RerankerModel::BceRerankerBaseV1).unwrap();
+let reranked = reranker
+    .rerank("XR-300 能快充吗", found, |found| &found.document.text, Some(5), None, None)
+    .unwrap();
+println!("{}", reranked[0].item.document.text);
+```
+*/
+#[derive(Debug, Clone)]
+pub struct HybridStore {
+    vectors: VectorStore,
+    keywords: Bm25Index,
+    depth: usize,
+    rrf_k: f64,
+}
+
+impl HybridStore {
+    pub fn new(embedding: EmbeddingEndpoint) -> Self {
+        HybridStore::from_store(VectorStore::new(embedding))
+    }
+
+    /// search a vector store together with a keyword index built from its documents
+    pub fn from_store(vectors: VectorStore) -> Self {
+        HybridStore {
+            keywords: Bm25Index::from_documents(vectors.documents().cloned()),
+            vectors,
+            depth: 50,
+            rrf_k: 60.0,
+        }
+    }
+
+    /// set how many documents each search retrieves before fusion, 50 by default
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth.max(1);
+        self
+    }
+
+    /// set the `k` of `reciprocal_rank_fusion`, 60 by default
+    pub fn with_rrf_k(mut self, rrf_k: f64) -> Self {
+        self.rrf_k = rrf_k.max(0.0);
+        self
+    }
+
+    pub fn vectors(&self) -> &VectorStore {
+        &self.vectors
+    }
+
+    pub fn keywords(&self) -> &Bm25Index {
+        &self.keywords
+    }
+
+    pub fn len(&self) -> usize {
+        self.vectors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vectors.is_empty()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Document> {
+        self.vectors.get(id)
+    }
+
+    /// add a document with its embedding computed elsewhere, see `VectorStore::upsert_embedded`
+    pub fn upsert_embedded(
+        &mut self,
+        document: Document,
+        vector: Vec<f32>,
+    ) -> Result<(), ErnieError> {
+        self.vectors.upsert_embedded(document.clone(), vector)?;
+        self.keywords.upsert(document);
+        Ok(())
+    }
+
+    /// embed documents and add them, see `VectorStore::upsert`
+    pub fn upsert(&mut self, documents: Vec<Document>) -> Result<(), ErnieError> {
+        self.vectors.upsert(documents.clone())?;
+        for document in documents {
+            self.keywords.upsert(document);
+        }
+        Ok(())
+    }
+
+    /// async version of `upsert`
+    pub async fn aupsert(&mut self, documents: Vec<Document>) -> Result<(), ErnieError> {
+        self.vectors.aupsert(documents.clone()).await?;
+        for document in documents {
+            self.keywords.upsert(document);
+        }
+        Ok(())
+    }
+
+    /// remove documents by id, returning how many were stored
+    pub fn delete(&mut self, ids: &[&str]) -> usize {
+        self.keywords.delete(ids);
+        self.vectors.delete(ids)
+    }
+
+    pub fn clear(&mut self) {
+        self.vectors.clear();
+        self.keywords.clear();
+    }
+
+    fn fuse(
+        &self,
+        similar: Vec<ScoredDocument>,
+        query: &str,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Vec<ScoredDocument> {
+        let matching = self.keywords.search(query, self.depth.max(k), filter);
+        let mut fused = reciprocal_rank_fusion(&[similar, matching], self.rrf_k);
+        fused.truncate(k);
+        fused
+    }
+
+    /// the `k` best documents for an embedding of the query computed elsewhere and the keywords of `query`
+    pub fn search_by_vector(
+        &self,
+        query: &str,
+        vector: &[f32],
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<ScoredDocument>, ErnieError> {
+        let similar = self
+            .vectors
+            .search_by_vector(vector, self.depth.max(k), filter)?;
+        Ok(self.fuse(similar, query, k, filter))
+    }
+
+    /// the `k` best documents for `query`, among the ones matching `filter`, the best first
+    pub fn search(
+        &self,
+        query: &str,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<ScoredDocument>, ErnieError> {
+        let similar = self.vectors.search(query, self.depth.max(k), filter)?;
+        Ok(self.fuse(similar, query, k, filter))
+    }
+
+    /// async version of `search`
+    pub async fn asearch(
+        &self,
+        query: &str,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<ScoredDocument>, ErnieError> {
+        let similar = self
+            .vectors
+            .asearch(query, self.depth.max(k), filter)
+            .await?;
+        Ok(self.fuse(similar, query, k, filter))
+    }
+
+    /// save the vector store, see `VectorStore::save`. The keyword index is built again by `load`.
+    pub fn save(&self, path: impl AsRef<Path>, format: StoreFormat) -> Result<(), ErnieError> {
+        self.vectors.save(path, format)
+    }
+
+    /// load a store saved by `save` or `VectorStore::save`
+    pub fn load(path: impl AsRef<Path>, embedding: EmbeddingEndpoint) -> Result<Self, ErnieError> {
+        Ok(HybridStore::from_store(VectorStore::load(path, embedding)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::offline_pool;
+    use crate::embedding::EmbeddingModel;
+
+    fn found(ids: &[&str]) -> Vec<ScoredDocument> {
+        ids.iter()
+            .map(|id| ScoredDocument {
+                document: Document::new(*id, *id),
+                score: 1.0,
+            })
+            .collect()
+    }
+
+    fn ids(found: &[ScoredDocument]) -> Vec<&str> {
+        found.iter().map(|f| f.document.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_reciprocal_rank_fusion() {
+        let fused = reciprocal_rank_fusion(&[found(&["a", "b", "c"]), found(&["c", "d"])], 60.0);
+        assert_eq!(ids(&fused), ["c", "a", "b", "d"]);
+        assert!((fused[0].score - (1.0 / 63.0 + 1.0 / 61.0)).abs() < 1e-12);
+        assert!(reciprocal_rank_fusion(&[], 60.0).is_empty());
+    }
+
+    #[test]
+    fn test_search_by_vector() {
+        let embedding = EmbeddingEndpoint::new_with_credential_pool(
+            EmbeddingModel::EmbeddingV1,
+            offline_pool(),
+        )
+        .unwrap();
+        let mut store = HybridStore::new(embedding).with_depth(2);
+        let axis = |i: usize| {
+            let mut vector = vec![0.0; 384];
+            vector[i] = 1.0;
+            vector
+        };
+        let texts = ["battery life", "fast charging", "XR-300 screen", "camera"];
+        for (i, text) in texts.iter().enumerate() {
+            store
+                .upsert_embedded(Document::new(i.to_string(), *text), axis(i))
+                .unwrap();
+        }
+        let mut query = axis(0);
+        query[1] = 0.5;
+        let found = store.search_by_vector("xr-300", &query, 2, None).unwrap();
+        assert_eq!(ids(&found), ["0", "2"]);
+        let found = store.search_by_vector("xr-300", &query, 3, None).unwrap();
+        assert_eq!(ids(&found), ["2", "0", "1"]);
+
+        assert_eq!(store.delete(&["2"]), 1);
+        let found = store.search_by_vector("xr-300", &query, 3, None).unwrap();
+        assert_eq!(ids(&found), ["0", "1", "3"]);
+    }
+}
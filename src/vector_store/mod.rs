@@ -0,0 +1,14 @@
+mod binary;
+mod bm25;
+mod document;
+mod filter;
+mod hnsw;
+mod hybrid;
+mod store;
+
+pub use bm25::{tokenize, Bm25Index};
+pub use document::{Document, ScoredDocument};
+pub use filter::Filter;
+pub use hnsw::{HnswConfig, HnswIndex};
+pub use hybrid::{reciprocal_rank_fusion, HybridStore};
+pub use store::{StoreFormat, VectorStore};
@@ -0,0 +1,664 @@
+use super::binary::{store_error, write_file, Encode, Reader};
+use super::document::{Document, ScoredDocument};
+use super::filter::Filter;
+use super::hnsw::{HnswConfig, HnswIndex};
+use crate::embedding::{l2_normalize, similarity, EmbeddingEndpoint, EmbeddingResponse};
+use crate::errors::ErnieError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// the first bytes of a store saved in the binary format, followed by the version of the format
+static BINARY_MAGIC: &[u8; 4] = b"ERVS";
+static BINARY_VERSION: u8 = 2;
+
+/// The file formats of a saved `VectorStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StoreFormat {
+    /// readable json, with the vectors as arrays of numbers. The graph of an HNSW index is not saved, a loaded store is searched by brute force until `with_index` is called.
+    Json,
+    /// compact binary, with the vectors as little endian `f32`, and the graph of the HNSW index if any
+    #[default]
+    Binary,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    document: Document,
+    /// normalized embedding of the text
+    vector: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonStore {
+    model: String,
+    documents: Vec<Entry>,
+}
+
+/// Normalized vectors searched by brute force.
+#[derive(Debug, Clone)]
+struct FlatIndex {
+    dimension: usize,
+    vectors: Vec<f32>,
+    deleted: Vec<bool>,
+}
+
+impl FlatIndex {
+    fn insert(&mut self, vector: &[f32]) -> Result<usize, ErnieError> {
+        if vector.len() != self.dimension {
+            return Err(ErnieError::DimensionMismatchError(format!(
+                "the store holds embeddings of dimension {}, not {}",
+                self.dimension,
+                vector.len()
+            )));
+        }
+        let start = self.vectors.len();
+        self.vectors.extend_from_slice(vector);
+        l2_normalize(&mut self.vectors[start..]);
+        self.deleted.push(false);
+        Ok(self.deleted.len() - 1)
+    }
+
+    fn vector(&self, node: usize) -> Option<&[f32]> {
+        match self.deleted.get(node) {
+            Some(false) => Some(&self.vectors[node * self.dimension..(node + 1) * self.dimension]),
+            _ => None,
+        }
+    }
+
+    fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        accept: impl Fn(usize) -> bool,
+    ) -> Result<Vec<(usize, f64)>, ErnieError> {
+        if query.len() != self.dimension {
+            return Err(ErnieError::DimensionMismatchError(format!(
+                "the store holds embeddings of dimension {}, not {}",
+                self.dimension,
+                query.len()
+            )));
+        }
+        let mut query = query.to_vec();
+        l2_normalize(&mut query);
+        let mut scored: Vec<(usize, f64)> = (0..self.deleted.len())
+            .filter(|&node| accept(node))
+            .filter_map(|node| {
+                let vector = self.vector(node)?;
+                Some((node, similarity(vector, &query)))
+            })
+            .collect();
+        scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        scored.truncate(k);
+        Ok(scored)
+    }
+}
+
+/// The vectors of a store, each one a node of the index, and how they are searched.
+#[derive(Debug, Clone)]
+enum Index {
+    Flat(FlatIndex),
+    Hnsw(HnswIndex),
+}
+
+impl Index {
+    fn flat(dimension: usize) -> Self {
+        Index::Flat(FlatIndex {
+            dimension,
+            vectors: Vec::new(),
+            deleted: Vec::new(),
+        })
+    }
+
+    /// an index of the same kind, without nodes
+    fn empty(&self) -> Self {
+        match self {
+            Index::Flat(flat) => Index::flat(flat.dimension),
+            Index::Hnsw(hnsw) => Index::Hnsw(HnswIndex::new(hnsw.dimension(), *hnsw.config())),
+        }
+    }
+
+    fn insert(&mut self, vector: &[f32]) -> Result<usize, ErnieError> {
+        match self {
+            Index::Flat(flat) => flat.insert(vector),
+            Index::Hnsw(hnsw) => hnsw.insert(vector),
+        }
+    }
+
+    fn remove(&mut self, node: usize) {
+        match self {
+            Index::Flat(flat) => flat.deleted[node] = true,
+            Index::Hnsw(hnsw) => {
+                hnsw.remove(node);
+            }
+        }
+    }
+
+    fn vector(&self, node: usize) -> Option<&[f32]> {
+        match self {
+            Index::Flat(flat) => flat.vector(node),
+            Index::Hnsw(hnsw) => hnsw.vector(node),
+        }
+    }
+
+    fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        accept: impl Fn(usize) -> bool,
+    ) -> Result<Vec<(usize, f64)>, ErnieError> {
+        match self {
+            Index::Flat(flat) => flat.search(query, k, accept),
+            Index::Hnsw(hnsw) => hnsw.search_filtered(query, k, accept),
+        }
+    }
+}
+
+/** VectorStore keeps documents and their embeddings in memory and finds the ones closest to a query, for retrieval augmented generation without a vector database.
+
+Documents are embedded in batches with the given `EmbeddingEndpoint` (see `EmbeddingEndpoint::embed_all`), and their normalized vectors stored as `f32`. Searches embed the query with the same endpoint and rank the documents by cosine similarity.
+By default the search is exact, by brute force, which is fast enough for some ten thousand documents. For larger stores, `with_index` switches to an approximate `HnswIndex`.
+The store can be saved to a file and loaded back with `save` and `load`.
+```no_run
+use erniebot_rs::embedding::{EmbeddingEndpoint, EmbeddingModel};
+use erniebot_rs::vector_store::{Document, Filter, HnswConfig, StoreFormat, VectorStore};
+let embedding = EmbeddingEndpoint::new(EmbeddingModel::EmbeddingV1).unwrap();
+let mut store = VectorStore::new(embedding.clone()).with_index(HnswConfig::default());
+store
+    .upsert(vec![
+        Document::new("1", "点击“忘记密码”即可重置密码").with_metadata("source", "faq"),
+        Document::new("2", "会员费用每月30元").with_metadata("source", "pricing"),
+    ])
+    .unwrap();
+let found = store
+    .search("怎么修改密码", 1, Some(&Filter::eq("source", "faq")))
+    .unwrap();
+println!("{} ({})", found[0].document.text, found[0].score);
+store.save("store.bin", StoreFormat::Binary).unwrap();
+let store = VectorStore::load("store.bin", embedding).unwrap();
+```
+*/
+#[derive(Debug, Clone)]
+pub struct VectorStore {
+    embedding: EmbeddingEndpoint,
+    /// document of each node of the index, `None` once deleted or replaced
+    documents: Vec<Option<Document>>,
+    /// node of each document by id
+    nodes: HashMap<String, usize>,
+    index: Index,
+}
+
+impl VectorStore {
+    /// create an empty store searched by brute force
+    pub fn new(embedding: EmbeddingEndpoint) -> Self {
+        let dimension = embedding.model().dimension();
+        VectorStore {
+            embedding,
+            documents: Vec::new(),
+            nodes: HashMap::new(),
+            index: Index::flat(dimension),
+        }
+    }
+
+    /// search the store with an `HnswIndex`, built from the stored documents
+    pub fn with_index(mut self, config: HnswConfig) -> Self {
+        self.rebuild(Index::Hnsw(HnswIndex::new(self.dimension(), config)));
+        self
+    }
+
+    /// move the live documents to `index`, dropping the deleted ones
+    fn rebuild(&mut self, mut index: Index) {
+        let mut documents = Vec::with_capacity(self.nodes.len());
+        for (node, document) in self.documents.iter_mut().enumerate() {
+            let (Some(document), Some(vector)) = (document.take(), self.index.vector(node)) else {
+                continue;
+            };
+            let node = index
+                .insert(vector)
+                .expect("the vectors of a store have the same dimension");
+            self.nodes.insert(document.id.clone(), node);
+            documents.push(Some(document));
+        }
+        self.documents = documents;
+        self.index = index;
+    }
+
+    /// free the memory held by deleted and replaced documents. With an `HnswIndex`, this builds the graph again.
+    pub fn compact(&mut self) {
+        self.rebuild(self.index.empty());
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// the dimension of the stored vectors, set by the model of the embedding endpoint
+    pub fn dimension(&self) -> usize {
+        self.embedding.model().dimension()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Document> {
+        self.documents[*self.nodes.get(id)?].as_ref()
+    }
+
+    /// the stored embedding of a document, normalized
+    pub fn get_vector(&self, id: &str) -> Option<&[f32]> {
+        self.index.vector(*self.nodes.get(id)?)
+    }
+
+    pub fn documents(&self) -> impl Iterator<Item = &Document> {
+        self.documents.iter().flatten()
+    }
+
+    /// add a document with its embedding computed elsewhere, replacing the stored document with the same id
+    pub fn upsert_embedded(
+        &mut self,
+        document: Document,
+        vector: Vec<f32>,
+    ) -> Result<(), ErnieError> {
+        let node = self.index.insert(&vector)?;
+        if let Some(replaced) = self.nodes.insert(document.id.clone(), node) {
+            self.index.remove(replaced);
+            self.documents[replaced] = None;
+        }
+        self.documents.push(Some(document));
+        Ok(())
+    }
+
+    fn upsert_response(
+        &mut self,
+        documents: Vec<Document>,
+        response: EmbeddingResponse,
+    ) -> Result<(), ErnieError> {
+        let embeddings = response.get_embeddings::<f32>()?;
+        if embeddings.len() != documents.len() {
+            return Err(ErnieError::GetResponseError(format!(
+                "got {} embeddings for {} documents",
+                embeddings.len(),
+                documents.len()
+            )));
+        }
+        // check every vector first, so that a bad one leaves the store unchanged
+        if let Some(embedding) = embeddings
+            .iter()
+            .find(|embedding| embedding.vector.len() != self.dimension())
+        {
+            return Err(ErnieError::DimensionMismatchError(format!(
+                "the store holds embeddings of dimension {}, not {}",
+                self.dimension(),
+                embedding.vector.len()
+            )));
+        }
+        for (document, embedding) in documents.into_iter().zip(embeddings) {
+            self.upsert_embedded(document, embedding.vector)?;
+        }
+        Ok(())
+    }
+
+    /// embed documents and add them, replacing the stored documents with the same ids. Nothing is added if embedding fails.
+    pub fn upsert(&mut self, documents: Vec<Document>) -> Result<(), ErnieError> {
+        let texts: Vec<String> = documents.iter().map(|d| d.text.clone()).collect();
+        let response = self.embedding.embed_all(&texts, None)?;
+        self.upsert_response(documents, response)
+    }
+
+    /// async version of `upsert`
+    pub async fn aupsert(&mut self, documents: Vec<Document>) -> Result<(), ErnieError> {
+        let texts: Vec<String> = documents.iter().map(|d| d.text.clone()).collect();
+        let response = self.embedding.aembed_all(&texts, None).await?;
+        self.upsert_response(documents, response)
+    }
+
+    /// remove documents by id, returning how many were stored
+    pub fn delete(&mut self, ids: &[&str]) -> usize {
+        let mut deleted = 0;
+        for id in ids {
+            if let Some(node) = self.nodes.remove(*id) {
+                self.index.remove(node);
+                self.documents[node] = None;
+                deleted += 1;
+            }
+        }
+        deleted
+    }
+
+    pub fn clear(&mut self) {
+        self.documents.clear();
+        self.nodes.clear();
+        self.index = self.index.empty();
+    }
+
+    /// the `k` documents most similar to an embedding, among the ones matching `filter`, the most similar first
+    pub fn search_by_vector(
+        &self,
+        vector: &[f32],
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<ScoredDocument>, ErnieError> {
+        let accept = |node: usize| {
+            self.documents[node].as_ref().is_some_and(|document| {
+                filter.is_none_or(|filter| filter.matches(&document.metadata))
+            })
+        };
+        Ok(self
+            .index
+            .search(vector, k, accept)?
+            .into_iter()
+            .filter_map(|(node, score)| {
+                Some(ScoredDocument {
+                    document: self.documents[node].clone()?,
+                    score,
+                })
+            })
+            .collect())
+    }
+
+    fn query_vector(response: EmbeddingResponse) -> Result<Vec<f32>, ErnieError> {
+        response
+            .get_embeddings::<f32>()?
+            .into_iter()
+            .next()
+            .map(|embedding| embedding.vector)
+            .ok_or(ErnieError::GetResponseError(
+                "no embedding in the response".to_string(),
+            ))
+    }
+
+    /// the `k` documents most similar to a query, among the ones matching `filter`, the most similar first
+    pub fn search(
+        &self,
+        query: &str,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<ScoredDocument>, ErnieError> {
+        let response = self.embedding.invoke(&vec![query.to_string()], None)?;
+        self.search_by_vector(&VectorStore::query_vector(response)?, k, filter)
+    }
+
+    /// async version of `search`
+    pub async fn asearch(
+        &self,
+        query: &str,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<ScoredDocument>, ErnieError> {
+        let response = self
+            .embedding
+            .ainvoke(&vec![query.to_string()], None)
+            .await?;
+        self.search_by_vector(&VectorStore::query_vector(response)?, k, filter)
+    }
+
+    fn to_binary(&self) -> Result<Vec<u8>, ErnieError> {
+        let mut bytes = Vec::with_capacity(self.len() * (self.dimension() * 4 + 256));
+        bytes.extend_from_slice(BINARY_MAGIC);
+        bytes.put_u8(BINARY_VERSION);
+        bytes.put_bytes(self.embedding.model().to_string().as_bytes());
+        match &self.index {
+            Index::Flat(flat) => {
+                bytes.put_u8(0);
+                bytes.put_u64(self.len() as u64);
+                for (node, document) in self.documents.iter().enumerate() {
+                    let (Some(document), Some(vector)) = (document, flat.vector(node)) else {
+                        continue;
+                    };
+                    bytes.put_bytes(&serde_json::to_vec(document).map_err(store_error)?);
+                    bytes.put_f32s(vector);
+                }
+            }
+            Index::Hnsw(hnsw) => {
+                bytes.put_u8(1);
+                bytes.put_u64(self.documents.len() as u64);
+                for document in &self.documents {
+                    match document {
+                        Some(document) => {
+                            bytes.put_bytes(&serde_json::to_vec(document).map_err(store_error)?)
+                        }
+                        None => bytes.put_bytes(&[]),
+                    }
+                }
+                hnsw.encode(&mut bytes);
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn from_binary(bytes: &[u8], embedding: EmbeddingEndpoint) -> Result<Self, ErnieError> {
+        let mut reader = Reader::new(bytes);
+        reader.header(BINARY_MAGIC, BINARY_VERSION)?;
+        let model = String::from_utf8(reader.bytes()?.to_vec()).map_err(store_error)?;
+        let mut store = VectorStore::new(embedding);
+        store.check_model(&model)?;
+        match reader.u8()? {
+            0 => {
+                for _ in 0..reader.u64()? {
+                    let document = serde_json::from_slice(reader.bytes()?).map_err(store_error)?;
+                    let vector = reader.f32s(store.dimension())?;
+                    store.upsert_embedded(document, vector)?;
+                }
+            }
+            1 => {
+                let count = reader.u64()? as usize;
+                let mut documents: Vec<Option<Document>> = Vec::with_capacity(count.min(1 << 20));
+                for _ in 0..count {
+                    let document = match reader.bytes()? {
+                        [] => None,
+                        bytes => Some(serde_json::from_slice(bytes).map_err(store_error)?),
+                    };
+                    documents.push(document);
+                }
+                let hnsw = HnswIndex::decode(&mut reader)?;
+                if hnsw.node_count() != count || hnsw.dimension() != store.dimension() {
+                    return Err(store_error("the index does not match the documents"));
+                }
+                store.nodes = documents
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(node, document)| Some((document.as_ref()?.id.clone(), node)))
+                    .collect();
+                store.documents = documents;
+                store.index = Index::Hnsw(hnsw);
+            }
+            _ => return Err(store_error("unknown index kind")),
+        }
+        Ok(store)
+    }
+
+    fn check_model(&self, model: &str) -> Result<(), ErnieError> {
+        if model != self.embedding.model().to_string() {
+            return Err(ErnieError::VectorStoreError(format!(
+                "the store was embedded with {}, it cannot be searched with {}",
+                model,
+                self.embedding.model()
+            )));
+        }
+        Ok(())
+    }
+
+    /// write the documents and their embeddings to a file, replacing it atomically
+    pub fn save(&self, path: impl AsRef<Path>, format: StoreFormat) -> Result<(), ErnieError> {
+        let bytes = match format {
+            StoreFormat::Binary => self.to_binary()?,
+            StoreFormat::Json => {
+                let documents = self
+                    .documents
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(node, document)| {
+                        Some(Entry {
+                            document: document.clone()?,
+                            vector: self.index.vector(node)?.to_vec(),
+                        })
+                    })
+                    .collect();
+                serde_json::to_vec(&JsonStore {
+                    model: self.embedding.model().to_string(),
+                    documents,
+                })
+                .map_err(store_error)?
+            }
+        };
+        write_file(path.as_ref(), &bytes)
+    }
+
+    /// read a store written by `save`, in either format. It must have been embedded with the model of `embedding`.
+    pub fn load(path: impl AsRef<Path>, embedding: EmbeddingEndpoint) -> Result<Self, ErnieError> {
+        let bytes = std::fs::read(path).map_err(store_error)?;
+        if bytes.starts_with(BINARY_MAGIC) {
+            return VectorStore::from_binary(&bytes, embedding);
+        }
+        let json: JsonStore = serde_json::from_slice(&bytes).map_err(store_error)?;
+        let mut store = VectorStore::new(embedding);
+        store.check_model(&json.model)?;
+        for entry in json.documents {
+            store.upsert_embedded(entry.document, entry.vector)?;
+        }
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StoreFormat, VectorStore};
+    use crate::credentials::offline_pool;
+    use crate::embedding::{EmbeddingEndpoint, EmbeddingModel, EmbeddingResponse};
+    use crate::vector_store::HnswConfig;
+    use crate::vector_store::{Document, Filter};
+
+    fn store() -> VectorStore {
+        let embedding = EmbeddingEndpoint::new_with_credential_pool(
+            EmbeddingModel::EmbeddingV1,
+            offline_pool(),
+        )
+        .unwrap();
+        VectorStore::new(embedding)
+    }
+
+    /// a vector of dimension 384 pointing mostly to `axis`
+    fn vector(axis: usize) -> Vec<f32> {
+        let mut vector = vec![0.01; 384];
+        vector[axis] = 1.0;
+        vector
+    }
+
+    #[test]
+    fn test_search() {
+        let mut store = store();
+        for (id, axis, source) in [("a", 0, "faq"), ("b", 1, "faq"), ("c", 2, "blog")] {
+            let document = Document::new(id, id).with_metadata("source", source);
+            store.upsert_embedded(document, vector(axis)).unwrap();
+        }
+        let found = store.search_by_vector(&vector(1), 2, None).unwrap();
+        assert_eq!(found[0].document.id, "b");
+        assert!(found[0].score > 0.99 && found[1].score < 0.1);
+
+        let faq = Filter::eq("source", "faq");
+        let found = store.search_by_vector(&vector(2), 3, Some(&faq)).unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|found| found.document.id != "c"));
+
+        // upsert, then delete
+        store
+            .upsert_embedded(Document::new("a", "new a"), vector(2))
+            .unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get("a").unwrap().text, "new a");
+        assert_eq!(store.delete(&["a", "missing"]), 1);
+        assert_eq!(store.get("b").unwrap().text, "b");
+        assert_eq!(store.get("c").unwrap().text, "c");
+        assert!(store
+            .upsert_embedded(Document::new("d", "d"), vec![1.0; 3])
+            .is_err());
+    }
+
+    #[test]
+    fn test_upsert_response() {
+        let mut store = store();
+        let documents = vec![Document::new("a", "a"), Document::new("b", "b")];
+        let response = |vectors: Vec<Vec<f32>>| {
+            EmbeddingResponse::new(serde_json::json!({
+                "data": vectors
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, vector)| serde_json::json!({"embedding": vector, "index": index}))
+                    .collect::<Vec<_>>(),
+            }))
+        };
+        let bad = response(vec![vector(0), vec![1.0; 3]]);
+        assert!(store.upsert_response(documents.clone(), bad).is_err());
+        assert!(store.is_empty());
+        let good = response(vec![vector(0), vector(1)]);
+        store.upsert_response(documents, good).unwrap();
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut store = store();
+        let document = Document::new("a", "a").with_metadata("year", 2024);
+        store.upsert_embedded(document, vector(0)).unwrap();
+        store
+            .upsert_embedded(Document::new("b", "b"), vector(1))
+            .unwrap();
+        let dir = std::env::temp_dir().join(format!("erniebot-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for format in [StoreFormat::Binary, StoreFormat::Json] {
+            let path = dir.join(format!("{:?}", format));
+            store.save(&path, format).unwrap();
+            let loaded = VectorStore::load(&path, store.embedding.clone()).unwrap();
+            assert_eq!(loaded.len(), 2);
+            assert_eq!(loaded.get("a").unwrap().metadata["year"], 2024);
+            assert_eq!(loaded.get_vector("b"), store.get_vector("b"));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_index() {
+        let mut store = store().with_index(HnswConfig::default().with_m(4));
+        for axis in 0..100 {
+            let document = Document::new(axis.to_string(), "").with_metadata("axis", axis);
+            store.upsert_embedded(document, vector(axis)).unwrap();
+        }
+        let found = store.search_by_vector(&vector(42), 1, None).unwrap();
+        assert_eq!(found[0].document.id, "42");
+        let odd = Filter::Not(Box::new(Filter::lte("axis", 41)));
+        let found = store.search_by_vector(&vector(40), 3, Some(&odd)).unwrap();
+        assert!(found
+            .iter()
+            .all(|found| found.document.id.parse::<u32>().unwrap() > 41));
+
+        store.delete(&["42"]);
+        store
+            .upsert_embedded(Document::new("43", "new 43"), vector(43))
+            .unwrap();
+        assert_eq!(store.len(), 99);
+        let found = store.search_by_vector(&vector(42), 10, None).unwrap();
+        assert!(found.iter().all(|found| found.document.id != "42"));
+        let found = store.search_by_vector(&vector(43), 1, None).unwrap();
+        assert_eq!(found[0].document.text, "new 43");
+
+        let path = std::env::temp_dir().join(format!("erniebot-index-{}", std::process::id()));
+        store.save(&path, StoreFormat::Binary).unwrap();
+        let mut loaded = VectorStore::load(&path, store.embedding.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            loaded.search_by_vector(&vector(43), 3, None).unwrap(),
+            store.search_by_vector(&vector(43), 3, None).unwrap()
+        );
+        loaded.compact();
+        assert_eq!(loaded.len(), 99);
+        assert_eq!(loaded.get("43").unwrap().text, "new 43");
+        assert_eq!(
+            loaded.search_by_vector(&vector(43), 1, None).unwrap()[0]
+                .document
+                .id,
+            "43"
+        );
+    }
+}